@@ -0,0 +1,99 @@
+//! Counts the allocations made for every packet sent and received by a Socket.
+//!
+//! Run with `cargo bench --bench allocations`.
+
+extern crate kestrel;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::Duration;
+
+use kestrel::{MessageType, RateLimits, RemoteID, Socket, SocketEvent};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// How many messages are sent between two iterations of the sockets
+const BATCH_SIZE: usize = 32;
+const BATCHES: usize = 100;
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+fn connect(socket1: &mut Socket, socket2: &mut Socket) -> RemoteID {
+    let remote1 = socket1.connect_to(socket2.local_addr().unwrap());
+    loop {
+        sleep(Duration::from_millis(1));
+        socket2.prepare_iteration();
+        socket1.prepare_iteration();
+        while let Some(event) = socket1.next_event() {
+            if event == SocketEvent::Connected(remote1) {
+                return remote1;
+            }
+        }
+    }
+}
+
+/// Sends `BATCHES` batches of messages, and returns the allocations per packet made by the
+/// sender and by the receiver, which gives the buffers of the messages back.
+fn measure(message: &[u8], packets_per_message: usize) -> (f64, f64) {
+    let mut sender = Socket::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+    let mut receiver = Socket::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+    // every message must be received
+    receiver.set_rate_limits(RateLimits { packets_per_second: 0, .. RateLimits::default() });
+    let remote = connect(&mut sender, &mut receiver);
+    let (mut sender_allocations, mut receiver_allocations) = (0, 0);
+    // the first batches fill the buffer pools
+    for batch in 0..BATCHES + 2 {
+        let before = allocations();
+        for _ in 0..BATCH_SIZE {
+            sender.send_message(remote, message, MessageType::Forgettable, 0).unwrap();
+        }
+        let sent = allocations();
+        sleep(Duration::from_millis(1));
+        for (_, messages) in receiver.receive_all_messages() {
+            for message in messages {
+                receiver.recycle(message);
+            }
+        }
+        let received = allocations();
+        while sender.next_event().is_some() {}
+        sender.prepare_iteration();
+        if batch >= 2 {
+            sender_allocations += sent - before;
+            receiver_allocations += received - sent;
+        }
+    }
+    let packets = (BATCHES * BATCH_SIZE * packets_per_message) as f64;
+    (sender_allocations as f64 / packets, receiver_allocations as f64 / packets)
+}
+
+fn main() {
+    for &(name, size, packets) in &[("single fragment", 1000, 1), ("4 fragments", 4000, 4)] {
+        let (sent, received) = measure(&vec![1u8; size], packets);
+        println!("{:>16}: {:.2} allocations per packet sent, {:.2} per packet received", name, sent, received);
+    }
+}
@@ -16,17 +16,11 @@ fn main() {
     let mut connection1_remote_id: Option<RemoteID> = None;
     let mut connection2_remote_id: Option<RemoteID> = None;
     
-    match connection1.receive_event().unwrap().unwrap() {
-        InEvent::NewConnectionFrom(_, remote_id, _) => {
-            connection1_remote_id = Some(remote_id);
-        },
-        _ => {}
+    if let InEvent::NewConnectionFrom(_, remote_id, _, _) = connection1.receive_event().unwrap().unwrap() {
+        connection1_remote_id = Some(remote_id);
     }
-    match connection2.receive_event().unwrap().unwrap() {
-        InEvent::NewConnectionFrom(_, remote_id, _) => {
-            connection2_remote_id = Some(remote_id);
-        },
-        _ => {}
+    if let InEvent::NewConnectionFrom(_, remote_id, _, _) = connection2.receive_event().unwrap().unwrap() {
+        connection2_remote_id = Some(remote_id);
     }
 
     
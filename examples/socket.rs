@@ -1,7 +1,7 @@
 extern crate kestrel as k;
 
 use std::net::UdpSocket;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 fn main() {
     let poll_interval = Duration::from_millis(50);
@@ -17,8 +17,14 @@ fn main() {
     socket1.send_forgettable_message(socket1_remote_id, buf.as_slice(), 0).unwrap();
     socket1.send_forgettable_message(socket1_remote_id, buf.as_slice(), 0).unwrap();
     socket1.send_forgettable_message(socket1_remote_id, buf.as_slice(), 0).unwrap();
-    for i in 0..10 {
-        socket2.prepare_iteration();
+    for _ in 0..10 {
+        // the messages of socket1 are sent once the connection is established
+        for event in socket1.update(Instant::now()) {
+            println!("socket1 event: {:?}", event);
+        }
+        for event in socket2.update(Instant::now()) {
+            println!("socket2 event: {:?}", event);
+        }
         match socket2.receive_all_messages_from(socket2_remote_id) {
             Ok(o) => {
                 println!("socket received messages: {:?}", o);
@@ -27,5 +33,6 @@ fn main() {
                 println!("socket2 had an error: {:?}", e);
             }
         }
+        std::thread::sleep(poll_interval);
     };
 }
\ No newline at end of file
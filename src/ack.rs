@@ -0,0 +1,417 @@
+use fnv::FnvHashMap as HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use consts::*;
+use udp_message::Ack;
+
+/// Identifies a message sent through a Socket or a Connection.
+///
+/// Tokens are returned when sending a message, and given back in `Acked` and `Lost` events
+/// once we know what became of the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageToken(pub (crate) u64);
+
+/// Returns true if `seq_id` was sent after `other`: seq_ids wrap around after u32::MAX, a seq_id
+/// is after the 2^31 - 1 seq_ids that precede it.
+pub (crate) fn seq_id_after(seq_id: u32, other: u32) -> bool {
+    seq_id != other && seq_id.wrapping_sub(other) < 1 << 31
+}
+
+/// Remembers which fragments were received for recent seq_ids, so that they can be acknowledged.
+///
+/// Past `MAX_ACKED_MESSAGES`, the oldest seq_id is forgotten, and so is every seq_id before it:
+/// their fragments are still acknowledged, but never received again.
+#[derive(Debug, Default)]
+pub (crate) struct AckTracker {
+    /// for every seq_id, the bitfield of received fragments, and whether it changed
+    /// since the last time it was acknowledged
+    received: HashMap<u32, (u64, bool)>,
+    /// seq_ids that need to be acknowledged
+    pending: Vec<u32>,
+    /// the most recent seq_id received
+    newest: Option<u32>,
+    /// the most recent seq_id forgotten
+    evicted: Option<u32>,
+    /// acks of the fragments of forgotten seq_ids
+    late_acks: Vec<Ack>,
+    /// seq_ids forgotten since the last call to `drain_forgotten`
+    forgotten: Vec<u32>,
+}
+
+impl AckTracker {
+    pub fn new() -> AckTracker {
+        Default::default()
+    }
+
+    /// Records that the fragment `frag_id` of `seq_id` was received.
+    ///
+    /// Returns false if this fragment was already received, or may have been, in which case it
+    /// should be ignored.
+    pub fn record(&mut self, seq_id: u32, frag_id: u8) -> bool {
+        if self.evicted.is_some_and(|evicted| !seq_id_after(seq_id, evicted)) {
+            // the sender needs an ack to stop sending it, even if we can't tell what we received
+            self.late_acks.push(Ack { seq_id, received_frags: 1u64 << frag_id });
+            return false;
+        }
+        if self.newest.is_none_or(|newest| seq_id_after(seq_id, newest)) {
+            self.newest = Some(seq_id);
+        }
+        if !self.received.contains_key(&seq_id) && self.received.len() >= MAX_ACKED_MESSAGES {
+            self.evict_oldest();
+        }
+        let entry = self.received.entry(seq_id).or_insert((0, false));
+        let is_new = entry.0 & (1u64 << frag_id) == 0;
+        entry.0 |= 1u64 << frag_id;
+        // a fragment that was already received is acked again, our first ack may have been lost
+        if !entry.1 {
+            entry.1 = true;
+            self.pending.push(seq_id);
+        }
+        is_new
+    }
+
+    /// Forgets the seq_id furthest behind the newest one
+    fn evict_oldest(&mut self) {
+        let newest = match self.newest {
+            Some(newest) => newest,
+            None => return,
+        };
+        let oldest = self.received.keys().cloned().max_by_key(|seq_id| newest.wrapping_sub(*seq_id));
+        if let Some(oldest) = oldest {
+            debug!("forgetting the fragments received for seq_id {}, they won't be received again", oldest);
+            self.received.remove(&oldest);
+            self.forgotten.push(oldest);
+            if self.evicted.is_none_or(|evicted| seq_id_after(oldest, evicted)) {
+                self.evicted = Some(oldest);
+            }
+        }
+    }
+
+    /// Returns the seq_ids forgotten since the last call: their incomplete messages can't be
+    /// completed anymore
+    pub fn drain_forgotten(&mut self) -> ::std::vec::Drain<'_, u32> {
+        self.forgotten.drain(..)
+    }
+
+    /// Pushes the acks that need to be sent to the remote to `acks`
+    pub fn drain_acks(&mut self, acks: &mut Vec<Ack>) {
+        let received = &mut self.received;
+        acks.extend(self.pending.drain(..).filter_map(|seq_id| {
+            // the seq_id may have been evicted since
+            received.get_mut(&seq_id).map(|entry| {
+                entry.1 = false;
+                Ack { seq_id, received_frags: entry.0 }
+            })
+        }));
+        acks.append(&mut self.late_acks);
+    }
+}
+
+#[derive(Debug)]
+struct SentMessage {
+    token: MessageToken,
+    frag_total: u8,
+    acked_frags: u64,
+    sent_at: Instant,
+    last_sent_at: Instant,
+    /// The content of the message, kept to send it again until it is acknowledged.
+    /// None if the message is not sent again.
+    data: Option<Arc<[u8]>>,
+    /// When the message stops being sent again and is considered lost
+    expires_at: Option<Instant>,
+    /// once a message is sent again, its loss was recorded already
+    retransmitted: bool,
+}
+
+impl SentMessage {
+    fn all_frags(&self) -> u64 {
+        // frag_total is at most 63, the shift can't overflow
+        u64::MAX >> (63 - self.frag_total as u32)
+    }
+
+    fn frag_count(&self) -> u32 {
+        self.frag_total as u32 + 1
+    }
+
+    fn lost_frags(&self) -> u32 {
+        self.frag_count() - self.acked_frags.count_ones()
+    }
+}
+
+/// A message that must be sent again, because it was not acknowledged in time
+#[derive(Debug)]
+pub (crate) struct Retransmission {
+    pub seq_id: u32,
+    pub data: Arc<[u8]>,
+    /// fragments that the remote already received, and that must not be sent again
+    pub acked_frags: u64,
+}
+
+/// Keeps track of the messages sent to a remote that were not acknowledged yet.
+#[derive(Debug, Default)]
+pub (crate) struct SentMessages {
+    in_flight: HashMap<u32, SentMessage>,
+    /// smoothed round trip time
+    srtt: Option<Duration>,
+    /// exponentially weighted fraction of lost fragments
+    loss: f32,
+}
+
+impl SentMessages {
+    pub fn new() -> SentMessages {
+        Default::default()
+    }
+
+    /// Starts tracking a message.
+    ///
+    /// If `data` is given, the message is sent again until it is acknowledged or until `expires_at`.
+    /// Otherwise, it is considered lost once it is not acknowledged in time.
+    pub fn insert(&mut self, seq_id: u32, token: MessageToken, frag_total: u8, now: Instant, data: Option<Arc<[u8]>>, expires_at: Option<Instant>) {
+        self.in_flight.insert(seq_id, SentMessage {
+            token,
+            frag_total,
+            acked_frags: 0,
+            sent_at: now,
+            last_sent_at: now,
+            data,
+            expires_at,
+            retransmitted: false,
+        });
+    }
+
+    fn record_loss(&mut self, lost_frags: u32, total_frags: u32) {
+        const ALPHA: f32 = 0.1;
+        let lost = lost_frags as f32 / total_frags as f32;
+        self.loss = self.loss * (1.0 - ALPHA) + lost * ALPHA;
+    }
+
+    /// Applies an ack received from the remote.
+    ///
+    /// Returns the token of the message if it is now fully acknowledged. Acks for messages
+    /// that are already acknowledged or lost are ignored.
+    pub fn on_ack(&mut self, ack: Ack, now: Instant) -> Option<MessageToken> {
+        let (token, complete, first_ack_delay, frag_count) = match self.in_flight.get_mut(&ack.seq_id) {
+            None => return None,
+            Some(sent_message) => {
+                // the ack of a message sent several times can't tell which one it answers
+                let first_ack_delay = if sent_message.acked_frags == 0 && !sent_message.retransmitted {
+                    Some(now.duration_since(sent_message.sent_at))
+                } else {
+                    None
+                };
+                sent_message.acked_frags |= ack.received_frags & sent_message.all_frags();
+                let complete = sent_message.acked_frags == sent_message.all_frags();
+                (sent_message.token, complete, first_ack_delay, sent_message.frag_count())
+            }
+        };
+        if let Some(rtt_sample) = first_ack_delay {
+            self.srtt = Some(match self.srtt {
+                None => rtt_sample,
+                Some(srtt) => srtt * 7 / 8 + rtt_sample / 8,
+            });
+        }
+        if complete {
+            let sent_message = self.in_flight.remove(&ack.seq_id).unwrap();
+            if !sent_message.retransmitted {
+                self.record_loss(0, frag_count);
+            }
+            Some(token)
+        } else {
+            None
+        }
+    }
+
+    /// How long a message can stay unacknowledged before being considered lost
+    fn lost_timeout(&self) -> Duration {
+        match self.srtt {
+            None => DEFAULT_LOST_TIMEOUT,
+            Some(srtt) => ::std::cmp::max(srtt * 4, MIN_LOST_TIMEOUT),
+        }
+    }
+
+    /// Removes and returns the tokens of the messages that are lost: messages that are not sent again
+    /// and were not acknowledged in time, and messages that expired
+    pub fn expire(&mut self, now: Instant) -> Vec<MessageToken> {
+        let lost_timeout = self.lost_timeout();
+        let lost_seq_ids: Vec<u32> = self.in_flight.iter()
+            .filter(|&(_, m)| match (&m.data, m.expires_at) {
+                (_, Some(expires_at)) if now >= expires_at => true,
+                (&None, _) => now.duration_since(m.sent_at) >= lost_timeout,
+                (&Some(_), _) => false,
+            })
+            .map(|(seq_id, _)| *seq_id)
+            .collect();
+        let mut lost_tokens = Vec::with_capacity(lost_seq_ids.len());
+        for seq_id in lost_seq_ids {
+            let sent_message = self.in_flight.remove(&seq_id).unwrap();
+            if !sent_message.retransmitted {
+                self.record_loss(sent_message.lost_frags(), sent_message.frag_count());
+            }
+            lost_tokens.push(sent_message.token);
+        }
+        lost_tokens.sort();
+        lost_tokens
+    }
+
+    /// Returns the messages that must be sent again now, expired messages are never sent again.
+    ///
+    /// The loss of the fragments that were not acknowledged is recorded the first time only.
+    pub fn retransmissions(&mut self, now: Instant) -> Vec<Retransmission> {
+        let lost_timeout = self.lost_timeout();
+        let mut retransmissions = Vec::new();
+        let mut lost = Vec::new();
+        for (seq_id, sent_message) in &mut self.in_flight {
+            if sent_message.expires_at.is_some_and(|expires_at| now >= expires_at) {
+                continue;
+            }
+            if let Some(ref data) = sent_message.data {
+                if now.duration_since(sent_message.last_sent_at) >= lost_timeout {
+                    if !sent_message.retransmitted {
+                        lost.push((sent_message.lost_frags(), sent_message.frag_count()));
+                    }
+                    sent_message.last_sent_at = now;
+                    sent_message.retransmitted = true;
+                    retransmissions.push(Retransmission {
+                        seq_id: *seq_id,
+                        data: data.clone(),
+                        acked_frags: sent_message.acked_frags,
+                    });
+                }
+            }
+        }
+        for (lost_frags, frag_count) in lost {
+            self.record_loss(lost_frags, frag_count);
+        }
+        retransmissions.sort_by_key(|r| r.seq_id);
+        retransmissions
+    }
+
+    /// Stops tracking a message that could not be sent at all
+    pub fn remove(&mut self, seq_id: u32) {
+        self.in_flight.remove(&seq_id);
+    }
+
+    /// Removes every message and returns their tokens, when the remote is gone
+    pub fn drain(&mut self) -> Vec<MessageToken> {
+        let mut tokens: Vec<MessageToken> = self.in_flight.drain().map(|(_, m)| m.token).collect();
+        tokens.sort();
+        tokens
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Estimated packet loss, between 0.0 and 100.0
+    pub fn packet_loss(&self) -> f32 {
+        self.loss * 100.0
+    }
+}
+
+#[test]
+fn ack_tracker_acks_once() {
+    let mut ack_tracker = AckTracker::new();
+    let mut acks = Vec::new();
+    ack_tracker.record(3, 0);
+    ack_tracker.record(3, 2);
+    ack_tracker.record(4, 0);
+    ack_tracker.drain_acks(&mut acks);
+    assert_eq!(::std::mem::take(&mut acks), vec![
+        Ack { seq_id: 3, received_frags: 0b101 },
+        Ack { seq_id: 4, received_frags: 0b1 },
+    ]);
+    ack_tracker.drain_acks(&mut acks);
+    assert!(acks.is_empty());
+    ack_tracker.record(3, 1);
+    ack_tracker.drain_acks(&mut acks);
+    assert_eq!(::std::mem::take(&mut acks), vec![Ack { seq_id: 3, received_frags: 0b111 }]);
+    // received again: acked again, but the fragment must be ignored
+    assert!(!ack_tracker.record(3, 1));
+    ack_tracker.drain_acks(&mut acks);
+    assert_eq!(acks, vec![Ack { seq_id: 3, received_frags: 0b111 }]);
+}
+
+#[test]
+fn ack_tracker_forgets_old_seq_ids() {
+    let mut ack_tracker = AckTracker::new();
+    let mut acks = Vec::new();
+    // seq_ids wrap around, the oldest are the ones before u32::MAX
+    let first = u32::MAX - 10;
+    for i in 0..MAX_ACKED_MESSAGES as u32 + 1 {
+        assert!(ack_tracker.record(first.wrapping_add(i), 0));
+    }
+    ack_tracker.drain_acks(&mut acks);
+    acks.clear();
+    assert_eq!(ack_tracker.drain_forgotten().collect::<Vec<_>>(), vec![first]);
+    // the first one was forgotten, its fragments are acked but never received again
+    assert!(!ack_tracker.record(first, 0));
+    assert!(!ack_tracker.record(first - 1, 1));
+    ack_tracker.drain_acks(&mut acks);
+    assert_eq!(acks, vec![
+        Ack { seq_id: first, received_frags: 0b1 },
+        Ack { seq_id: first - 1, received_frags: 0b10 },
+    ]);
+    assert!(!ack_tracker.record(first + 1, 0));
+    assert!(ack_tracker.record(first + 1, 1));
+    assert!(ack_tracker.record(MAX_ACKED_MESSAGES as u32, 0));
+}
+
+#[test]
+fn sent_messages_acked_and_lost() {
+    let start = Instant::now();
+    let mut sent_messages = SentMessages::new();
+    sent_messages.insert(0, MessageToken(10), 1, start, None, None);
+    sent_messages.insert(1, MessageToken(11), 0, start, None, None);
+    let later = start + Duration::from_millis(50);
+    assert_eq!(sent_messages.on_ack(Ack { seq_id: 0, received_frags: 0b01 }, later), None);
+    assert_eq!(sent_messages.rtt(), Some(Duration::from_millis(50)));
+    assert_eq!(sent_messages.on_ack(Ack { seq_id: 0, received_frags: 0b11 }, later), Some(MessageToken(10)));
+    // acked twice: ignored
+    assert_eq!(sent_messages.on_ack(Ack { seq_id: 0, received_frags: 0b11 }, later), None);
+    assert!(sent_messages.expire(start + Duration::from_millis(199)).is_empty());
+    assert_eq!(sent_messages.expire(start + Duration::from_millis(200)), vec![MessageToken(11)]);
+    assert!(sent_messages.packet_loss() > 0.0);
+}
+
+#[test]
+fn sent_messages_retransmitted() {
+    let start = Instant::now();
+    let mut sent_messages = SentMessages::new();
+    let data: Arc<[u8]> = Arc::from(&[1u8, 2, 3][..]);
+    sent_messages.insert(0, MessageToken(10), 1, start, Some(data.clone()), None);
+    sent_messages.insert(1, MessageToken(11), 0, start, Some(data), Some(start + Duration::from_millis(1500)));
+    assert!(sent_messages.retransmissions(start + Duration::from_millis(999)).is_empty());
+    assert_eq!(sent_messages.retransmissions(start + DEFAULT_LOST_TIMEOUT).len(), 2);
+    // the ack of a message sent again doesn't give the rtt
+    assert_eq!(sent_messages.on_ack(Ack { seq_id: 0, received_frags: 0b01 }, start + DEFAULT_LOST_TIMEOUT), None);
+    assert_eq!(sent_messages.rtt(), None);
+    // reliable messages are only lost when they expire
+    assert!(sent_messages.expire(start + Duration::from_millis(1499)).is_empty());
+    assert_eq!(sent_messages.expire(start + Duration::from_millis(1500)), vec![MessageToken(11)]);
+    let retransmissions = sent_messages.retransmissions(start + DEFAULT_LOST_TIMEOUT * 2);
+    assert_eq!(retransmissions.len(), 1);
+    assert_eq!(retransmissions[0].acked_frags, 0b01);
+    assert_eq!(sent_messages.drain(), vec![MessageToken(10)]);
+}
+
+#[test]
+fn sent_messages_expired_or_lost_once() {
+    let start = Instant::now();
+    let mut sent_messages = SentMessages::new();
+    let data: Arc<[u8]> = Arc::from(&[1u8, 2, 3][..]);
+    sent_messages.insert(0, MessageToken(10), 0, start, Some(data.clone()), Some(start + DEFAULT_LOST_TIMEOUT));
+    sent_messages.insert(1, MessageToken(11), 1, start, Some(data), None);
+    // expired: reported lost, and never sent again
+    let retransmissions = sent_messages.retransmissions(start + DEFAULT_LOST_TIMEOUT);
+    assert_eq!(retransmissions.len(), 1);
+    assert_eq!(retransmissions[0].seq_id, 1);
+    let loss = sent_messages.packet_loss();
+    assert!(loss > 0.0);
+    // sending it again doesn't count its fragments as lost again
+    assert_eq!(sent_messages.retransmissions(start + DEFAULT_LOST_TIMEOUT * 2).len(), 1);
+    assert_eq!(sent_messages.on_ack(Ack { seq_id: 1, received_frags: 0b11 }, start + DEFAULT_LOST_TIMEOUT * 2), Some(MessageToken(11)));
+    assert_eq!(sent_messages.packet_loss(), loss);
+    assert_eq!(sent_messages.expire(start + DEFAULT_LOST_TIMEOUT * 2), vec![MessageToken(10)]);
+    assert!(sent_messages.packet_loss() > loss);
+}
@@ -0,0 +1,298 @@
+//! An asynchronous front-end for a Socket, driven by the tokio runtime instead of
+//! a dedicated thread.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::io::Interest;
+use tokio::net::UdpSocket as TokioUdpSocket;
+use tokio::time::{interval, Interval, MissedTickBehavior};
+
+use ack::MessageToken;
+use connection::{InData, InEvent};
+use consts::POLL_INTERVAL;
+use limiter::RateLimits;
+use crypto::PreSharedKey;
+use checksum::Checksum;
+use token::ConnectToken;
+use socket::{MessageType, RemoteID, Socket, SocketError};
+use stats::{RemoteStats, SocketStats};
+
+/// Something received by an `AsyncConnection`
+#[derive(Debug)]
+pub enum Incoming {
+    Data(InData),
+    Event(InEvent),
+}
+
+/// A connection living inside the tokio runtime.
+///
+/// Incoming data and events are received by polling it as a `Stream`. The stream must be polled
+/// regularly even if you don't expect any data, otherwise acks won't be sent and lost messages
+/// won't be detected.
+///
+/// It is `Send`, so it can be moved to any task of a multi-threaded runtime.
+#[derive(Debug)]
+pub struct AsyncConnection {
+    socket: Socket,
+    /// The same udp socket as the one owned by `socket`, registered in the runtime
+    /// to be woken up when it is readable or writable.
+    io: TokioUdpSocket,
+    interval: Interval,
+    incoming: VecDeque<Incoming>,
+}
+
+impl AsyncConnection {
+    /// Binds an asynchronous connection to address `address`
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn bind<A: ToSocketAddrs>(address: A) -> ::std::io::Result<AsyncConnection> {
+        let udp_socket = UdpSocket::bind(address)?;
+        udp_socket.set_nonblocking(true)?;
+        let io = TokioUdpSocket::from_std(udp_socket.try_clone()?)?;
+        let mut interval = interval(Duration::from_millis(POLL_INTERVAL as u64));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(AsyncConnection {
+            socket: Socket::new(udp_socket),
+            io,
+            interval,
+            incoming: VecDeque::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> ::std::io::Result<::std::net::SocketAddr> {
+        self.io.local_addr()
+    }
+
+    /// Starts connecting to a remote. `InEvent::NewConnectionFrom` or `InEvent::ConnectFailed`
+    /// tells how it went.
+    pub fn try_connect<A: ToSocketAddrs>(&mut self, addr: A) -> ::std::io::Result<RemoteID> {
+        self.socket.try_connect(addr)
+    }
+
+    /// See `Socket::connect_with_token`
+    pub fn connect_with_token(&mut self, addr: SocketAddr, token: ConnectToken) -> RemoteID {
+        self.socket.connect_with_token(addr, token)
+    }
+
+    pub fn disconnect(&mut self, remote_id: RemoteID) -> Result<(), SocketError> {
+        self.socket.disconnect(remote_id)
+    }
+
+    /// See `Socket::ban_ip`
+    pub fn ban_ip(&mut self, ip: IpAddr, duration: Option<Duration>) {
+        self.socket.ban_ip(ip, duration)
+    }
+
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        self.socket.unban_ip(ip)
+    }
+
+    /// See `Socket::ban_remote`
+    pub fn ban_remote(&mut self, remote_id: RemoteID, duration: Option<Duration>) -> Result<(), SocketError> {
+        self.socket.ban_remote(remote_id, duration)
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.socket.set_rate_limits(limits)
+    }
+
+    pub fn set_max_remotes(&mut self, max_remotes: usize) {
+        self.socket.set_max_remotes(max_remotes)
+    }
+
+    /// See `Socket::set_resumption_grace`
+    pub fn set_resumption_grace(&mut self, grace: Option<Duration>) {
+        self.socket.set_resumption_grace(grace)
+    }
+
+    /// See `Socket::set_pre_shared_key`
+    pub fn set_pre_shared_key(&mut self, psk: Option<PreSharedKey>) {
+        self.socket.set_pre_shared_key(psk)
+    }
+
+    /// See `Socket::set_checksum`
+    pub fn set_checksum(&mut self, checksum: Checksum) -> Result<(), SocketError> {
+        self.socket.set_checksum(checksum)
+    }
+
+    /// Sends data to a remote, waiting for the udp socket to be writable.
+    ///
+    /// The returned token will be given back by an `InEvent::Acked` or an `InEvent::Lost` event,
+    /// depending on whether the remote received the data or not.
+    pub fn send_data<'a>(&'a mut self, remote_id: RemoteID, data: &'a [u8], message_type: MessageType, priority: i8) -> SendData<'a> {
+        SendData {
+            connection: self,
+            remote_id,
+            data,
+            message_type,
+            priority,
+        }
+    }
+
+    pub fn send_forgettable_data<'a>(&'a mut self, remote_id: RemoteID, data: &'a [u8]) -> SendData<'a> {
+        self.send_data(remote_id, data, MessageType::Forgettable, 0)
+    }
+
+    /// See `Socket::broadcast_message`. Broadcasts don't wait for the udp socket to be writable,
+    /// sends that would block are reported by `InEvent::SendFailed` events.
+    pub fn broadcast_data(&mut self, data: &[u8], message_type: MessageType, priority: i8) -> Result<MessageToken, SocketError> {
+        self.socket.broadcast_message(data, message_type, priority)
+    }
+
+    /// See `Socket::send_group_message`
+    pub fn send_group_data(&mut self, group: &str, data: &[u8], message_type: MessageType, priority: i8) -> Result<MessageToken, SocketError> {
+        self.socket.send_group_message(group, data, message_type, priority)
+    }
+
+    /// See `Socket::join_group`
+    pub fn join_group(&mut self, group: &str, remote_id: RemoteID) -> Result<(), SocketError> {
+        self.socket.join_group(group, remote_id)
+    }
+
+    /// See `Socket::leave_group`
+    pub fn leave_group(&mut self, group: &str, remote_id: RemoteID) -> bool {
+        self.socket.leave_group(group, remote_id)
+    }
+
+    pub fn stats(&mut self) -> SocketStats {
+        self.socket.stats()
+    }
+
+    pub fn remote_stats(&mut self, remote_id: RemoteID) -> Result<RemoteStats, SocketError> {
+        self.socket.remote_stats(remote_id)
+    }
+}
+
+/// Runs one iteration of the socket and queues everything it received
+fn iterate(socket: &mut Socket, incoming: &mut VecDeque<Incoming>) {
+    for (remote_id, messages) in socket.receive_all_messages() {
+        incoming.extend(messages.into_iter().map(|m| Incoming::Data(InData(remote_id, m))));
+    }
+    while let Some(event) = socket.next_event() {
+        if let Some(event) = InEvent::from_socket_event(socket, event) {
+            incoming.push_back(Incoming::Event(event));
+        }
+    }
+}
+
+impl Stream for AsyncConnection {
+    type Item = Incoming;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Incoming>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(incoming) = this.incoming.pop_front() {
+                return Poll::Ready(Some(incoming));
+            }
+            // both must be polled every time, so that both register the waker
+            let readable = this.io.poll_recv_ready(cx).is_ready();
+            let ticked = this.interval.poll_tick(cx).is_ready();
+            if readable {
+                let socket = &mut this.socket;
+                let incoming = &mut this.incoming;
+                // `socket` reads until WouldBlock: returning WouldBlock tells the runtime
+                // to wait for the udp socket to be readable again.
+                let _would_block = this.io.try_io(Interest::READABLE, || {
+                    iterate(socket, incoming);
+                    Err::<(), _>(ErrorKind::WouldBlock.into())
+                });
+            } else if ticked {
+                iterate(&mut this.socket, &mut this.incoming);
+            } else {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+/// Future returned by `AsyncConnection::send_data`
+#[derive(Debug)]
+pub struct SendData<'a> {
+    connection: &'a mut AsyncConnection,
+    remote_id: RemoteID,
+    data: &'a [u8],
+    message_type: MessageType,
+    priority: i8,
+}
+
+impl<'a> Future for SendData<'a> {
+    type Output = Result<MessageToken, SocketError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if this.connection.io.poll_send_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+            let socket = &mut this.connection.socket;
+            let (remote_id, data, message_type, priority) = (this.remote_id, this.data, this.message_type, this.priority);
+            let r = this.connection.io.try_io(Interest::WRITABLE, || {
+                match socket.send_message(remote_id, data, message_type, priority) {
+                    // the kernel's buffer is full and nothing was sent: wait for it to be writable again
+                    Err(SocketError::IoError(ref e)) if e.kind() == ErrorKind::WouldBlock => Err(ErrorKind::WouldBlock.into()),
+                    r => Ok(r),
+                }
+            });
+            match r {
+                Ok(r) => return Poll::Ready(r),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+#[test]
+fn async_connection_send_receive() {
+    use std::future::poll_fn;
+    let runtime = ::tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let _guard = runtime.enter();
+    let mut connection1 = AsyncConnection::bind("127.0.0.1:0").unwrap();
+    let mut connection2 = AsyncConnection::bind("127.0.0.1:0").unwrap();
+    let remote1 = connection1.try_connect(connection2.local_addr().unwrap()).unwrap();
+    // the handshake needs both connections to be polled
+    let (mut incoming1, mut incoming2) = (None, None);
+    runtime.block_on(poll_fn(|cx| {
+        if incoming1.is_none() {
+            if let Poll::Ready(i) = Pin::new(&mut connection1).poll_next(cx) {
+                incoming1 = Some(i);
+            }
+        }
+        if incoming2.is_none() {
+            if let Poll::Ready(i) = Pin::new(&mut connection2).poll_next(cx) {
+                incoming2 = Some(i);
+            }
+        }
+        if incoming1.is_some() && incoming2.is_some() { Poll::Ready(()) } else { Poll::Pending }
+    }));
+    match incoming1.unwrap() {
+        Some(Incoming::Event(InEvent::NewConnectionFrom(_, remote_id, false, None))) => assert_eq!(remote_id, remote1),
+        i => panic!("expected to be connected, got {:?}", i),
+    }
+    let remote2 = match incoming2.unwrap() {
+        Some(Incoming::Event(InEvent::NewConnectionFrom(_, remote_id, true, None))) => remote_id,
+        i => panic!("expected a new connection, got {:?}", i),
+    };
+
+    let token = runtime.block_on(connection1.send_data(remote1, &[1, 2, 3], MessageType::KeyMessage, 0)).unwrap();
+    match runtime.block_on(poll_fn(|cx| Pin::new(&mut connection2).poll_next(cx))) {
+        Some(Incoming::Data(InData(remote_id, data))) => {
+            assert_eq!(remote_id, remote2);
+            assert_eq!(&*data, &[1, 2, 3]);
+        },
+        i => panic!("expected data, got {:?}", i),
+    }
+    match runtime.block_on(poll_fn(|cx| Pin::new(&mut connection1).poll_next(cx))) {
+        Some(Incoming::Event(InEvent::Acked(remote_id, acked_token))) => {
+            assert_eq!(remote_id, remote1);
+            assert_eq!(acked_token, token);
+        },
+        i => panic!("expected an ack, got {:?}", i),
+    }
+}
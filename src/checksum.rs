@@ -0,0 +1,95 @@
+//! Integrity checks of the packets that are not encrypted.
+//!
+//! The checksum follows the packet, so that its size only changes where the packet ends. Sealed
+//! packets never have one: their authentication tag already detects any corruption.
+
+use byteorder::{BigEndian, ByteOrder};
+use crc::crc32::checksum_ieee;
+use crc32c::crc32c;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// How a Socket detects the packets corrupted on the way.
+///
+/// Both ends of a connection must use the same: a server rejects the clients that don't with
+/// `RejectReason::ChecksumMismatch`. Handshake packets always use `Crc32`, so that they can be
+/// read before the checksum is agreed on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Checksum {
+    /// The IEEE CRC32, 4 bytes
+    Crc32 = 0,
+    /// The Castagnoli CRC32, 4 bytes, computed with the CRC32 instructions of the CPU when it has them
+    Crc32c = 1,
+    /// The 64-bit XXH3 hash, 8 bytes, for a stronger detection of corrupted packets
+    Hash64 = 2,
+    /// Nothing is checked: only for Sockets with a pre-shared key, whose packets are all encrypted
+    /// but the handshake
+    None = 3,
+}
+
+impl Checksum {
+    pub (crate) fn from_u8(b: u8) -> Option<Checksum> {
+        match b {
+            0 => Some(Checksum::Crc32),
+            1 => Some(Checksum::Crc32c),
+            2 => Some(Checksum::Hash64),
+            3 => Some(Checksum::None),
+            _ => None,
+        }
+    }
+
+    /// How many bytes the checksum adds to every packet that is not encrypted
+    pub fn size(self) -> usize {
+        match self {
+            Checksum::Crc32 | Checksum::Crc32c => 4,
+            Checksum::Hash64 => 8,
+            Checksum::None => 0,
+        }
+    }
+
+    /// Appends the checksum of everything in `buffer`
+    pub (crate) fn append(self, buffer: &mut Vec<u8>) {
+        let mut bytes = [0u8; 8];
+        match self {
+            Checksum::Crc32 => BigEndian::write_u32(&mut bytes, checksum_ieee(buffer)),
+            Checksum::Crc32c => BigEndian::write_u32(&mut bytes, crc32c(buffer)),
+            Checksum::Hash64 => BigEndian::write_u64(&mut bytes, xxh3_64(buffer)),
+            Checksum::None => {},
+        }
+        buffer.extend_from_slice(&bytes[..self.size()]);
+    }
+
+    /// Checks the checksum at the end of `bytes`, and returns what it covers.
+    ///
+    /// Returns None if `bytes` is too short to hold one, or if it doesn't match.
+    pub (crate) fn verify(self, bytes: &[u8]) -> Option<&[u8]> {
+        if bytes.len() < self.size() {
+            return None;
+        }
+        let (covered, checksum) = bytes.split_at(bytes.len() - self.size());
+        let valid = match self {
+            Checksum::Crc32 => BigEndian::read_u32(checksum) == checksum_ieee(covered),
+            Checksum::Crc32c => BigEndian::read_u32(checksum) == crc32c(covered),
+            Checksum::Hash64 => BigEndian::read_u64(checksum) == xxh3_64(covered),
+            Checksum::None => true,
+        };
+        if valid { Some(covered) } else { None }
+    }
+}
+
+#[test]
+fn checksums() {
+    for &checksum in &[Checksum::Crc32, Checksum::Crc32c, Checksum::Hash64, Checksum::None] {
+        assert_eq!(Checksum::from_u8(checksum as u8), Some(checksum));
+        let mut buffer = b"123456789".to_vec();
+        checksum.append(&mut buffer);
+        assert_eq!(buffer.len(), 9 + checksum.size());
+        assert_eq!(checksum.verify(&buffer), Some(&b"123456789"[..]));
+        if checksum != Checksum::None {
+            buffer[3] ^= 1;
+            assert_eq!(checksum.verify(&buffer), None);
+        }
+    }
+    // the check values of both CRC32s
+    assert_eq!(checksum_ieee(b"123456789"), 0xCBF43926);
+    assert_eq!(crc32c(b"123456789"), 0xE3069283);
+}
@@ -0,0 +1,65 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where a Socket reads the current time from.
+///
+/// Every timeout of the Socket (lost messages, connection attempts...) is computed from this clock,
+/// which must never go backwards.
+pub trait Clock: Debug + Send {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, the default clock of a Socket
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves forward when told to, to test time-based behaviour
+/// instantly and deterministically.
+///
+/// Clones share the same time: keep a clone to advance the clock given to a Socket.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// Creates a clock stopped at the current time
+    pub fn new() -> MockClock {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[test]
+fn mock_clock_advance() {
+    let clock = MockClock::new();
+    let shared_clock = clock.clone();
+    let start = shared_clock.now();
+    assert_eq!(shared_clock.now(), start);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(shared_clock.now(), start + Duration::from_secs(10));
+}
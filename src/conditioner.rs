@@ -0,0 +1,309 @@
+use std::cell::RefCell;
+use std::io::Result;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use transport::DatagramTransport;
+use clock::{Clock, SystemClock};
+
+/// Datagrams that would wait longer than this for the bandwidth to be available are dropped
+const MAX_BANDWIDTH_QUEUE_DELAY: Duration = Duration::from_secs(1);
+
+/// The conditions applied by a `LinkConditioner` to every datagram it sends.
+///
+/// Probabilities are between 0.0 (never) and 1.0 (always). The default is a perfect link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConditions {
+    /// Probability that a datagram is dropped
+    pub loss: f32,
+    /// Delay added to every datagram
+    pub latency: Duration,
+    /// Random delay added on top of `latency`, between 0 and `jitter`
+    pub jitter: Duration,
+    /// Probability that a datagram is sent twice
+    pub duplication: f32,
+    /// Probability that a datagram is held back for `reordering_delay`, so that it arrives
+    /// after the datagrams sent right after it
+    pub reordering: f32,
+    pub reordering_delay: Duration,
+    /// Probability that one bit of a datagram is flipped
+    pub corruption: f32,
+    /// Maximum bytes per second, None for an unlimited bandwidth.
+    ///
+    /// Datagrams over the limit are queued, and dropped if they would wait for more than a second.
+    pub bandwidth: Option<u32>,
+}
+
+impl Default for LinkConditions {
+    fn default() -> LinkConditions {
+        LinkConditions {
+            loss: 0.0,
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            duplication: 0.0,
+            reordering: 0.0,
+            reordering_delay: Duration::from_millis(20),
+            corruption: 0.0,
+            bandwidth: None,
+        }
+    }
+}
+
+/// A small xorshift64* generator. Not suitable for anything but simulations.
+#[derive(Debug)]
+pub (crate) struct XorShiftRng(u64);
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> XorShiftRng {
+        // the state must never be 0
+        XorShiftRng(seed ^ 0x9E37_79B9_7F4A_7C15 | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a float between 0.0 (included) and 1.0 (excluded)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Returns true with probability `p`
+    pub fn chance(&mut self, p: f32) -> bool {
+        p > 0.0 && self.next_f32() < p
+    }
+
+    /// Returns a duration between 0 and `max`, both included
+    pub fn duration(&mut self, max: Duration) -> Duration {
+        let nanos = max.as_nanos() as u64;
+        if nanos == 0 {
+            return max;
+        }
+        Duration::from_nanos(self.next_u64() % (nanos + 1))
+    }
+}
+
+#[derive(Debug)]
+struct DelayedDatagram<A> {
+    release_at: Instant,
+    /// keeps datagrams released at the same time in the order they were sent
+    order: u64,
+    addr: A,
+    data: Box<[u8]>,
+}
+
+#[derive(Debug)]
+struct LinkState<A> {
+    rng: XorShiftRng,
+    delayed: Vec<DelayedDatagram<A>>,
+    next_order: u64,
+    /// when the last queued datagram will be done using the bandwidth
+    link_free_at: Instant,
+}
+
+/// A transport that degrades the datagrams sent through another transport, to test
+/// how a Socket behaves on a bad network.
+///
+/// Only outgoing datagrams are affected: wrap the transports of both ends to degrade both
+/// directions. Delayed datagrams are sent whenever `send_to` or `recv_from` is called, so they
+/// are sent during the next `Socket::prepare_iteration` after their delay is over.
+///
+/// All the randomness comes from `seed`, so a test sending the same datagrams at the same times
+/// always sees the same result.
+#[derive(Debug)]
+pub struct LinkConditioner<T: DatagramTransport> {
+    transport: T,
+    conditions: LinkConditions,
+    state: RefCell<LinkState<T::Addr>>,
+    clock: Box<dyn Clock>,
+}
+
+impl<T: DatagramTransport> LinkConditioner<T> {
+    pub fn new(transport: T, conditions: LinkConditions, seed: u64) -> LinkConditioner<T> {
+        LinkConditioner::with_clock(transport, conditions, seed, Box::new(SystemClock))
+    }
+
+    /// Same as `new`, but delays are computed from `clock`, which should be the clock of the Socket.
+    pub fn with_clock(transport: T, conditions: LinkConditions, seed: u64, clock: Box<dyn Clock>) -> LinkConditioner<T> {
+        LinkConditioner {
+            transport,
+            conditions,
+            state: RefCell::new(LinkState {
+                rng: XorShiftRng::new(seed),
+                delayed: Vec::new(),
+                next_order: 0,
+                link_free_at: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    pub fn conditions(&self) -> LinkConditions {
+        self.conditions
+    }
+
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    /// Number of datagrams waiting for their delay to be over
+    pub fn delayed_datagrams(&self) -> usize {
+        self.state.borrow().delayed.len()
+    }
+
+    /// Sends the datagrams whose delay is over
+    pub fn flush(&self, now: Instant) {
+        let mut released = {
+            let mut state = self.state.borrow_mut();
+            let (released, delayed) = state.delayed.drain(..).partition(|d| d.release_at <= now);
+            state.delayed = delayed;
+            released
+        };
+        released.sort_by_key(|d: &DelayedDatagram<T::Addr>| (d.release_at, d.order));
+        for datagram in released {
+            if let Err(e) = self.transport.send_to(&datagram.data, &datagram.addr) {
+                // like any datagram lost on the way, nobody is told about it
+                debug!("link conditioner: sending a delayed datagram to {:?} failed: {}", datagram.addr, e);
+            }
+        }
+    }
+
+    fn enqueue(&self, state: &mut LinkState<T::Addr>, data: &[u8], addr: &T::Addr, departure: Instant) {
+        let conditions = &self.conditions;
+        let mut data: Box<[u8]> = data.into();
+        if !data.is_empty() && state.rng.chance(conditions.corruption) {
+            let bit = state.rng.next_u64() as usize % (data.len() * 8);
+            data[bit / 8] ^= 1 << (bit % 8);
+        }
+        let mut release_at = departure + conditions.latency + state.rng.duration(conditions.jitter);
+        if state.rng.chance(conditions.reordering) {
+            release_at += conditions.reordering_delay;
+        }
+        state.delayed.push(DelayedDatagram {
+            release_at,
+            order: state.next_order,
+            addr: addr.clone(),
+            data,
+        });
+        state.next_order += 1;
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for LinkConditioner<T> {
+    type Addr = T::Addr;
+
+    /// Always succeeds: like on a real network, datagrams that fail to be sent are silently lost.
+    fn send_to(&self, buf: &[u8], addr: &T::Addr) -> Result<usize> {
+        let now = self.clock.now();
+        {
+            let mut state = self.state.borrow_mut();
+            if state.rng.chance(self.conditions.loss) {
+                trace!("link conditioner: dropping a datagram of {} bytes to {:?}", buf.len(), addr);
+                return Ok(buf.len());
+            }
+            let mut departure = now;
+            if let Some(bandwidth) = self.conditions.bandwidth {
+                departure = ::std::cmp::max(now, state.link_free_at);
+                if departure.duration_since(now) > MAX_BANDWIDTH_QUEUE_DELAY {
+                    trace!("link conditioner: bandwidth exceeded, dropping a datagram of {} bytes to {:?}", buf.len(), addr);
+                    return Ok(buf.len());
+                }
+                state.link_free_at = departure + Duration::from_secs_f64(buf.len() as f64 / bandwidth as f64);
+            }
+            self.enqueue(&mut state, buf, addr, departure);
+            if state.rng.chance(self.conditions.duplication) {
+                self.enqueue(&mut state, buf, addr, departure);
+            }
+        }
+        self.flush(now);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, T::Addr)> {
+        self.flush(self.clock.now());
+        self.transport.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> Result<T::Addr> {
+        self.transport.local_addr()
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        self.transport.set_nonblocking()
+    }
+
+    fn ip_addr(addr: &T::Addr) -> Option<IpAddr> {
+        T::ip_addr(addr)
+    }
+}
+
+#[cfg(test)]
+fn received_datagrams<T: DatagramTransport>(transport: &T) -> Vec<Box<[u8]>> {
+    let mut datagrams = Vec::new();
+    let mut buffer = [0u8; 64];
+    while let Ok((size, _)) = transport.recv_from(&mut buffer) {
+        datagrams.push(buffer[..size].into());
+    }
+    datagrams
+}
+
+#[test]
+fn link_conditioner_loss_and_duplication() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let peer_addr = transport1.peer_addr();
+    let mut conditioner = LinkConditioner::new(transport1, LinkConditions { loss: 1.0, .. Default::default() }, 42);
+    conditioner.send_to(&[1], &peer_addr).unwrap();
+    assert!(received_datagrams(&transport2).is_empty());
+    conditioner.set_conditions(LinkConditions { duplication: 1.0, .. Default::default() });
+    conditioner.send_to(&[2], &peer_addr).unwrap();
+    assert_eq!(received_datagrams(&transport2), vec![Box::from(&[2u8][..]), Box::from(&[2u8][..])]);
+}
+
+#[test]
+fn link_conditioner_latency_and_reordering() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let peer_addr = transport1.peer_addr();
+    let clock = MockClock::new();
+    let conditions = LinkConditions { latency: Duration::from_millis(20), .. Default::default() };
+    let mut conditioner = LinkConditioner::with_clock(transport1, conditions, 42, Box::new(clock.clone()));
+    conditioner.send_to(&[1], &peer_addr).unwrap();
+    clock.advance(Duration::from_millis(19));
+    conditioner.flush(clock.now());
+    assert!(received_datagrams(&transport2).is_empty());
+    assert_eq!(conditioner.delayed_datagrams(), 1);
+    clock.advance(Duration::from_millis(1));
+    conditioner.flush(clock.now());
+    assert_eq!(received_datagrams(&transport2), vec![Box::from(&[1u8][..])]);
+
+    conditioner.set_conditions(LinkConditions { reordering: 1.0, .. Default::default() });
+    conditioner.send_to(&[2], &peer_addr).unwrap();
+    conditioner.set_conditions(Default::default());
+    conditioner.send_to(&[3], &peer_addr).unwrap();
+    clock.advance(Duration::from_millis(20));
+    conditioner.flush(clock.now());
+    assert_eq!(received_datagrams(&transport2), vec![Box::from(&[3u8][..]), Box::from(&[2u8][..])]);
+}
+
+#[test]
+fn link_conditioner_is_reproducible() {
+    use transport::ChannelTransport;
+    let conditions = LinkConditions { loss: 0.5, corruption: 0.5, .. Default::default() };
+    let run = || {
+        let (transport1, transport2) = ChannelTransport::pair();
+        let peer_addr = transport1.peer_addr();
+        let conditioner = LinkConditioner::new(transport1, conditions, 7);
+        for i in 0..64u8 {
+            conditioner.send_to(&[i; 8], &peer_addr).unwrap();
+        }
+        received_datagrams(&transport2)
+    };
+    let datagrams = run();
+    assert!(!datagrams.is_empty() && datagrams.len() < 64);
+    assert_eq!(datagrams, run());
+}
@@ -4,11 +4,20 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::{spawn as spawn_thread, Thread, JoinHandle};
 use std::sync::mpsc::{Receiver, Sender, channel, TryRecvError};
-use std::net::{UdpSocket, ToSocketAddrs, SocketAddr};
+use std::net::{UdpSocket, ToSocketAddrs, SocketAddr, IpAddr};
 use std::time::Duration;
 use std::ops::Deref;
+use std::io::ErrorKind;
 
-use socket::{RemoteID, Socket, MessageType};
+use socket::{RemoteID, Socket, MessageType, SocketError, SocketErrorKind, SocketEvent};
+use stats::{RemoteStats, SocketStats};
+use ack::MessageToken;
+use pool::Payload;
+use limiter::RateLimits;
+use crypto::PreSharedKey;
+use checksum::Checksum;
+use token::{ConnectToken, ConnectTokenData};
+use udp_message::RejectReason;
 
 #[derive(Debug)]
 pub enum ConnectionMainThreadFatalError {}
@@ -16,29 +25,123 @@ pub enum ConnectionMainThreadFatalError {}
 pub enum ConnectionRecvError {}
 
 #[derive(Debug)]
-pub struct InData(pub RemoteID, pub Box<[u8]>);
+pub struct InData(pub RemoteID, pub Payload);
+
+/// Who outgoing data is sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Remote(RemoteID),
+    /// See `Socket::broadcast_message`
+    AllRemotes,
+    /// See `Socket::send_group_message`
+    Group(String),
+}
 
 #[derive(Debug)]
 pub struct OutData<B: AsRef<[u8]> + Sync + Send> {
-    pub remote_id: RemoteID,
+    pub destination: Destination,
     pub data: B,
     pub priority: i8,
-    pub message_type: MessageType
+    pub message_type: MessageType,
+    pub token: MessageToken,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum InEvent {
     /// bool means "initiated by remote", so true if it
-    /// was intiated by remote, false if we made the request ourselves
-    NewConnectionFrom(SocketAddr, RemoteID, bool),
-    /// RemoteID was disconnected
-    Disconnected(RemoteID)
+    /// was intiated by remote, false if we made the request ourselves.
+    ///
+    /// The last field is what the connect token of the remote held, when they are required.
+    NewConnectionFrom(SocketAddr, RemoteID, bool, Option<ConnectTokenData>),
+    /// RemoteID was disconnected, or did not send anything for too long
+    Disconnected(RemoteID),
+    /// RemoteID moved to this address, after its NAT rebound it or its network changed
+    Migrated(RemoteID, SocketAddr),
+    /// RemoteID did not send anything for too long, its session may still be resumed,
+    /// see `Socket::set_resumption_grace`
+    Suspended(RemoteID),
+    /// The session of RemoteID was resumed
+    Resumed(RemoteID),
+    /// A connection request to this address could not be made, or the remote
+    /// did not answer in time (`ErrorKind::TimedOut`)
+    ConnectFailed(SocketAddr, ErrorKind),
+    /// The remote at this address refused the connection
+    ConnectRejected(SocketAddr, RejectReason),
+    /// A message to RemoteID could not be sent
+    SendFailed(RemoteID, SocketErrorKind),
+    /// The message with this token could not be sent to any remote of a broadcast or a group
+    BroadcastFailed(MessageToken, SocketErrorKind),
+    /// The OS reported that RemoteID could not be reached (ICMP port unreachable)
+    RemoteUnreachable(RemoteID),
+    /// Receiving incoming messages failed. The connection keeps running, but some
+    /// messages may have been lost.
+    ReceiveFailed(ErrorKind),
+    /// Answer to `OutEvent::RequestStats`
+    Stats(SocketStats),
+    /// Answer to `OutEvent::RequestRemoteStats`. The stats are None if RemoteID is not a valid remote.
+    RemoteStats(RemoteID, Option<RemoteStats>),
+    /// The message with this token was entirely received by RemoteID
+    Acked(RemoteID, MessageToken),
+    /// The message with this token was not acknowledged in time by RemoteID, and is considered lost
+    Lost(RemoteID, MessageToken),
 }
 
-#[derive(Debug, Clone, Copy)]
+impl InEvent {
+    /// Converts an event of the socket, whose remotes are looked up to find their address.
+    ///
+    /// Returns None if the event is about a remote that doesn't exist anymore.
+    pub (crate) fn from_socket_event(socket: &Socket, event: SocketEvent) -> Option<InEvent> {
+        Some(match event {
+            SocketEvent::ReceiveFailed(kind) => InEvent::ReceiveFailed(kind),
+            SocketEvent::SendFailed(remote_id, SocketErrorKind::RemoteUnreachable) => InEvent::RemoteUnreachable(remote_id),
+            SocketEvent::SendFailed(remote_id, kind) => InEvent::SendFailed(remote_id, kind),
+            SocketEvent::Acked(remote_id, token) => InEvent::Acked(remote_id, token),
+            SocketEvent::Lost(remote_id, token) => InEvent::Lost(remote_id, token),
+            SocketEvent::Connected(remote_id) => {
+                InEvent::NewConnectionFrom(socket.remote_addr(remote_id).ok()?, remote_id, false, socket.connect_token_data(remote_id).ok()?)
+            },
+            SocketEvent::NewRemote(remote_id) => {
+                InEvent::NewConnectionFrom(socket.remote_addr(remote_id).ok()?, remote_id, true, socket.connect_token_data(remote_id).ok()?)
+            },
+            SocketEvent::ConnectFailed(remote_id) => InEvent::ConnectFailed(socket.remote_addr(remote_id).ok()?, ErrorKind::TimedOut),
+            SocketEvent::ConnectRejected(remote_id, reason) => InEvent::ConnectRejected(socket.remote_addr(remote_id).ok()?, reason),
+            SocketEvent::Disconnected(remote_id) | SocketEvent::TimedOut(remote_id) | SocketEvent::KeysExhausted(remote_id) => InEvent::Disconnected(remote_id),
+            SocketEvent::Migrated(remote_id) => InEvent::Migrated(remote_id, socket.remote_addr(remote_id).ok()?),
+            SocketEvent::Suspended(remote_id) => InEvent::Suspended(remote_id),
+            SocketEvent::Resumed(remote_id) => InEvent::Resumed(remote_id),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum OutEvent {
     NewConnection(SocketAddr),
+    /// See `Socket::connect_with_token`
+    NewConnectionWithToken(SocketAddr, ConnectToken),
     Disconnect(RemoteID),
+    /// Ask for the statistics of the whole socket, answered by `InEvent::Stats`
+    RequestStats,
+    /// Ask for the statistics of one remote, answered by `InEvent::RemoteStats`
+    RequestRemoteStats(RemoteID),
+    /// Drop everything sent by this IP, for a while or forever if the duration is None.
+    /// Its remotes are disconnected.
+    BanIp(IpAddr, Option<Duration>),
+    UnbanIp(IpAddr),
+    /// Ban the IP of a remote, see `BanIp`
+    BanRemote(RemoteID, Option<Duration>),
+    SetRateLimits(RateLimits),
+    /// See `Socket::set_pre_shared_key`
+    SetPreSharedKey(Option<PreSharedKey>),
+    /// See `Socket::set_checksum`
+    SetChecksum(Checksum),
+    /// See `Socket::set_max_remotes`
+    SetMaxRemotes(usize),
+    /// See `Socket::set_resumption_grace`
+    SetResumptionGrace(Option<Duration>),
+    /// See `Socket::join_group`
+    JoinGroup(String, RemoteID),
+    /// See `Socket::leave_group`
+    LeaveGroup(String, RemoteID),
 }
 
 #[derive(Debug)]
@@ -49,9 +152,10 @@ pub struct Connection<O: AsRef<[u8]> + Sync + Send> {
     incoming_event_receiver: Receiver<InEvent>,
     outgoing_data_sender: Sender<OutData<O>>,
     outgoing_event_sender: Sender<OutEvent>,
+    next_token: u64,
 }
 
-struct ConnectionThreadContext<O: AsRef<[u8]> + Sync + Send> {
+pub (crate) struct ConnectionThreadContext<O: AsRef<[u8]> + Sync + Send> {
     pub socket: Socket,
     pub in_data_sender: Sender<InData>,
     pub in_event_sender: Sender<InEvent>,
@@ -64,19 +168,23 @@ struct ConnectionThreadContext<O: AsRef<[u8]> + Sync + Send> {
 impl<O: AsRef<[u8]> + Sync + Send> ConnectionThreadContext<O> {
     fn send_event_to_main(&self, event: InEvent) { 
         let r = self.in_event_sender.send(event);
-        if let Err(_) = r {
+        if r.is_err() {
+            warn!("main thread stopped listening for events, shutting down");
             self.shutdown();
         }
     }
 
-    fn start(mut self) -> Result<(), ConnectionMainThreadFatalError> {
+    pub fn start(mut self) -> Result<(), ConnectionMainThreadFatalError> {
         let poll_interval = Duration::from_millis(10);
+        debug!("connection_main_thread started");
         while !self.should_stop.load(Ordering::Relaxed) {
             self.process_outgoing_events();
             self.send_outgoing();
             self.receive_incoming();
+            self.process_socket_events();
             ::std::thread::sleep(poll_interval);
         }
+        debug!("connection_main_thread stopped");
         Ok(())
     }
 
@@ -89,15 +197,51 @@ impl<O: AsRef<[u8]> + Sync + Send> ConnectionThreadContext<O> {
                     break;
                 },
                 Ok(OutEvent::NewConnection(socket_addr)) => {
-                    let r = self.socket.try_connect(socket_addr);
-                    match r {
-                        Ok(remote_id) => self.send_event_to_main(InEvent::NewConnectionFrom(socket_addr, remote_id, false)),
-                        Err(e) => println!("process_outgoing_events: NewConnection got error {:?}", e),
+                    // NewConnectionFrom is sent once the remote accepts the connection
+                    if let Err(e) = self.socket.try_connect(socket_addr) {
+                        self.send_event_to_main(InEvent::ConnectFailed(socket_addr, e.kind()));
                     }
                 },
+                Ok(OutEvent::NewConnectionWithToken(socket_addr, token)) => {
+                    self.socket.connect_with_token(socket_addr, token);
+                },
                 Ok(OutEvent::Disconnect(remote_id)) => {
-                    unimplemented!()
-                }
+                    // the remote may already be gone, there is nothing left to do then
+                    let _r = self.socket.disconnect(remote_id);
+                },
+                Ok(OutEvent::RequestStats) => {
+                    let stats = self.socket.stats();
+                    self.send_event_to_main(InEvent::Stats(stats));
+                },
+                Ok(OutEvent::RequestRemoteStats(remote_id)) => {
+                    let stats = self.socket.remote_stats(remote_id).ok();
+                    self.send_event_to_main(InEvent::RemoteStats(remote_id, stats));
+                },
+                Ok(OutEvent::BanIp(ip, duration)) => self.socket.ban_ip(ip, duration),
+                Ok(OutEvent::UnbanIp(ip)) => {
+                    self.socket.unban_ip(ip);
+                },
+                Ok(OutEvent::BanRemote(remote_id, duration)) => {
+                    // the remote may already be gone, there is nothing to ban then
+                    let _r = self.socket.ban_remote(remote_id, duration);
+                },
+                Ok(OutEvent::SetRateLimits(limits)) => self.socket.set_rate_limits(limits),
+                Ok(OutEvent::SetPreSharedKey(psk)) => self.socket.set_pre_shared_key(psk),
+                Ok(OutEvent::SetChecksum(checksum)) => {
+                    if let Err(e) = self.socket.set_checksum(checksum) {
+                        warn!("checksum {:?} refused: {}", checksum, e);
+                    }
+                },
+                Ok(OutEvent::SetMaxRemotes(max_remotes)) => self.socket.set_max_remotes(max_remotes),
+                Ok(OutEvent::SetResumptionGrace(grace)) => self.socket.set_resumption_grace(grace),
+                Ok(OutEvent::JoinGroup(group, remote_id)) => {
+                    if let Err(e) = self.socket.join_group(&group, remote_id) {
+                        self.send_event_to_main(InEvent::SendFailed(remote_id, e.kind()));
+                    }
+                },
+                Ok(OutEvent::LeaveGroup(group, remote_id)) => {
+                    self.socket.leave_group(&group, remote_id);
+                },
             }
         }
     }
@@ -105,12 +249,13 @@ impl<O: AsRef<[u8]> + Sync + Send> ConnectionThreadContext<O> {
     fn receive_incoming(&mut self) {
         'all_remotes: for (remote_id, remote_messages) in self.socket.receive_all_messages() {
             for message in remote_messages {
-                let r = self.in_data_sender.send(InData(remote_id.clone(), message));
+                let r = self.in_data_sender.send(InData(remote_id, message));
 
-                if let Err(_) = r {
+                if r.is_err() {
                     // if there is an error while sending the messages to the main thread,
                     // there's not point in continuing. It probably means that the main thread panicked
-                    // somehow. TODO: log?
+                    // somehow.
+                    warn!("main thread stopped listening for incoming data, shutting down");
                     self.shutdown();
                     break 'all_remotes;
                 }
@@ -126,12 +271,35 @@ impl<O: AsRef<[u8]> + Sync + Send> ConnectionThreadContext<O> {
                     self.shutdown();
                     break;
                 },
-                Ok(OutData { remote_id, data, priority, message_type}) => {
-                    let r = self.socket.send_message(remote_id, data.as_ref(), message_type, priority);
+                Ok(OutData { destination: Destination::Remote(remote_id), data, priority, message_type, token }) => {
+                    let r = self.socket.send_message_with_token(remote_id, data.as_ref(), message_type, priority, token);
+                    match r {
+                        Ok(()) => {},
+                        Err(SocketError::RemoteUnreachable(remote_id)) => {
+                            self.send_event_to_main(InEvent::RemoteUnreachable(remote_id))
+                        },
+                        Err(e) => self.send_event_to_main(InEvent::SendFailed(remote_id, e.kind())),
+                    }
+                },
+                Ok(OutData { destination, data, priority, message_type, token }) => {
+                    // failures to send to single remotes are reported as socket events
+                    let r = match destination {
+                        Destination::Group(ref group) => self.socket.send_group_message_with_token(group, data.as_ref(), message_type, priority, token),
+                        _ => self.socket.broadcast_message_with_token(data.as_ref(), message_type, priority, token),
+                    };
                     if let Err(e) = r {
-                        println!("Error while sendng message to {}: {:?}", remote_id, e);
+                        self.send_event_to_main(InEvent::BroadcastFailed(token, e.kind()));
                     }
-                }
+                },
+            }
+        }
+    }
+
+    /// Forwards the events queued inside the socket to the main thread
+    fn process_socket_events(&mut self) {
+        while let Some(event) = self.socket.next_event() {
+            if let Some(event) = InEvent::from_socket_event(&self.socket, event) {
+                self.send_event_to_main(event);
             }
         }
     }
@@ -173,6 +341,7 @@ impl<O: AsRef<[u8]> + Sync + Send + 'static> Connection<O> {
             incoming_event_receiver: in_event_receiver,
             outgoing_data_sender: out_data_sender,
             outgoing_event_sender: out_event_sender,
+            next_token: 0,
         })
     }
 
@@ -180,28 +349,48 @@ impl<O: AsRef<[u8]> + Sync + Send + 'static> Connection<O> {
     pub fn shutdown(self) -> Result<(), ConnectionMainThreadFatalError> {
         // TODO disconnect the remote thread's remote 
         self.should_stop.as_ref().store(true, Ordering::Relaxed);
-        let r = self.thread_handle.join().unwrap();
-        r
+        self.thread_handle.join().unwrap()
+    }
+
+    /// Sends data to a remote.
+    ///
+    /// The returned token will be given back by an `InEvent::Acked` or an `InEvent::Lost` event,
+    /// depending on whether the remote received the data or not.
+    pub fn send_data(&mut self, remote_id: RemoteID, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        self.send_data_to(Destination::Remote(remote_id), data, message_type, priority)
+    }
+
+    /// Sends data to every connected remote, see `Socket::broadcast_message`.
+    ///
+    /// Each remote gives the token back with its own `InEvent::Acked` or `InEvent::Lost` event.
+    pub fn broadcast_data(&mut self, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        self.send_data_to(Destination::AllRemotes, data, message_type, priority)
+    }
+
+    /// Sends data to the remotes of a group, see `Socket::send_group_message`
+    pub fn send_group_data(&mut self, group: &str, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        self.send_data_to(Destination::Group(group.to_owned()), data, message_type, priority)
     }
 
-    pub fn send_data(&mut self, remote_id: RemoteID, data: O, message_type: MessageType, priority: i8) {
+    /// Sends data to a remote, to every remote or to a group
+    pub fn send_data_to(&mut self, destination: Destination, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        let token = MessageToken(self.next_token);
+        self.next_token += 1;
         self.outgoing_data_sender.send(OutData {
-            remote_id,
+            destination,
             data,
             message_type,
-            priority
-        }).expect("could not connect to remote thread")
+            priority,
+            token,
+        }).expect("could not connect to remote thread");
+        token
     }
     
-    pub fn send_forgettable_data(&mut self, remote_id: RemoteID, data: O) {
-        self.outgoing_data_sender.send(OutData {
-            remote_id,
-            data,
-            message_type: MessageType::Forgettable,
-            priority: 0
-        }).expect("could not connect to remote thread")
+    pub fn send_forgettable_data(&mut self, remote_id: RemoteID, data: O) -> MessageToken {
+        self.send_data(remote_id, data, MessageType::Forgettable, 0)
     }
 
+    #[allow(clippy::result_unit_err)]
     pub fn receive_data(&mut self) -> Result<Option<InData>, ()> {
         match self.incoming_data_receiver.try_recv() {
             Err(TryRecvError::Empty) => Ok(None),
@@ -210,6 +399,7 @@ impl<O: AsRef<[u8]> + Sync + Send + 'static> Connection<O> {
         }
     }
     
+    #[allow(clippy::result_unit_err)]
     pub fn receive_event(&mut self) -> Result<Option<InEvent>, ()> {
         match self.incoming_event_receiver.try_recv() {
             Err(TryRecvError::Empty) => Ok(None),
@@ -223,7 +413,24 @@ impl<O: AsRef<[u8]> + Sync + Send + 'static> Connection<O> {
         self.outgoing_event_sender.send(event).expect("could not connect to remote thread");
     }
 
+    /// Asks for the statistics of the whole connection. They will be received as `InEvent::Stats`.
+    ///
+    /// Returns Error when the remote thread was killed
+    #[allow(clippy::result_unit_err)]
+    pub fn request_stats(&mut self) -> Result<(), ()> {
+        self.outgoing_event_sender.send(OutEvent::RequestStats).map_err(|_| ())
+    }
+
+    /// Asks for the statistics of one remote. They will be received as `InEvent::RemoteStats`.
+    ///
+    /// Returns Error when the remote thread was killed
+    #[allow(clippy::result_unit_err)]
+    pub fn request_remote_stats(&mut self, remote_id: RemoteID) -> Result<(), ()> {
+        self.outgoing_event_sender.send(OutEvent::RequestRemoteStats(remote_id)).map_err(|_| ())
+    }
+
     // Returns Error when the remote thread was killed
+    #[allow(clippy::result_unit_err)]
     pub fn try_connect<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), ()> {
         let socket_addr = addr.to_socket_addrs().unwrap().next().unwrap();
         let r = self.outgoing_event_sender.send(OutEvent::NewConnection(socket_addr));
@@ -238,7 +445,38 @@ impl<O: AsRef<[u8]> + Sync + Send + 'static> Connection<O> {
 fn connection_init_destroy() {
     let connection = Connection::<Box<[u8]>>::new("0.0.0.0:0").unwrap();
     ::std::thread::sleep(::std::time::Duration::from_millis(10));
-    let _handle = connection.shutdown().unwrap();
+    connection.shutdown().unwrap();
+}
+
+#[test]
+fn connection_send_invalid_remote() {
+    let mut connection = Connection::<Box<[u8]>>::new("0.0.0.0:0").unwrap();
+    let remote_id = RemoteID::new(42, 0);
+    connection.send_forgettable_data(remote_id, Box::new([1u8, 2, 3]));
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+    match connection.receive_event().unwrap() {
+        Some(InEvent::SendFailed(id, SocketErrorKind::InvalidRemoteId)) => assert_eq!(id, remote_id),
+        e => panic!("expected a SendFailed event, got {:?}", e),
+    }
+    connection.shutdown().unwrap();
+}
+
+#[test]
+fn connection_request_stats() {
+    let mut connection = Connection::<Box<[u8]>>::new("0.0.0.0:0").unwrap();
+    let remote_id = RemoteID::new(42, 0);
+    connection.request_stats().unwrap();
+    connection.request_remote_stats(remote_id).unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(50));
+    match connection.receive_event().unwrap() {
+        Some(InEvent::Stats(stats)) => assert_eq!(stats.remotes, 0),
+        e => panic!("expected a Stats event, got {:?}", e),
+    }
+    match connection.receive_event().unwrap() {
+        Some(InEvent::RemoteStats(id, None)) => assert_eq!(id, remote_id),
+        e => panic!("expected a RemoteStats event, got {:?}", e),
+    }
+    connection.shutdown().unwrap();
 }
 
 #[cfg(test)]
@@ -1,24 +1,82 @@
+use std::time::Duration;
 
-pub (crate) const CRC32_SIZE: usize = 4;
+
+// 1 byte at the start of every packet to tell what kind of packet this is (fragment, ack, ...)
+pub (crate) const PACKET_TYPE_SIZE: usize = 1;
+
+// the largest checksum at the end of a packet, a 64-bit hash
+pub (crate) const MAX_CHECKSUM_SIZE: usize = 8;
 
 // 4 bytes for the seq_id, 1 for the frag_id, 1 for the frag_total
 pub (crate) const FRAG_HEADER_SIZE: usize = 4 + 1 + 1;
 
+// 4 bytes for the acked seq_id, 8 for the bitfield of received fragments
+pub (crate) const ACK_SIZE: usize = 4 + 8;
+
 // 1024 + 256 is an arbitrary value below most common MTU values
-// since the baseline is around 1400, 1280 for the "inner" message + udp message header of 10 bytes
-// is not too bad, although we could do better.
-pub (crate) const MAX_UDP_MESSAGE_SIZE: usize = 1024 + 256 + CRC32_SIZE + FRAG_HEADER_SIZE;
+// since the baseline is around 1400, 1280 for the "inner" message + udp message header and
+// checksum of up to 15 bytes is not too bad, although we could do better.
+pub (crate) const MAX_UDP_MESSAGE_SIZE: usize = 1024 + 256 + PACKET_TYPE_SIZE + FRAG_HEADER_SIZE + MAX_CHECKSUM_SIZE;
+
+// an encrypted udp message is followed by a 16 bytes authentication tag and its 4 bytes sequence
+// number instead of a checksum, and the connection id of the receiver once it is known
+pub (crate) const MAX_DATAGRAM_SIZE: usize = MAX_UDP_MESSAGE_SIZE + 16 + 8;
+
+/// The maximum amount of unused buffers a Socket keeps to receive and send datagrams in
+pub (crate) const MAX_POOLED_BUFFERS: usize = 256;
+
+/// How many datagrams a Socket asks its transport to receive at once
+pub (crate) const RECEIVE_BATCH_SIZE: usize = 32;
 
 // we limit the amount of fragments to 64 here, because we would like to code ack messages
 // on 64bits (1 bit per fragment received), thus having only 1 message for 1 seq_id
 // this *should* be enough for fast paced games, as you can send up to 81KB in 1 sequence
 pub (crate) const MAX_FRAGMENTS_IN_MESSAGE: usize = 64;
 
+/// The maximum amount of seq_ids a remote remembers the received fragments of, to acknowledge them.
+pub (crate) const MAX_ACKED_MESSAGES: usize = 256;
+
+/// How long a sent message can stay unacknowledged before being considered lost,
+/// when the round trip time to the remote is not known yet.
+pub (crate) const DEFAULT_LOST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Lower bound of the time a message can stay unacknowledged before being considered lost.
+///
+/// Once the round trip time is known, messages are considered lost after 4 round trips.
+pub (crate) const MIN_LOST_TIMEOUT: Duration = Duration::from_millis(100);
+
 /// The amount of time in ms a Socket should passively wait before the next loop iteration.
 pub (crate) const POLL_INTERVAL: u32 = 10;
 
-/// The amount fo iterations a Socket should do at least to consider a "Connecting" or "AckConnecting"
+/// How long a Socket waits for an answer before considering a "Connecting" or "AckConnecting"
 /// status failed
-///
-/// I'm not too happy with the name, I'm sure it could be named better.
-pub (crate) const CONNECT_ABANDON_TOTAL_ITERATIONS: u32 = 10_000 / 10;
\ No newline at end of file
+pub (crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a ConnectRequest is sent again while waiting for an answer
+pub (crate) const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A Heartbeat is sent to a connected remote when nothing else was sent to it for this long
+pub (crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// A connected remote is disconnected when nothing was received from it for this long
+pub (crate) const REMOTE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many remotes a Socket accepts by default, see `Socket::set_max_remotes`
+pub (crate) const DEFAULT_MAX_REMOTES: usize = 1024;
+
+/// How many encrypted packets back a remote remembers having received, to drop the packets
+/// that are sent again by someone else
+pub (crate) const REPLAY_WINDOW_SIZE: usize = 256;
+
+/// How often a new address of a remote is challenged again, while it doesn't answer
+pub (crate) const PATH_CHALLENGE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the cookie sent in a ConnectChallenge can be sent back to us
+pub (crate) const COOKIE_LIFETIME: Duration = Duration::from_secs(5);
+
+/// Maximum amount of source addresses a Socket keeps rate limits for. Past this, datagrams
+/// from new addresses are dropped until some addresses are forgotten.
+pub (crate) const MAX_RATE_LIMITED_ADDRESSES: usize = 65536;
+
+/// How often the rate limits of addresses that stopped sending are forgotten
+pub (crate) const RATE_LIMITER_PRUNE_INTERVAL: Duration = Duration::from_secs(1);
@@ -0,0 +1,108 @@
+//! Stateless cookies for the connection handshake.
+//!
+//! A server answers a ConnectRequest with a cookie instead of allocating a remote right away,
+//! and only accepts the connection once the cookie comes back. Since the cookie is sent to the
+//! address the request claims to come from, a client spoofing its address never sees it.
+
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use consts::COOKIE_LIFETIME;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 8 bytes for the timestamp, 16 for the truncated HMAC-SHA256 of the address and the timestamp
+pub (crate) const COOKIE_SIZE: usize = 8 + 16;
+
+pub (crate) type Cookie = [u8; COOKIE_SIZE];
+
+/// Feeds whatever `Hash` writes into a HMAC, so any transport address can be authenticated.
+struct MacHasher<'a>(&'a mut HmacSha256);
+
+impl<'a> Hasher for MacHasher<'a> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("only used to feed the HMAC")
+    }
+}
+
+/// Generates and verifies the cookies of a Socket with a random key.
+///
+/// Cookies are only valid for the CookieGenerator that created them, and for `COOKIE_LIFETIME`.
+#[derive(Debug)]
+pub (crate) struct CookieGenerator {
+    key: [u8; 32],
+    /// cookie timestamps are in milliseconds since this instant
+    epoch: Instant,
+}
+
+impl CookieGenerator {
+    pub fn new(now: Instant) -> CookieGenerator {
+        let mut key = [0u8; 32];
+        getrandom::getrandom(&mut key).expect("failed to generate a random cookie key");
+        CookieGenerator { key, epoch: now }
+    }
+
+    fn mac<A: Hash>(&self, addr: &A, timestamp: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(timestamp);
+        addr.hash(&mut MacHasher(&mut mac));
+        mac
+    }
+
+    /// Creates a cookie for a client at address `addr`
+    pub fn generate<A: Hash>(&self, addr: &A, now: Instant) -> Cookie {
+        let mut cookie = [0u8; COOKIE_SIZE];
+        let timestamp = now.duration_since(self.epoch).as_millis() as u64;
+        BigEndian::write_u64(&mut cookie[..8], timestamp);
+        let tag = self.mac(addr, &cookie[..8]).finalize().into_bytes();
+        cookie[8..].copy_from_slice(&tag[..COOKIE_SIZE - 8]);
+        cookie
+    }
+
+    /// Returns true if `cookie` was generated by us for `addr`, and is not too old
+    pub fn verify<A: Hash>(&self, cookie: &Cookie, addr: &A, now: Instant) -> bool {
+        // the timestamp comes from anyone, it may be far past anything an Instant can hold
+        let created_at = match self.epoch.checked_add(Duration::from_millis(BigEndian::read_u64(&cookie[..8]))) {
+            Some(created_at) => created_at,
+            None => return false,
+        };
+        if created_at > now || now.duration_since(created_at) > COOKIE_LIFETIME {
+            return false;
+        }
+        self.mac(addr, &cookie[..8]).verify_truncated_left(&cookie[8..]).is_ok()
+    }
+}
+
+#[test]
+fn cookie_verification() {
+    let start = Instant::now();
+    let generator = CookieGenerator::new(start);
+    let addr: ::std::net::SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let other_addr: ::std::net::SocketAddr = "127.0.0.1:4001".parse().unwrap();
+    let cookie = generator.generate(&addr, start + Duration::from_millis(10));
+    assert!(generator.verify(&cookie, &addr, start + Duration::from_millis(10)));
+    assert!(generator.verify(&cookie, &addr, start + Duration::from_millis(10) + COOKIE_LIFETIME));
+    assert!(!generator.verify(&cookie, &addr, start + Duration::from_millis(11) + COOKIE_LIFETIME));
+    assert!(!generator.verify(&cookie, &other_addr, start + Duration::from_millis(10)));
+    // another generator has another key
+    assert!(!CookieGenerator::new(start).verify(&cookie, &addr, start + Duration::from_millis(10)));
+
+    let mut forged = cookie;
+    forged[COOKIE_SIZE - 1] ^= 1;
+    assert!(!generator.verify(&forged, &addr, start + Duration::from_millis(10)));
+    // a client can't make its cookie last longer
+    let mut extended = cookie;
+    BigEndian::write_u64(&mut extended[..8], 5000);
+    assert!(!generator.verify(&extended, &addr, start + Duration::from_millis(5000)));
+    let mut overflowing = cookie;
+    BigEndian::write_u64(&mut overflowing[..8], u64::MAX);
+    assert!(!generator.verify(&overflowing, &addr, start + Duration::from_millis(10)));
+}
@@ -0,0 +1,145 @@
+//! Authenticated encryption of the packets of a connection.
+//!
+//! Both ends share a key: the pre-shared key, or the session key of a connect token. During the
+//! handshake each end sends a random salt, and proves it knows the shared key with a HMAC. The
+//! keys of the connection, one per direction, are derived from the shared key and both salts, so
+//! they are different for every connection.
+
+use std::fmt;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A key known by both ends of a connection, used to authenticate the handshake and
+/// to derive the keys of the connection.
+pub type PreSharedKey = [u8; 32];
+
+pub (crate) const SALT_SIZE: usize = 16;
+/// Size of the truncated HMAC-SHA256 proving that an end knows the shared key
+pub (crate) const HANDSHAKE_MAC_SIZE: usize = 16;
+/// Size of the Poly1305 tag appended to every encrypted packet
+pub (crate) const AEAD_TAG_SIZE: usize = 16;
+
+/// What the HMAC of a ConnectResponse and of a ConnectAccept start with, so that one can't be
+/// replayed as the other
+pub (crate) const RESPONSE_LABEL: &[u8] = b"kestrel connect response";
+pub (crate) const ACCEPT_LABEL: &[u8] = b"kestrel connect accept";
+
+pub (crate) type Salt = [u8; SALT_SIZE];
+pub (crate) type HandshakeMac = [u8; HANDSHAKE_MAC_SIZE];
+
+pub (crate) fn random_salt() -> Salt {
+    let mut salt = [0u8; SALT_SIZE];
+    getrandom::getrandom(&mut salt).expect("failed to generate a random salt");
+    salt
+}
+
+fn hmac(key: &[u8], label: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).unwrap();
+    mac.update(label);
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// Proves that we know `key`, for the handshake packet identified by `label` and holding `parts`
+pub (crate) fn handshake_mac(key: &PreSharedKey, label: &[u8], parts: &[&[u8]]) -> HandshakeMac {
+    let mut handshake_mac = [0u8; HANDSHAKE_MAC_SIZE];
+    handshake_mac.copy_from_slice(&hmac(key, label, parts).finalize().into_bytes()[..HANDSHAKE_MAC_SIZE]);
+    handshake_mac
+}
+
+pub (crate) fn verify_handshake_mac(key: &PreSharedKey, label: &[u8], parts: &[&[u8]], handshake_mac: &HandshakeMac) -> bool {
+    hmac(key, label, parts).verify_truncated_left(handshake_mac).is_ok()
+}
+
+/// The keys of one connection: one to seal what we send, one to open what we receive.
+pub (crate) struct SessionKeys {
+    sealing: ChaCha20Poly1305,
+    opening: ChaCha20Poly1305,
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SessionKeys { .. }")
+    }
+}
+
+fn direction_key(key: &PreSharedKey, sender_salt: &Salt, receiver_salt: &Salt) -> ChaCha20Poly1305 {
+    let derived = hmac(key, b"kestrel session key", &[sender_salt, receiver_salt]).finalize().into_bytes();
+    ChaCha20Poly1305::new(Key::from_slice(&derived))
+}
+
+/// Packets are numbered per direction, so each sequence number is used once per key
+fn nonce(seq: u32) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[8..].copy_from_slice(&seq.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+impl SessionKeys {
+    /// `salt` is the one we sent during the handshake, `remote_salt` the one the remote sent
+    pub fn derive(key: &PreSharedKey, salt: &Salt, remote_salt: &Salt) -> SessionKeys {
+        SessionKeys {
+            sealing: direction_key(key, salt, remote_salt),
+            opening: direction_key(key, remote_salt, salt),
+        }
+    }
+
+    /// Encrypts everything after the first `header_size` bytes of `buffer`, and appends
+    /// a tag authenticating the whole buffer.
+    pub fn seal(&self, buffer: &mut Vec<u8>, header_size: usize, seq: u32) {
+        let (header, payload) = buffer.split_at_mut(header_size);
+        let tag = self.sealing.encrypt_in_place_detached(&nonce(seq), header, payload)
+            .expect("packets are always small enough to be encrypted");
+        buffer.extend_from_slice(&tag);
+    }
+
+    /// Reverts `seal`, the tag is removed from `buffer`. Fails if anything was modified on the way.
+    pub fn open(&self, buffer: &mut Vec<u8>, header_size: usize, seq: u32) -> Result<(), ()> {
+        if buffer.len() < header_size + AEAD_TAG_SIZE {
+            return Err(());
+        }
+        let tag_offset = buffer.len() - AEAD_TAG_SIZE;
+        let tag = *Tag::from_slice(&buffer[tag_offset..]);
+        buffer.truncate(tag_offset);
+        let (header, payload) = buffer.split_at_mut(header_size);
+        self.opening.decrypt_in_place_detached(&nonce(seq), header, payload, &tag).map_err(|_| ())
+    }
+}
+
+#[test]
+fn session_keys_seal_open() {
+    let key = [42u8; 32];
+    let (salt1, salt2) = (random_salt(), random_salt());
+    let keys1 = SessionKeys::derive(&key, &salt1, &salt2);
+    let keys2 = SessionKeys::derive(&key, &salt2, &salt1);
+    let mut buffer = vec![1, 2, 3, 4, 5, 6];
+    keys1.seal(&mut buffer, 2, 7);
+    assert_eq!(buffer.len(), 6 + AEAD_TAG_SIZE);
+    assert_ne!(&buffer[2..6], &[3, 4, 5, 6]);
+
+    let mut opened = buffer.clone();
+    keys2.open(&mut opened, 2, 7).unwrap();
+    assert_eq!(opened, vec![1, 2, 3, 4, 5, 6]);
+    // the wrong sequence number, a modified header, or our own key can't open it
+    assert!(keys2.open(&mut buffer.clone(), 2, 8).is_err());
+    let mut modified = buffer.clone();
+    modified[0] ^= 1;
+    assert!(keys2.open(&mut modified, 2, 7).is_err());
+    assert!(keys1.open(&mut buffer.clone(), 2, 7).is_err());
+}
+
+#[test]
+fn handshake_mac_verification() {
+    let key = [1u8; 32];
+    let mac = handshake_mac(&key, b"label", &[&[1, 2], &[3]]);
+    assert!(verify_handshake_mac(&key, b"label", &[&[1, 2], &[3]], &mac));
+    assert!(!verify_handshake_mac(&key, b"other label", &[&[1, 2], &[3]], &mac));
+    assert!(!verify_handshake_mac(&[2u8; 32], b"label", &[&[1, 2], &[3]], &mac));
+}
@@ -1,11 +1,12 @@
 use std::sync::Arc;
-use misc::ClonableIterator;
-use misc::*;
+use std::slice::Chunks;
 use consts::*;
 use udp_message::*;
 use fragment_combiner::FragmentGenerator;
+use pool::{BufferPool, Payload};
+use checksum::Checksum;
 
-const MAX_FRAGMENT_MESSAGE_SIZE: usize = MAX_UDP_MESSAGE_SIZE - CRC32_SIZE - FRAG_HEADER_SIZE;
+const MAX_FRAGMENT_MESSAGE_SIZE: usize = MAX_UDP_MESSAGE_SIZE - PACKET_TYPE_SIZE - FRAG_HEADER_SIZE - MAX_CHECKSUM_SIZE;
 /// A fragment is a destructed UdpPacket that can hold at most
 ///
 #[derive(Debug)]
@@ -18,7 +19,7 @@ pub struct Fragment<T: AsRef<[u8]>> {
     pub data: T
 }
 
-impl<'a> Clone for Fragment<&'a [u8]> {
+impl Clone for Fragment<&[u8]> {
     fn clone(&self) -> Self {
         Fragment {
             seq_id: self.seq_id,
@@ -29,7 +30,7 @@ impl<'a> Clone for Fragment<&'a [u8]> {
     }
 }
 
-impl<'a> Fragment<&'a [u8]> {
+impl Fragment<&[u8]> {
     pub fn into_boxed(self) -> Fragment<Box<[u8]>> {
         Fragment {
             seq_id: self.seq_id,
@@ -50,7 +51,10 @@ fn frag_udp_success_conversions() {
     };
     let udp_message: UdpMessage<_> = UdpMessage::from(&sent_fragment);
 
-    let received_fragment = udp_message.into_fragment().unwrap();
+    let received_fragment = match udp_message.into_packet(&mut BufferPool::new()).unwrap() {
+        Packet::Fragment(fragment) => fragment,
+        p => panic!("expected a fragment, got {:?}", p),
+    };
 
     assert_eq!(received_fragment.seq_id, sent_fragment.seq_id);
     assert_eq!(received_fragment.frag_id, sent_fragment.frag_id);
@@ -60,9 +64,9 @@ fn frag_udp_success_conversions() {
 
 #[test]
 fn frag_udp_fail_not_big_enough() {
-    let received_message: &'static [u8] = &[0u8, 0u8, 0u8, 0u8, 1u8, 2u8, 5u8];
+    let received_message: &'static [u8] = &[0u8, 0u8, 0u8, 0u8, 1u8];
     let received_fragment = UdpMessage::new(received_message);
-    let e = received_fragment.into_fragment().unwrap_err();
+    let e = received_fragment.into_packet(Checksum::None).unwrap_err();
     assert_eq!(e, UdpMessageError::NotBigEnough);
 }
 
@@ -70,93 +74,85 @@ fn frag_udp_fail_not_big_enough() {
 fn frag_udp_fail_invalid_crc() {
     let received_message: &'static [u8] = &[0; 20];
     let received_udp_message = UdpMessage::new(received_message);
-    let e = received_udp_message.into_fragment().unwrap_err();
+    let e = received_udp_message.into_packet(Checksum::Crc32).unwrap_err();
     assert_eq!(e, UdpMessageError::InvalidCrc);
 }
 
 /// Restore the data from multiple fragments
 ///
-/// This method accepts an iterator, but the iterator doesn't have to be sorted,
-/// sorting is done by this function itself.
+/// The fragments don't have to be sorted, they are sorted in place by this function itself.
 ///
 /// Panics if the number of fragment is not equal to the length of the given Vec
 ///
 /// returns an error if the message couldn't be restored properly: a frag_id is higher than frag_total,
 /// 2 frag_id are the same, ...
-pub (crate) fn build_data_from_fragments<I, B>(fragments: I) -> Result<Box<[u8]>, ()> 
-where   B: AsRef<[u8]> + 'static,
-        I: Iterator<Item = Fragment<B>> + ExactSizeIterator {
-    // start with vec!(None; n) and for every fragment, replace None by Some(...)
-    // it does not matter if the original slice is out of order, this vec will be in order
-    // Note that we can't do `= vec!(None; fragments.len())` because Option<Fragment<_>> is not `Clone`
-    let mut fragments_vec: Vec<Option<Fragment<B>>> = (0..fragments.len()).map(|_| None).collect();
+///
+/// The message is written right after the data of the first fragment, in its buffer: a message of
+/// a single fragment is returned without being copied. The buffers of the other fragments are
+/// given back to `pool`, and `fragments` is left empty to be used again.
+pub (crate) fn build_data_from_fragments<B>(fragments: &mut Vec<Fragment<B>>, pool: &mut BufferPool) -> Result<Payload, ()> 
+where   B: AsRef<[u8]> + Into<Payload> + 'static {
+    fragments.sort_unstable_by_key(|fragment| fragment.frag_id);
+    // once sorted, frag_ids that are too high or the same leave a frag_id out of place
+    if fragments.iter().enumerate().any(|(i, fragment)| fragment.frag_id as usize != i) {
+        return Err(())
+    }
+    assert_eq!(usize::from(fragments[0].frag_total) + 1, fragments.len());
     // track the size of all data chunks summed
-    let mut total_data_size: usize = 0;
+    let total_data_size: usize = fragments.iter().map(|fragment| fragment.data.as_ref().len()).sum();
+
+    let mut fragments = fragments.drain(..);
+    let mut reassembled_data: Payload = fragments.next().unwrap().data.into();
+    reassembled_data.reserve(total_data_size - reassembled_data.len());
     for fragment in fragments {
-        let frag_id = fragment.frag_id as usize;
-        if frag_id >= fragments_vec.len() || fragments_vec[frag_id].is_some() {
-            return Err(())
-        };
-        total_data_size += fragment.data.as_ref().len();
-        fragments_vec[frag_id] = Some(fragment);
-    }
-    // security check: no None are left, otherwise that means the message is incomplete
-    assert!(fragments_vec.iter().all(Option::is_some));
-    assert_eq!(usize::from(fragments_vec[0].as_ref().unwrap().frag_total) + 1, fragments_vec.len());
-
-    let mut reassembled_data: Vec<u8> = Vec::with_capacity(total_data_size);
-    for o in fragments_vec.iter() {
-        // unwrapping is 0 cost here since we assert-ed earlier that all the elements are "is_some"
-        let fragment = o.as_ref().unwrap();
-        reassembled_data.extend(fragment.data.as_ref());
+        reassembled_data.extend_from_slice(fragment.data.as_ref());
+        pool.give_back(fragment.data.into().into_buffer());
     };
-    Ok(reassembled_data.into_boxed_slice())
+    Ok(reassembled_data)
 }
 
 #[test]
 fn build_data_from_fragments_success() {
-    let fragments: Vec<Fragment<Box<[u8]>>> = vec![
+    let mut fragments: Vec<Fragment<Box<[u8]>>> = vec![
         Fragment { seq_id: 5, frag_id: 1, frag_total: 2, data: Box::new([4, 5]) },
         Fragment { seq_id: 5, frag_id: 0, frag_total: 2, data: Box::new([1, 2, 3]) },
         Fragment { seq_id: 5, frag_id: 2, frag_total: 2, data: Box::new([6, 7, 8, 9]) },
     ];
 
-    let message: Box<[u8]> = build_data_from_fragments(fragments.into_iter()).unwrap();
+    let message: Payload = build_data_from_fragments(&mut fragments, &mut BufferPool::new()).unwrap();
     assert_eq!(message.as_ref(), &[1u8, 2, 3, 4, 5, 6, 7, 8, 9]);
 }
 
 #[test]
 #[should_panic]
 fn build_data_from_fragments_fail_wrong_frag_total() {
-    let fragments: Vec<Fragment<Box<[u8]>>> = vec![
+    let mut fragments: Vec<Fragment<Box<[u8]>>> = vec![
         Fragment { seq_id: 5, frag_id: 1, frag_total: 3, data: Box::new([4, 5]) },
         Fragment { seq_id: 5, frag_id: 0, frag_total: 3, data: Box::new([1, 2, 3]) },
         Fragment { seq_id: 5, frag_id: 2, frag_total: 3, data: Box::new([6, 7, 8, 9]) },
     ];
 
-    build_data_from_fragments(fragments.into_iter()).unwrap();
+    build_data_from_fragments(&mut fragments, &mut BufferPool::new()).unwrap();
 }
 
 #[test]
 fn build_data_from_fragments_fail_wrong_frag_id() {
-    let fragments: Vec<Fragment<Box<[u8]>>> = vec![
+    let mut fragments: Vec<Fragment<Box<[u8]>>> = vec![
         Fragment { seq_id: 5, frag_id: 0, frag_total: 1, data: Box::new([1, 2, 3]) },
         Fragment { seq_id: 5, frag_id: 5, frag_total: 1, data: Box::new([6, 7, 8, 9]) },
     ];
 
-    let e = build_data_from_fragments(fragments.into_iter()).unwrap_err();
-    assert_eq!(e, ());
+    assert_eq!(build_data_from_fragments(&mut fragments, &mut BufferPool::new()).unwrap_err(), ());
 }
 
 #[test]
 fn build_data_from_fragments_fail_duplicate_frag_id() {
-    let fragments: Vec<Fragment<Box<[u8]>>> = vec![
+    let mut fragments: Vec<Fragment<Box<[u8]>>> = vec![
         Fragment { seq_id: 5, frag_id: 0, frag_total: 1, data: Box::new([1, 2, 3]) },
         Fragment { seq_id: 5, frag_id: 0, frag_total: 1, data: Box::new([6, 7, 8, 9]) },
     ];
 
-    let e = build_data_from_fragments(fragments.into_iter()).unwrap_err();
-    assert_eq!(e, ());
+    assert_eq!(build_data_from_fragments(&mut fragments, &mut BufferPool::new()).unwrap_err(), ());
 }
 
 /// Build fragments (as an iterator)
@@ -164,8 +160,8 @@ fn build_data_from_fragments_fail_duplicate_frag_id() {
 /// Returns 0 if the message is too big.
 ///
 /// The message cannot be nothing (empty slice), otherwise it will panic.
-pub (crate) fn build_fragments_from_data<'a, D: AsRef<[u8]>>(data: &'a D, seq_id: u32) -> Result<Box<'a + ClonableIterator<Item = Fragment<&[u8]>>>, ()> {
-    if data.as_ref().len() == 0 {
+pub (crate) fn build_fragments_from_data<'a, D: AsRef<[u8]>>(data: &'a D, seq_id: u32) -> Result<FragmentGenerator<'a, Chunks<'a, u8>>, ()> {
+    if data.as_ref().is_empty() {
         panic!("build_fragments_from_data cannot build fragments if the message is empty");
     }
 
@@ -181,7 +177,7 @@ pub (crate) fn build_fragments_from_data<'a, D: AsRef<[u8]>>(data: &'a D, seq_id
     }
     let frag_total = (fragments_count - 1) as u8;
     let iter = data.as_ref().chunks(MAX_FRAGMENT_MESSAGE_SIZE);
-    Ok(Box::new(FragmentGenerator::new(iter, seq_id, frag_total)))
+    Ok(FragmentGenerator::new(iter, seq_id, frag_total))
 }
 
 #[test]
@@ -189,8 +185,8 @@ fn build_rebuild_data() {
     let seq_id: u32 = 1;
     let data = vec!(0; 1024);
     let frags_iter_boxed = build_fragments_from_data(&data, seq_id).unwrap();
-    let frags: Vec<Fragment<Box<[u8]>>> = frags_iter_boxed.map(|f| f.into_boxed()).collect();
-    let new_data = build_data_from_fragments(frags.into_iter()).unwrap();
+    let mut frags: Vec<Fragment<Box<[u8]>>> = frags_iter_boxed.map(|f| f.into_boxed()).collect();
+    let new_data = build_data_from_fragments(&mut frags, &mut BufferPool::new()).unwrap();
     assert_eq!(new_data.len(), data.len());
 }
 
@@ -3,73 +3,103 @@ use std::collections::VecDeque;
 use itertools::Itertools;
 
 use fragment::{Fragment, build_data_from_fragments};
+use pool::{BufferPool, Payload};
 
 #[derive(Debug)]
-pub (crate) struct FragmentCombiner<B: AsRef<[u8]> + 'static> {
-    pending_fragments: HashMap<u32, HashMap<u8, Fragment<B>>>,
-    out_messages: VecDeque<Box<[u8]>>,
+pub (crate) struct FragmentCombiner<B: AsRef<[u8]> + Into<Payload> + 'static> {
+    pending_fragments: HashMap<u32, Vec<Fragment<B>>>,
+    /// emptied Vecs of fragments, to receive the next messages without allocating
+    spare_fragments: Vec<Vec<Fragment<B>>>,
+    out_messages: VecDeque<Payload>,
 }
 
-impl<B: AsRef<[u8]> + 'static> FragmentCombiner<B> {
+impl<B: AsRef<[u8]> + Into<Payload> + 'static> FragmentCombiner<B> {
     pub fn new() -> Self {
         FragmentCombiner {
             pending_fragments: HashMap::default(),
+            spare_fragments: Vec::new(),
             out_messages: VecDeque::new(),
         }
     }
 
-    /// Removes the HashMap for key `seq_id`, an tries to create a message out of that.
+    /// Removes the fragments for key `seq_id`, an tries to create a message out of that.
     ///
-    /// Panics if there is no HashMap at `seq_id`
+    /// Panics if there are no fragments at `seq_id`
     ///
     /// Returns an Error if all the fragments do not have the same frag_total,
     /// or if "build_message_from_fragments" encountered an error
-    fn transform_message(&mut self, seq_id: u32) -> Result<(), ()> {
-        let fragments = self.pending_fragments.remove(&seq_id).unwrap();
-        if !fragments.values().map(|f| f.frag_total).all_equal() {
+    fn transform_message(&mut self, seq_id: u32, pool: &mut BufferPool) -> Result<(), ()> {
+        let mut fragments = self.pending_fragments.remove(&seq_id).unwrap();
+        let message = if fragments.iter().map(|f| f.frag_total).all_equal() {
+            build_data_from_fragments(&mut fragments, pool)
+        } else {
             // some fragments don't have the same frag_total
-            return Err(());
-        }
-        // build_data_from_fragments with an IntoIterator with just the values
-        let message = build_data_from_fragments(fragments.into_iter().map(|(_k, v)| v))?;
-        self.out_messages.push_back(message);
+            Err(())
+        };
+        fragments.clear();
+        self.spare_fragments.push(fragments);
+        self.out_messages.push_back(message?);
         Ok(())
     }
 
-    pub fn next_out_message(&mut self) -> Option<Box<[u8]>> {
+    pub fn next_out_message(&mut self) -> Option<Payload> {
         self.out_messages.pop_front()
     }
 
-    /// Returns all the waiting out messages, and empties the internal queue
-    pub fn extract_out_messages(&mut self) -> VecDeque<Box<[u8]>> {
-        let empty = VecDeque::default();
-        if self.out_messages.len() == 0 {
-            empty
+    /// Returns all the waiting out messages, and empties the internal queue.
+    ///
+    /// The queue is left with room for as many messages, so that it doesn't grow again.
+    pub fn extract_out_messages(&mut self) -> VecDeque<Payload> {
+        if self.out_messages.is_empty() {
+            VecDeque::default()
         } else {
-            ::std::mem::replace(&mut self.out_messages, empty)
+            let capacity = self.out_messages.len();
+            ::std::mem::replace(&mut self.out_messages, VecDeque::with_capacity(capacity))
+        }
+    }
+
+    /// Drops the fragments received for `seq_id`, whose message will never be complete, and gives
+    /// their buffers back to `pool`
+    pub fn forget(&mut self, seq_id: u32, pool: &mut BufferPool) {
+        if let Some(mut fragments) = self.pending_fragments.remove(&seq_id) {
+            for fragment in fragments.drain(..) {
+                pool.give_back(fragment.data.into().into_buffer());
+            }
+            self.spare_fragments.push(fragments);
         }
     }
 
     /// Push a fragment into the internal queue.
     ///
-    /// If the fragment is the last to arrive
-    pub fn push(&mut self, fragment: Fragment<B>) {
+    /// If the fragment is the last to arrive, its message is built and the buffers it doesn't
+    /// need are given back to `pool`
+    pub fn push(&mut self, fragment: Fragment<B>, pool: &mut BufferPool) {
         let seq_id = fragment.seq_id;
         let frag_total = fragment.frag_total;
 
+        if frag_total == 0 && !self.pending_fragments.contains_key(&seq_id) {
+            // the whole message, there is nothing to wait for
+            self.out_messages.push_back(fragment.data.into());
+            return;
+        }
+
         let try_transform = { 
             let entry = self.pending_fragments.entry(seq_id);
 
-            // if the hashmap doesn't exist, create an empty one
-            let seq_hash_map = entry.or_insert_with(|| HashMap::with_capacity_and_hasher(frag_total as usize, Default::default()));
+            // if the fragments don't exist, take an empty Vec for them
+            let spare_fragments = &mut self.spare_fragments;
+            let seq_fragments = entry.or_insert_with(|| spare_fragments.pop().unwrap_or_default());
 
             // if the seq_id/frag_id combo already existed, override it. It can happen when the sender re-sends a packet we've already received
             // because it didn't receive the ack on time.
-            seq_hash_map.insert(fragment.frag_id, fragment);
-            if seq_hash_map.len() == frag_total as usize + 1 {
+            match seq_fragments.iter_mut().find(|f| f.frag_id == fragment.frag_id) {
+                Some(existing) => *existing = fragment,
+                None => seq_fragments.push(fragment),
+            }
+            if seq_fragments.len() == frag_total as usize + 1 {
                 true
                 // try to transform fragments into a message, because we have enough of them here
-            } else if seq_hash_map.len() > frag_total as usize + 1 {
+            } else if seq_fragments.len() > frag_total as usize + 1 {
                 // there are too many messages! This can only happen when a packet "lied" about its frag_total.
                 // If we try to re-build the message here, we will get an error because all of the fragments
                 // don't have the same frag_total
@@ -80,8 +110,10 @@ impl<B: AsRef<[u8]> + 'static> FragmentCombiner<B> {
         };
 
         if try_transform {
-            let _r = self.transform_message(seq_id);
-            // failures to transform messages are ignored. Logging may be an option here TODO
+            // failures to transform messages are ignored, the fragments are simply dropped.
+            if self.transform_message(seq_id, pool).is_err() {
+                debug!("dropping message with seq_id {}: fragments are inconsistent", seq_id);
+            }
         }
     }
 }
@@ -99,7 +131,7 @@ fn fragment_combiner_success() {
     ];
     let mut fragment_combiner = FragmentCombiner::new();
     for fragment in fragments {
-        fragment_combiner.push(fragment);
+        fragment_combiner.push(fragment, &mut BufferPool::new());
     }
 
     let out_message = fragment_combiner.next_out_message().unwrap();
@@ -108,6 +140,20 @@ fn fragment_combiner_success() {
     assert_eq!(out_message.as_ref(), &[1, 2, 3, 4, 5, 6, 7, 8, 9]);
 }
 
+#[test]
+fn fragment_combiner_forgets_incomplete_messages() {
+    let mut fragment_combiner = FragmentCombiner::new();
+    let mut pool = BufferPool::new();
+    fragment_combiner.push(Fragment { seq_id: 3, frag_id: 0, frag_total: 1, data: Box::new([1u8]) as Box<[u8]> }, &mut pool);
+    fragment_combiner.push(Fragment { seq_id: 4, frag_id: 0, frag_total: 1, data: Box::new([3u8]) }, &mut pool);
+    fragment_combiner.forget(3, &mut pool);
+    assert!(!fragment_combiner.pending_fragments.contains_key(&3));
+    fragment_combiner.push(Fragment { seq_id: 3, frag_id: 1, frag_total: 1, data: Box::new([2u8]) }, &mut pool);
+    fragment_combiner.push(Fragment { seq_id: 4, frag_id: 1, frag_total: 1, data: Box::new([4u8]) }, &mut pool);
+    assert_eq!(fragment_combiner.next_out_message().unwrap().as_ref(), &[3u8, 4]);
+    assert!(fragment_combiner.next_out_message().is_none());
+}
+
 pub (crate) struct FragmentGenerator<'a, I> where I: Iterator<Item = &'a [u8]> + Clone {
     seq_id: u32,
     frag_total: u8,
@@ -136,7 +182,7 @@ impl<'a, I: Iterator<Item = &'a [u8]> + Clone> Iterator for FragmentGenerator<'a
                     seq_id: self.seq_id,
                     frag_total: self.frag_total,
                     frag_id: self.next_frag,
-                    data: data
+                    data
                 };
                 self.next_frag += 1;
                 Some(frag)
@@ -6,18 +6,77 @@ extern crate fnv;
 extern crate itertools;
 
 extern crate crc;
+extern crate crc32c;
+extern crate xxhash_rust;
 extern crate byteorder;
+extern crate hmac;
+extern crate sha2;
+extern crate getrandom;
+extern crate chacha20poly1305;
 
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate futures_core;
+#[cfg(all(any(feature = "mmsg", feature = "gso", feature = "sharded"), target_os = "linux"))]
+extern crate libc;
+
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
+
+#[cfg(not(feature = "log"))]
 #[macro_use]
-extern crate failure;
+mod log_macros;
 
-mod misc;
 mod consts;
 mod connection;
 mod fragment_combiner;
 mod fragment;
 mod udp_message;
 mod socket;
+mod stats;
+mod ack;
+mod transport;
+mod conditioner;
+mod clock;
+mod cookie;
+mod limiter;
+mod crypto;
+mod token;
+mod replay;
+mod slots;
+mod migration;
+mod resumption;
+mod pool;
+mod checksum;
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+mod mmsg;
+#[cfg(all(feature = "gso", target_os = "linux"))]
+mod offload;
+#[cfg(all(feature = "sharded", target_os = "linux"))]
+mod sharded;
+#[cfg(all(any(feature = "mmsg", feature = "gso", feature = "sharded"), target_os = "linux"))]
+mod sockaddr;
+#[cfg(feature = "async")]
+mod async_connection;
 
 pub use connection::*;
-pub use socket::*;
\ No newline at end of file
+pub use socket::*;
+pub use stats::{RemoteStats, SocketStats, DroppedFragments};
+pub use ack::MessageToken;
+pub use pool::Payload;
+pub use transport::{DatagramTransport, ChannelTransport, ChannelAddr};
+pub use conditioner::{LinkConditioner, LinkConditions};
+pub use clock::{Clock, SystemClock, MockClock};
+pub use limiter::RateLimits;
+pub use crypto::PreSharedKey;
+pub use udp_message::RejectReason;
+pub use checksum::Checksum;
+pub use token::{ConnectToken, ConnectTokenData, ConnectTokenError, ConnectTokenGenerator, ConnectTokenKey, ConnectTokenValidator, USER_DATA_SIZE, MAX_SERVER_ADDRESSES};
+#[cfg(feature = "async")]
+pub use async_connection::{AsyncConnection, Incoming, SendData};
+#[cfg(all(feature = "gso", target_os = "linux"))]
+pub use offload::OffloadUdpSocket;
+#[cfg(all(feature = "sharded", target_os = "linux"))]
+pub use sharded::ShardedConnection;
@@ -0,0 +1,256 @@
+//! Protections against floods and abusive clients: per-address rate limits and a ban list.
+//!
+//! Both are checked for every datagram right after it is received, before it is parsed.
+
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use fnv::FnvHashMap as HashMap;
+
+use consts::{MAX_RATE_LIMITED_ADDRESSES, RATE_LIMITER_PRUNE_INTERVAL};
+
+/// Token bucket limits applied to each source address. A rate of 0 disables the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Datagrams per second accepted from a remote
+    pub packets_per_second: u32,
+    /// Datagrams a remote can send at once after being quiet for a while
+    pub packet_burst: u32,
+    /// Datagrams per second accepted from an address that is not a remote yet, which can only be
+    /// connection attempts
+    pub connects_per_second: u32,
+    /// Datagrams an address that is not a remote yet can send at once
+    pub connect_burst: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            packets_per_second: 2000,
+            packet_burst: 500,
+            connects_per_second: 4,
+            connect_burst: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub (crate) struct TokenBucket {
+    tokens: f32,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(burst: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f32,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, rate: u32, burst: u32, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * rate as f32).min(::std::cmp::max(burst, 1) as f32);
+        self.updated_at = now;
+    }
+
+    /// Takes one token, returns false if there was none left
+    pub fn take(&mut self, rate: u32, burst: u32, now: Instant) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        self.refill(rate, burst, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// A full bucket is the same as no bucket at all
+    pub fn is_full(&mut self, rate: u32, burst: u32, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        self.tokens >= ::std::cmp::max(burst, 1) as f32
+    }
+}
+
+#[derive(Debug)]
+struct AddrBuckets {
+    packets: TokenBucket,
+    connects: TokenBucket,
+}
+
+/// The token buckets of every address we recently received something from.
+#[derive(Debug)]
+pub (crate) struct RateLimiter<A: Hash + Eq> {
+    limits: RateLimits,
+    buckets: HashMap<A, AddrBuckets>,
+    pruned_at: Instant,
+}
+
+impl<A: Hash + Eq + Clone> RateLimiter<A> {
+    pub fn new(limits: RateLimits, now: Instant) -> RateLimiter<A> {
+        RateLimiter {
+            limits,
+            buckets: Default::default(),
+            pruned_at: now,
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+    }
+
+    /// Returns true if a datagram from `addr` can be handled. `is_remote` tells whether `addr`
+    /// is one of our remotes, otherwise the connection attempts limit applies.
+    ///
+    /// When too many addresses are tracked, datagrams from new addresses are refused. Pruning
+    /// takes a while with that many addresses, it is done at most every `RATE_LIMITER_PRUNE_INTERVAL`.
+    pub fn allow(&mut self, addr: &A, is_remote: bool, now: Instant) -> bool {
+        let limits = self.limits;
+        if !self.buckets.contains_key(addr) {
+            if self.buckets.len() >= MAX_RATE_LIMITED_ADDRESSES {
+                self.maybe_prune(now);
+                if self.buckets.len() >= MAX_RATE_LIMITED_ADDRESSES {
+                    return false;
+                }
+            }
+            self.buckets.insert(addr.clone(), AddrBuckets {
+                packets: TokenBucket::new(limits.packet_burst, now),
+                connects: TokenBucket::new(limits.connect_burst, now),
+            });
+        }
+        let buckets = self.buckets.get_mut(addr).unwrap();
+        if is_remote {
+            buckets.packets.take(limits.packets_per_second, limits.packet_burst, now)
+        } else {
+            buckets.connects.take(limits.connects_per_second, limits.connect_burst, now)
+        }
+    }
+
+    /// Forgets the addresses that are back to full buckets
+    pub fn prune(&mut self, now: Instant) {
+        let limits = self.limits;
+        self.buckets.retain(|_, buckets| {
+            !(buckets.packets.is_full(limits.packets_per_second, limits.packet_burst, now)
+                && buckets.connects.is_full(limits.connects_per_second, limits.connect_burst, now))
+        });
+        self.pruned_at = now;
+    }
+
+    /// Prunes the buckets if it has not been done for a while
+    pub fn maybe_prune(&mut self, now: Instant) {
+        if now.duration_since(self.pruned_at) >= RATE_LIMITER_PRUNE_INTERVAL {
+            self.prune(now);
+        }
+    }
+}
+
+/// Banned IPs and addresses, and when their ban ends. `None` is a permanent ban, and so is a ban
+/// too long to end.
+#[derive(Debug)]
+pub (crate) struct BanList<A: Hash + Eq> {
+    ips: HashMap<IpAddr, Option<Instant>>,
+    addrs: HashMap<A, Option<Instant>>,
+}
+
+fn is_active(ban: Option<&Option<Instant>>, now: Instant) -> bool {
+    match ban {
+        Some(&Some(until)) => now < until,
+        Some(&None) => true,
+        None => false,
+    }
+}
+
+impl<A: Hash + Eq> BanList<A> {
+    pub fn new() -> BanList<A> {
+        BanList {
+            ips: Default::default(),
+            addrs: Default::default(),
+        }
+    }
+
+    pub fn ban_ip(&mut self, ip: IpAddr, duration: Option<Duration>, now: Instant) {
+        self.ips.insert(ip, duration.and_then(|d| now.checked_add(d)));
+    }
+
+    pub fn unban_ip(&mut self, ip: &IpAddr) -> bool {
+        self.ips.remove(ip).is_some()
+    }
+
+    pub fn ban_addr(&mut self, addr: A, duration: Option<Duration>, now: Instant) {
+        self.addrs.insert(addr, duration.and_then(|d| now.checked_add(d)));
+    }
+
+    pub fn unban_addr(&mut self, addr: &A) -> bool {
+        self.addrs.remove(addr).is_some()
+    }
+
+    /// `ip` is the IP of `addr`, if it has one
+    pub fn is_banned(&self, addr: &A, ip: Option<IpAddr>, now: Instant) -> bool {
+        is_active(self.addrs.get(addr), now) || ip.is_some_and(|ip| is_active(self.ips.get(&ip), now))
+    }
+
+    /// Removes the bans that are over
+    pub fn prune(&mut self, now: Instant) {
+        self.ips.retain(|_, until| until.is_none_or(|until| now < until));
+        self.addrs.retain(|_, until| until.is_none_or(|until| now < until));
+    }
+}
+
+#[test]
+fn token_bucket_refill() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(2, start);
+    assert!(bucket.take(10, 2, start));
+    assert!(bucket.take(10, 2, start));
+    assert!(!bucket.take(10, 2, start));
+    assert!(!bucket.take(10, 2, start + Duration::from_millis(50)));
+    assert!(bucket.take(10, 2, start + Duration::from_millis(100)));
+    // the bucket never holds more than the burst
+    assert!(bucket.is_full(10, 2, start + Duration::from_secs(10)));
+    assert!(bucket.take(10, 2, start + Duration::from_secs(10)));
+    assert!(bucket.take(10, 2, start + Duration::from_secs(10)));
+    assert!(!bucket.take(10, 2, start + Duration::from_secs(10)));
+    // a rate of 0 disables the limit
+    assert!(bucket.take(0, 2, start + Duration::from_secs(10)));
+}
+
+#[test]
+fn ban_list_expiry() {
+    let start = Instant::now();
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let mut bans = BanList::<u32>::new();
+    bans.ban_ip(ip, Some(Duration::from_secs(60)), start);
+    bans.ban_addr(7, None, start);
+    assert!(bans.is_banned(&1, Some(ip), start));
+    assert!(!bans.is_banned(&1, None, start));
+    assert!(bans.is_banned(&7, None, start));
+    bans.prune(start + Duration::from_secs(60));
+    assert!(!bans.is_banned(&1, Some(ip), start + Duration::from_secs(60)));
+    assert!(bans.is_banned(&7, None, start + Duration::from_secs(3600)));
+    assert!(bans.unban_addr(&7));
+    assert!(!bans.is_banned(&7, None, start));
+    bans.ban_addr(8, Some(Duration::MAX), start);
+    assert!(bans.is_banned(&8, None, start + Duration::from_secs(3600)));
+}
+
+#[test]
+fn rate_limiter_prunes_at_most_every_interval() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::<u32>::new(Default::default(), start);
+    for addr in 0..MAX_RATE_LIMITED_ADDRESSES as u32 {
+        assert!(limiter.allow(&addr, false, start));
+    }
+    let new_addr = MAX_RATE_LIMITED_ADDRESSES as u32;
+    // pruned at start: new addresses are refused until the next prune
+    assert!(!limiter.allow(&new_addr, false, start + RATE_LIMITER_PRUNE_INTERVAL / 2));
+    assert_eq!(limiter.pruned_at, start);
+    assert!(limiter.allow(&new_addr, false, start + RATE_LIMITER_PRUNE_INTERVAL * 60));
+}
@@ -0,0 +1,33 @@
+// When the `log` feature is disabled, these macros replace the ones from the `log` crate.
+//
+// The arguments are still type-checked (so that building with or without the feature
+// doesn't give different warnings), but nothing is ever formatted.
+#![allow(unused_macros)]
+
+macro_rules! kestrel_no_log {
+    ($($arg:tt)+) => {
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { kestrel_no_log!($($arg)+) }
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { kestrel_no_log!($($arg)+) }
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { kestrel_no_log!($($arg)+) }
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { kestrel_no_log!($($arg)+) }
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { kestrel_no_log!($($arg)+) }
+}
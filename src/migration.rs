@@ -0,0 +1,99 @@
+//! Connections that survive a change of address.
+//!
+//! NAT rebinding, or a switch from Wi-Fi to cellular, changes the address a remote sends from.
+//! During the handshake each end picks a random connection id, and the other end appends it to
+//! every packet it sends. A packet from an unknown address with the connection id of a remote is
+//! still handled, but the remote only moves to that address once it answered a path challenge
+//! sent there: nobody can redirect a connection to an address it doesn't receive at.
+
+use std::time::Instant;
+
+use consts::PATH_CHALLENGE_INTERVAL;
+
+/// A connection id is 8 random bytes
+pub (crate) const CONNECTION_ID_SIZE: usize = 8;
+
+/// Identifies a connection on the end that picked it, whatever address the other end sends from
+pub (crate) type ConnectionId = [u8; CONNECTION_ID_SIZE];
+
+/// A path challenge holds 8 random bytes, that the remote must send back
+pub (crate) const PATH_TOKEN_SIZE: usize = 8;
+
+pub (crate) type PathToken = [u8; PATH_TOKEN_SIZE];
+
+pub (crate) fn random_connection_id() -> ConnectionId {
+    let mut connection_id = [0u8; CONNECTION_ID_SIZE];
+    getrandom::getrandom(&mut connection_id).expect("failed to generate a random connection id");
+    connection_id
+}
+
+fn random_path_token() -> PathToken {
+    let mut token = [0u8; PATH_TOKEN_SIZE];
+    getrandom::getrandom(&mut token).expect("failed to generate a random path token");
+    token
+}
+
+#[derive(Debug)]
+struct PathChallenge<A> {
+    addr: A,
+    token: PathToken,
+    sent_at: Instant,
+}
+
+/// The path challenge of a remote that sent from a new address.
+///
+/// Only the address challenged last can be validated, a remote only has one address at a time.
+#[derive(Debug)]
+pub (crate) struct PathValidation<A> {
+    pending: Option<PathChallenge<A>>,
+}
+
+impl<A: Clone + PartialEq> PathValidation<A> {
+    pub fn new() -> PathValidation<A> {
+        PathValidation { pending: None }
+    }
+
+    /// Returns the token to challenge `addr` with, or None if it was challenged less than
+    /// `PATH_CHALLENGE_INTERVAL` ago
+    pub fn challenge(&mut self, addr: &A, now: Instant) -> Option<PathToken> {
+        if let Some(ref pending) = self.pending {
+            if pending.addr == *addr && now.duration_since(pending.sent_at) < PATH_CHALLENGE_INTERVAL {
+                return None;
+            }
+        }
+        let token = random_path_token();
+        self.pending = Some(PathChallenge { addr: addr.clone(), token, sent_at: now });
+        Some(token)
+    }
+
+    /// Returns true if `token` answers the challenge sent to `addr`, which is then over
+    pub fn validate(&mut self, addr: &A, token: &PathToken) -> bool {
+        let valid = self.pending.as_ref().is_some_and(|pending| pending.addr == *addr && pending.token == *token);
+        if valid {
+            self.pending = None;
+        }
+        valid
+    }
+}
+
+#[test]
+fn path_validation() {
+    use std::time::Duration;
+    let start = Instant::now();
+    let mut path_validation = PathValidation::new();
+    let token = path_validation.challenge(&1, start).unwrap();
+    // not challenged again right away
+    assert_eq!(path_validation.challenge(&1, start + Duration::from_millis(1)), None);
+    assert!(!path_validation.validate(&2, &token));
+    assert!(!path_validation.validate(&1, &[0u8; PATH_TOKEN_SIZE]));
+    assert!(path_validation.validate(&1, &token));
+    assert!(!path_validation.validate(&1, &token));
+
+    // a challenge to another address replaces the previous one
+    let token1 = path_validation.challenge(&1, start).unwrap();
+    let token2 = path_validation.challenge(&2, start).unwrap();
+    assert!(!path_validation.validate(&1, &token1));
+    assert!(path_validation.validate(&2, &token2));
+    assert!(path_validation.challenge(&3, start).is_some());
+    assert!(path_validation.challenge(&3, start + PATH_CHALLENGE_INTERVAL).is_some());
+}
@@ -0,0 +1,95 @@
+//! Batches of datagrams sent or received with one syscall, with `sendmmsg` and `recvmmsg`.
+//!
+//! Only used on Linux with the `mmsg` feature, `DatagramTransport` loops over `send_to` and
+//! `recv_from` everywhere else.
+
+use std::cmp::min;
+use std::io::{Error, Result};
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use libc;
+use sockaddr::{to_socket_addr, from_socket_addr};
+
+/// The most datagrams sent or received by one syscall, the headers are kept on the stack
+const MAX_BATCH_SIZE: usize = 32;
+
+pub (crate) fn recv_batch(socket: &UdpSocket, buffers: &mut [Vec<u8>], addrs: &mut Vec<SocketAddr>) -> Result<usize> {
+    let count = min(buffers.len(), MAX_BATCH_SIZE);
+    // plain C structs, all zeroes is a valid value for them
+    let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut names: [libc::sockaddr_storage; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    for i in 0..count {
+        iovecs[i].iov_base = buffers[i].as_mut_ptr() as *mut libc::c_void;
+        iovecs[i].iov_len = buffers[i].len();
+        headers[i].msg_hdr.msg_name = &mut names[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+        headers[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+        headers[i].msg_hdr.msg_iovlen = 1;
+    }
+    // the buffers, names and iovecs outlive the call, and every header points to its own
+    let received = unsafe {
+        libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, 0, ptr::null_mut())
+    };
+    if received < 0 {
+        return Err(Error::last_os_error());
+    }
+    let mut kept = 0;
+    for i in 0..received as usize {
+        // a datagram from an address we can't answer is skipped, its buffer goes after the kept ones
+        let addr = match to_socket_addr(&names[i]) {
+            Ok(addr) => addr,
+            Err(_) => continue,
+        };
+        // like recv_from, the excess bytes of a datagram too large for its buffer are discarded
+        buffers[i].truncate(headers[i].msg_len as usize);
+        buffers.swap(kept, i);
+        addrs.push(addr);
+        kept += 1;
+    }
+    Ok(kept)
+}
+
+pub (crate) fn send_batch<B: AsRef<[u8]>>(socket: &UdpSocket, datagrams: &[B], addr: &SocketAddr) -> Result<usize> {
+    let count = min(datagrams.len(), MAX_BATCH_SIZE);
+    let (mut name, name_len) = from_socket_addr(addr);
+    let mut iovecs: [libc::iovec; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    let mut headers: [libc::mmsghdr; MAX_BATCH_SIZE] = unsafe { mem::zeroed() };
+    for i in 0..count {
+        let datagram = datagrams[i].as_ref();
+        // sendmmsg only reads the datagrams, the pointer is mutable because iovec is shared with recvmmsg
+        iovecs[i].iov_base = datagram.as_ptr() as *mut libc::c_void;
+        iovecs[i].iov_len = datagram.len();
+        headers[i].msg_hdr.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        headers[i].msg_hdr.msg_namelen = name_len;
+        headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+        headers[i].msg_hdr.msg_iovlen = 1;
+    }
+    let sent = unsafe {
+        libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, 0)
+    };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(sent as usize)
+}
+
+#[test]
+fn udp_socket_batches() {
+    let socket1 = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket2 = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket2.set_nonblocking(true).unwrap();
+    let datagrams = [vec![1u8, 2, 3], vec![4], vec![5, 6]];
+    assert_eq!(send_batch(&socket1, &datagrams, &socket2.local_addr().unwrap()).unwrap(), 3);
+    ::std::thread::sleep(::std::time::Duration::from_millis(10));
+    let mut buffers = vec![vec![0u8; 16]; 4];
+    let mut addrs = Vec::new();
+    assert_eq!(recv_batch(&socket2, &mut buffers, &mut addrs).unwrap(), 3);
+    assert_eq!(&buffers[..3], &datagrams[..]);
+    assert_eq!(buffers[3].len(), 16);
+    assert_eq!(addrs, vec![socket1.local_addr().unwrap(); 3]);
+    assert_eq!(recv_batch(&socket2, &mut buffers, &mut addrs).unwrap_err().kind(), ::std::io::ErrorKind::WouldBlock);
+}
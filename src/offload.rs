@@ -0,0 +1,276 @@
+//! UDP segmentation offload on Linux, with the `gso` feature.
+//!
+//! The fragments of a message are datagrams of the same size, but the last one, sent to the same
+//! remote: they are handed to the kernel in a single buffer, which it splits in datagrams as late
+//! as it can (GSO). The other way around, the kernel can coalesce the datagrams received from one
+//! sender in a single buffer (GRO), which is split back here.
+
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::fmt;
+use std::io::{Error, Result};
+use std::mem;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+use libc;
+use sockaddr::{to_socket_addr, from_socket_addr};
+use transport::DatagramTransport;
+
+/// The most datagrams the kernel accepts in one segmented buffer
+const MAX_SEGMENTS: usize = 64;
+
+/// The largest segmented buffer, the payload of the largest IPv4 packet
+const MAX_SEGMENTED_SIZE: usize = 65507;
+
+/// The size of the buffer coalesced datagrams are received in, the largest UDP payload
+const MAX_COALESCED_SIZE: usize = 65535;
+
+/// A `UdpSocket` that sends trains of datagrams of the same size with one segmented buffer, and
+/// receives datagrams coalesced by the kernel.
+///
+/// If the kernel or the network device refuses a segmented buffer, the datagrams are sent one by
+/// one from then on.
+pub struct OffloadUdpSocket {
+    socket: UdpSocket,
+    segmentation: Cell<bool>,
+    received: RefCell<Coalesced>,
+}
+
+/// The last buffer received, and the datagrams that were not read from it yet
+struct Coalesced {
+    buffer: Vec<u8>,
+    offset: usize,
+    segment_size: usize,
+    addr: SocketAddr,
+}
+
+impl OffloadUdpSocket {
+    /// Asks the kernel to coalesce the datagrams received by `socket`.
+    ///
+    /// Fails if the kernel doesn't support it (before Linux 5.0).
+    pub fn new(socket: UdpSocket) -> Result<OffloadUdpSocket> {
+        let enabled: libc::c_int = 1;
+        let r = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), libc::SOL_UDP, libc::UDP_GRO,
+                &enabled as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if r < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(OffloadUdpSocket {
+            socket,
+            segmentation: Cell::new(true),
+            received: RefCell::new(Coalesced {
+                buffer: Vec::with_capacity(MAX_COALESCED_SIZE),
+                offset: 0,
+                segment_size: 0,
+                addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+            }),
+        })
+    }
+
+    pub fn get_ref(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    /// Sends `datagrams` in one buffer, the kernel splits it every `segment_size` bytes
+    fn send_segmented<B: AsRef<[u8]>>(&self, datagrams: &[B], segment_size: usize, addr: &SocketAddr) -> Result<()> {
+        let (mut name, name_len) = from_socket_addr(addr);
+        // plain C structs, all zeroes is a valid value for them
+        let mut iovecs: [libc::iovec; MAX_SEGMENTS] = unsafe { mem::zeroed() };
+        for (iovec, datagram) in iovecs.iter_mut().zip(datagrams) {
+            // sendmsg only reads the datagrams
+            iovec.iov_base = datagram.as_ref().as_ptr() as *mut libc::c_void;
+            iovec.iov_len = datagram.as_ref().len();
+        }
+        // u64s to be aligned for cmsghdr
+        let mut control = [0u64; 4];
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        header.msg_namelen = name_len;
+        header.msg_iov = iovecs.as_mut_ptr();
+        header.msg_iovlen = min(datagrams.len(), MAX_SEGMENTS) as _;
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as libc::c_uint) } as _;
+        // the control buffer is large enough for one u16, CMSG_FIRSTHDR can't be null
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as libc::c_uint) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size as u16);
+        }
+        if unsafe { libc::sendmsg(self.socket.as_raw_fd(), &header, 0) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Coalesced {
+    /// Receives the next buffer, of one datagram or of several coalesced ones
+    fn receive(&mut self, socket: &UdpSocket) -> Result<()> {
+        self.buffer.resize(MAX_COALESCED_SIZE, 0);
+        self.offset = 0;
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iovec = libc::iovec {
+            iov_base: self.buffer.as_mut_ptr() as *mut libc::c_void,
+            iov_len: self.buffer.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut header: libc::msghdr = unsafe { mem::zeroed() };
+        header.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        header.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        header.msg_iov = &mut iovec;
+        header.msg_iovlen = 1;
+        header.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        header.msg_controllen = mem::size_of_val(&control) as _;
+        let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+        if size < 0 {
+            self.buffer.clear();
+            return Err(Error::last_os_error());
+        }
+        self.buffer.truncate(size as usize);
+        // without a UDP_GRO message, the buffer holds a single datagram
+        self.segment_size = size as usize;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    self.segment_size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as usize;
+                }
+                cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+            }
+        }
+        self.addr = to_socket_addr(&name)?;
+        Ok(())
+    }
+
+    /// The next datagram of the buffer, None once they were all read
+    fn next_datagram(&mut self) -> Option<&[u8]> {
+        if self.offset >= self.buffer.len() {
+            return None;
+        }
+        let start = self.offset;
+        self.offset = min(start + self.segment_size.max(1), self.buffer.len());
+        Some(&self.buffer[start..self.offset])
+    }
+}
+
+/// How many datagrams from the start of `datagrams` can be sent as one segmented buffer: they must
+/// all be of the same size, but the last one which may be shorter
+fn train_length<B: AsRef<[u8]>>(datagrams: &[B]) -> usize {
+    let segment_size = match datagrams.first() {
+        Some(datagram) => datagram.as_ref().len(),
+        None => return 0,
+    };
+    if segment_size == 0 {
+        return 1;
+    }
+    let mut length = 0;
+    for datagram in datagrams.iter().take(min(MAX_SEGMENTS, MAX_SEGMENTED_SIZE / segment_size)) {
+        let size = datagram.as_ref().len();
+        if size > segment_size {
+            break;
+        }
+        length += 1;
+        if size < segment_size {
+            break;
+        }
+    }
+    length
+}
+
+impl DatagramTransport for OffloadUdpSocket {
+    type Addr = SocketAddr;
+
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> Result<usize> {
+        self.socket.send_to(buf, addr)
+    }
+
+    /// A datagram coalesced with others is read from the buffer they were received in, only once
+    /// all of them are read is another buffer received.
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut received = self.received.borrow_mut();
+        if received.offset >= received.buffer.len() {
+            received.receive(&self.socket)?;
+        }
+        let addr = received.addr;
+        let datagram = received.next_datagram().unwrap_or(&[]);
+        let size = min(buf.len(), datagram.len());
+        buf[..size].copy_from_slice(&datagram[..size]);
+        Ok((size, addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        self.socket.set_nonblocking(true)
+    }
+
+    fn ip_addr(addr: &SocketAddr) -> Option<IpAddr> {
+        Some(addr.ip())
+    }
+
+    /// Sends the datagrams from the start of `datagrams` that make a train with one segmented
+    /// buffer, or the first datagram alone.
+    fn send_batch<B: AsRef<[u8]>>(&self, datagrams: &[B], addr: &SocketAddr) -> Result<usize> {
+        let length = if self.segmentation.get() { train_length(datagrams) } else { 1 };
+        if length >= 2 {
+            let segment_size = datagrams[0].as_ref().len();
+            match self.send_segmented(&datagrams[..length], segment_size, addr) {
+                Ok(()) => return Ok(length),
+                // EIO: the network device can't compute the checksums of the segments
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) || e.raw_os_error() == Some(libc::EINVAL) => {
+                    warn!("segmentation offload failed, sending datagrams one by one from now on: {}", e);
+                    self.segmentation.set(false);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        match datagrams.first() {
+            Some(datagram) => self.socket.send_to(datagram.as_ref(), addr).map(|_| 1),
+            None => Ok(0),
+        }
+    }
+}
+
+impl fmt::Debug for OffloadUdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OffloadUdpSocket")
+            .field("socket", &self.socket)
+            .field("segmentation", &self.segmentation.get())
+            .finish()
+    }
+}
+
+#[test]
+fn fragment_trains() {
+    let train: [&[u8]; 4] = [&[1, 2, 3], &[4, 5, 6], &[7, 8], &[9, 10, 11]];
+    assert_eq!(train_length(&train), 3);
+    assert_eq!(train_length(&train[2..]), 1);
+    assert_eq!(train_length(&train[..0]), 0);
+    let large = vec![vec![0u8; 2000]; 40];
+    assert_eq!(train_length(&large), MAX_SEGMENTED_SIZE / 2000);
+}
+
+#[test]
+fn offload_udp_socket() {
+    let socket1 = OffloadUdpSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+    let socket2 = OffloadUdpSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+    socket2.set_nonblocking().unwrap();
+    let datagrams = [vec![1u8; 100], vec![2u8; 100], vec![3u8; 100], vec![4u8; 50], vec![5u8; 10]];
+    let addr2 = socket2.local_addr().unwrap();
+    assert_eq!(socket1.send_batch(&datagrams, &addr2).unwrap(), 4);
+    assert_eq!(socket1.send_batch(&datagrams[4..], &addr2).unwrap(), 1);
+    ::std::thread::sleep(::std::time::Duration::from_millis(10));
+    let mut buffers = vec![vec![0u8; 200]; 6];
+    let mut addrs = Vec::new();
+    assert_eq!(socket2.recv_batch(&mut buffers, &mut addrs).unwrap(), 5);
+    assert_eq!(&buffers[..5], &datagrams[..]);
+    assert_eq!(addrs, vec![socket1.local_addr().unwrap(); 5]);
+}
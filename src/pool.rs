@@ -0,0 +1,171 @@
+//! Buffers reused from one datagram to the next.
+//!
+//! Every datagram is received into, or built in, a buffer taken from the pool of its Socket, and
+//! the buffer is given back once the datagram is sent or parsed. A fragment keeps its buffer until
+//! its message is complete: a message of a single fragment is then handed out in the buffer it was
+//! received in, without being copied.
+
+use std::fmt;
+use std::ops::Deref;
+
+use consts::{MAX_DATAGRAM_SIZE, MAX_POOLED_BUFFERS};
+
+#[derive(Debug)]
+pub (crate) struct BufferPool {
+    buffers: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool { buffers: Vec::new() }
+    }
+
+    /// Returns an empty buffer, large enough to hold any datagram without growing
+    pub fn take(&mut self) -> Vec<u8> {
+        self.buffers.pop().unwrap_or_else(|| Vec::with_capacity(MAX_DATAGRAM_SIZE))
+    }
+
+    /// Keeps a buffer to be taken again, unless it is too small to hold a datagram or enough
+    /// buffers are already kept
+    pub fn give_back(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() >= MAX_DATAGRAM_SIZE && self.buffers.len() < MAX_POOLED_BUFFERS {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+}
+
+/// The data of a received message.
+///
+/// It is still in the buffer of the datagram it arrived in, after the headers, which are skipped
+/// by `Deref` and `AsRef<[u8]>`.
+#[derive(Clone)]
+pub struct Payload {
+    buffer: Vec<u8>,
+    start: usize,
+}
+
+impl Payload {
+    /// Panics if `start` is past the end of `buffer`
+    pub (crate) fn new(buffer: Vec<u8>, start: usize) -> Payload {
+        assert!(start <= buffer.len(), "Payload: cannot start at {}, the buffer holds {} bytes", start, buffer.len());
+        Payload { buffer, start }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+
+    pub (crate) fn reserve(&mut self, additional: usize) {
+        self.buffer.reserve(additional);
+    }
+
+    /// Appends data after the payload, the buffer grows if it needs to
+    pub (crate) fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the buffer the payload is in, headers included, to give it back to a pool
+    pub (crate) fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    /// Moves the data to the beginning of its buffer, nothing is allocated
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.buffer.drain(..self.start);
+        self.buffer
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl fmt::Debug for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // formatted exactly as the slice it holds
+        self.as_slice().fmt(f)
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Payload) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Payload {}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Payload {
+        Payload::new(data, 0)
+    }
+}
+
+impl From<Box<[u8]>> for Payload {
+    fn from(data: Box<[u8]>) -> Payload {
+        Payload::new(data.into_vec(), 0)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Payload {
+    fn from(data: &'a [u8]) -> Payload {
+        Payload::new(data.to_vec(), 0)
+    }
+}
+
+impl From<Payload> for Vec<u8> {
+    fn from(payload: Payload) -> Vec<u8> {
+        payload.into_vec()
+    }
+}
+
+impl From<Payload> for Box<[u8]> {
+    fn from(payload: Payload) -> Box<[u8]> {
+        payload.into_vec().into_boxed_slice()
+    }
+}
+
+#[test]
+fn buffer_pool_reuse() {
+    let mut pool = BufferPool::new();
+    let mut buffer = pool.take();
+    assert!(buffer.capacity() >= MAX_DATAGRAM_SIZE);
+    buffer.extend_from_slice(&[1, 2, 3]);
+    let ptr = buffer.as_ptr();
+    pool.give_back(buffer);
+    let buffer = pool.take();
+    // the same allocation, emptied
+    assert_eq!(buffer.as_ptr(), ptr);
+    assert!(buffer.is_empty());
+    // buffers too small for a datagram are not kept
+    pool.give_back(vec![0u8; 4]);
+    assert_eq!(pool.len(), 0);
+    for _ in 0..MAX_POOLED_BUFFERS + 1 {
+        pool.give_back(Vec::with_capacity(MAX_DATAGRAM_SIZE));
+    }
+    assert_eq!(pool.len(), MAX_POOLED_BUFFERS);
+}
+
+#[test]
+fn payload_skips_headers() {
+    let payload = Payload::new(vec![9, 9, 1, 2, 3], 2);
+    assert_eq!(&*payload, &[1, 2, 3]);
+    assert_eq!(payload, Payload::from(vec![1, 2, 3]));
+    assert_eq!(format!("{:?}", payload), "[1, 2, 3]");
+    assert_eq!(payload.into_vec(), vec![1, 2, 3]);
+}
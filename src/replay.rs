@@ -0,0 +1,65 @@
+//! Protection against packets captured on the way and sent again later.
+//!
+//! Encrypted packets can't be forged, but they can be replayed: every encrypted packet has a
+//! sequence number, and each one is only accepted once.
+
+use consts::REPLAY_WINDOW_SIZE;
+
+/// No packet has this sequence number, a Socket stops sending before reaching it
+const EMPTY: u32 = u32::MAX;
+
+/// Remembers the sequence numbers of the last `REPLAY_WINDOW_SIZE` packets received.
+///
+/// Packets older than that are rejected, even if they were never received: they were most likely
+/// lost, and the messages they held have been sent again since.
+#[derive(Debug)]
+pub (crate) struct ReplayProtection {
+    most_recent: u32,
+    /// `received[seq % REPLAY_WINDOW_SIZE]` is the most recent seq received at that index
+    received: Box<[u32; REPLAY_WINDOW_SIZE]>,
+}
+
+impl ReplayProtection {
+    pub fn new() -> ReplayProtection {
+        ReplayProtection {
+            most_recent: 0,
+            received: Box::new([EMPTY; REPLAY_WINDOW_SIZE]),
+        }
+    }
+
+    /// Records that the packet `seq` was received. Returns false if it must be dropped,
+    /// because it was already received or is too old to tell.
+    pub fn record(&mut self, seq: u32) -> bool {
+        if seq == EMPTY || (seq as u64) + (REPLAY_WINDOW_SIZE as u64) <= self.most_recent as u64 {
+            return false;
+        }
+        let index = seq as usize % REPLAY_WINDOW_SIZE;
+        let previous = self.received[index];
+        if previous != EMPTY && previous >= seq {
+            return false;
+        }
+        self.received[index] = seq;
+        if seq > self.most_recent {
+            self.most_recent = seq;
+        }
+        true
+    }
+}
+
+#[test]
+fn replay_protection_window() {
+    let mut replay_protection = ReplayProtection::new();
+    assert!(replay_protection.record(0));
+    assert!(!replay_protection.record(0));
+    // out of order, but inside the window
+    assert!(replay_protection.record(5));
+    assert!(replay_protection.record(3));
+    assert!(!replay_protection.record(3));
+    assert!(!replay_protection.record(5));
+
+    assert!(replay_protection.record(5 + REPLAY_WINDOW_SIZE as u32));
+    // 4 was never received, but is now too old
+    assert!(!replay_protection.record(4));
+    assert!(replay_protection.record(6));
+    assert!(!replay_protection.record(EMPTY));
+}
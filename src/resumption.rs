@@ -0,0 +1,33 @@
+//! Sessions that survive a brief disconnect.
+//!
+//! Once `Socket::set_resumption_grace` is called, a connected remote that did not send anything
+//! for too long is suspended instead of disconnected: its seq_ids, the messages it did not
+//! acknowledge yet and the keys of the connection are kept for the grace period. The end that
+//! accepted the connection gave a session ticket to the other end in its ConnectAccept, and the
+//! other end sends it back in ResumeRequests until the session is resumed or the grace period
+//! is over.
+
+/// A session ticket is 16 random bytes
+pub (crate) const SESSION_TICKET_SIZE: usize = 16;
+
+pub (crate) type SessionTicket = [u8; SESSION_TICKET_SIZE];
+
+pub (crate) fn random_session_ticket() -> SessionTicket {
+    let mut ticket = [0u8; SESSION_TICKET_SIZE];
+    getrandom::getrandom(&mut ticket).expect("failed to generate a random session ticket");
+    ticket
+}
+
+/// Compares the tickets in constant time, so that a ticket can't be guessed one byte at a time
+pub (crate) fn verify_session_ticket(expected: &SessionTicket, received: &SessionTicket) -> bool {
+    expected.iter().zip(received.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[test]
+fn session_ticket_verification() {
+    let ticket = random_session_ticket();
+    assert!(verify_session_ticket(&ticket, &ticket));
+    let mut forged = ticket;
+    forged[SESSION_TICKET_SIZE - 1] ^= 1;
+    assert!(!verify_session_ticket(&ticket, &forged));
+}
@@ -0,0 +1,366 @@
+//! A server whose remotes are shared between several threads, on Linux with the `sharded` feature.
+
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::{UdpSocket, ToSocketAddrs, SocketAddr};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender, channel, TryRecvError};
+use std::thread::JoinHandle;
+
+use libc;
+use sockaddr::from_socket_addr;
+use connection::{ConnectionThreadContext, ConnectionMainThreadFatalError, Destination, InData, InEvent, OutData, OutEvent};
+use socket::{RemoteID, Socket, MessageType, SocketErrorKind};
+use ack::MessageToken;
+use fragment::build_fragments_from_data;
+
+/// Data sent by a ShardedConnection, shared by every shard when it is broadcast
+#[derive(Debug)]
+pub (crate) struct Shared<O>(Arc<O>);
+
+impl<O: AsRef<[u8]>> AsRef<[u8]> for Shared<O> {
+    fn as_ref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+#[derive(Debug)]
+struct Shard<O: AsRef<[u8]> + Sync + Send> {
+    thread_handle: JoinHandle<::std::result::Result<(), ConnectionMainThreadFatalError>>,
+    outgoing_data_sender: Sender<OutData<Shared<O>>>,
+    outgoing_event_sender: Sender<OutEvent>,
+}
+
+/// A server `Connection` whose remotes are shared between several threads, each with its own
+/// socket bound to the same port with `SO_REUSEPORT`.
+///
+/// The kernel picks the socket of a datagram from its source and destination addresses, so every
+/// datagram of a remote reaches the same thread, which owns the remote. The index of a RemoteID
+/// tells which thread that is, RemoteIDs are unique across all threads.
+///
+/// It only accepts connections: the answer to a connection started by a thread could reach another
+/// one, `OutEvent::NewConnection` is answered by `InEvent::ConnectFailed` with `ErrorKind::Unsupported`.
+/// For the same reason, a remote moving to another address, or resuming its session from one, is
+/// lost if its new address leads to another thread.
+///
+/// Requests about a remote go to its thread, the others to every thread: each thread answers
+/// `OutEvent::RequestStats` with the stats of its own socket, and accepts its share of
+/// `OutEvent::SetMaxRemotes`.
+#[derive(Debug)]
+pub struct ShardedConnection<O: AsRef<[u8]> + Sync + Send> {
+    should_stop: Arc<AtomicBool>,
+    shards: Vec<Shard<O>>,
+    local_addr: SocketAddr,
+    incoming_data_receiver: Receiver<InData>,
+    incoming_event_receiver: Receiver<InEvent>,
+    /// events about requests that never reach a thread
+    local_events: VecDeque<InEvent>,
+    next_token: u64,
+}
+
+/// Binds a udp socket that other sockets can be bound with to the same address
+fn bind_reuse_port(addr: &SocketAddr) -> Result<UdpSocket> {
+    let family = match *addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // owned right away, to be closed if anything below fails
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enabled: libc::c_int = 1;
+    let r = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_REUSEPORT,
+            &enabled as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if r < 0 {
+        return Err(Error::last_os_error());
+    }
+    let (name, name_len) = from_socket_addr(addr);
+    let r = unsafe {
+        libc::bind(socket.as_raw_fd(), &name as *const libc::sockaddr_storage as *const libc::sockaddr, name_len)
+    };
+    if r < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+impl<O: AsRef<[u8]> + Sync + Send + 'static> ShardedConnection<O> {
+    /// Binds `shards` sockets to `address`, each driven by its own thread.
+    ///
+    /// With port 0, the first socket picks the port the others are bound to.
+    pub fn new<A: ToSocketAddrs>(address: A, shards: usize) -> Result<ShardedConnection<O>> {
+        if shards == 0 || shards > u32::MAX as usize {
+            return Err(Error::new(ErrorKind::InvalidInput, "a sharded connection needs between 1 and u32::MAX shards"));
+        }
+        let address = address.to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no address to bind to"))?;
+        let first_socket = bind_reuse_port(&address)?;
+        let local_addr = first_socket.local_addr()?;
+        let mut udp_sockets = vec![first_socket];
+        for _ in 1..shards {
+            udp_sockets.push(bind_reuse_port(&local_addr)?);
+        }
+
+        let (in_data_sender, in_data_receiver) = channel::<InData>();
+        let (in_event_sender, in_event_receiver) = channel::<InEvent>();
+        let should_stop = Arc::new(AtomicBool::new(false));
+        let mut connection_shards = Vec::with_capacity(shards);
+        for (shard, udp_socket) in udp_sockets.into_iter().enumerate() {
+            let (out_data_sender, out_data_receiver) = channel::<OutData<Shared<O>>>();
+            let (out_event_sender, out_event_receiver) = channel::<OutEvent>();
+            let in_data_sender = in_data_sender.clone();
+            let in_event_sender = in_event_sender.clone();
+            let should_stop = should_stop.clone();
+            let thread_builder = ::std::thread::Builder::new();
+            let thread_handle = thread_builder.name(format!("connection_shard_thread_{}", shard)).spawn(move || {
+                let mut socket = Socket::new(udp_socket);
+                socket.set_shard(shard as u32, shards as u32);
+                ConnectionThreadContext {
+                    socket,
+                    in_data_sender,
+                    in_event_sender,
+                    out_data_receiver,
+                    out_event_receiver,
+                    should_stop,
+                }.start()
+            }).expect("Could not spawn connection_shard_thread correctly");
+            connection_shards.push(Shard {
+                thread_handle,
+                outgoing_data_sender: out_data_sender,
+                outgoing_event_sender: out_event_sender,
+            });
+        }
+
+        Ok(ShardedConnection {
+            should_stop,
+            shards: connection_shards,
+            local_addr,
+            incoming_data_receiver: in_data_receiver,
+            incoming_event_receiver: in_event_receiver,
+            local_events: VecDeque::new(),
+            next_token: 0,
+        })
+    }
+
+    /// The address every socket is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// How many threads share the remotes
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The thread owning `remote_id`
+    pub fn shard_of(&self, remote_id: RemoteID) -> usize {
+        remote_id.index() as usize % self.shards.len()
+    }
+
+    /// Stops every thread.
+    pub fn shutdown(self) -> ::std::result::Result<(), ConnectionMainThreadFatalError> {
+        self.should_stop.store(true, Ordering::Relaxed);
+        let mut r = Ok(());
+        for shard in self.shards {
+            let shard_r = shard.thread_handle.join().unwrap();
+            r = r.and(shard_r);
+        }
+        r
+    }
+
+    /// Sends data to a remote, see `Connection::send_data`.
+    pub fn send_data(&mut self, remote_id: RemoteID, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        self.send_data_to(Destination::Remote(remote_id), data, message_type, priority)
+    }
+
+    /// Sends data to every connected remote of every thread, see `Socket::broadcast_message`.
+    pub fn broadcast_data(&mut self, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        self.send_data_to(Destination::AllRemotes, data, message_type, priority)
+    }
+
+    /// Sends data to the remotes of a group, whatever thread they belong to.
+    pub fn send_group_data(&mut self, group: &str, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        self.send_data_to(Destination::Group(group.to_owned()), data, message_type, priority)
+    }
+
+    /// Sends data to a remote, to every remote or to a group.
+    ///
+    /// Data sent to several remotes is shared by the threads, it is not copied.
+    pub fn send_data_to(&mut self, destination: Destination, data: O, message_type: MessageType, priority: i8) -> MessageToken {
+        let token = MessageToken(self.next_token);
+        self.next_token += 1;
+        let data = Arc::new(data);
+        let shards = match destination {
+            Destination::Remote(remote_id) => {
+                let shard = self.shard_of(remote_id);
+                &self.shards[shard..shard + 1]
+            },
+            Destination::AllRemotes | Destination::Group(_) => {
+                // checked once here, instead of a BroadcastFailed event from every thread
+                if build_fragments_from_data(&(*data).as_ref(), 0).is_err() {
+                    self.local_events.push_back(InEvent::BroadcastFailed(token, SocketErrorKind::MessageTooLarge));
+                    return token;
+                }
+                &self.shards[..]
+            },
+        };
+        for shard in shards {
+            shard.outgoing_data_sender.send(OutData {
+                destination: destination.clone(),
+                data: Shared(data.clone()),
+                message_type,
+                priority,
+                token,
+            }).expect("could not connect to remote thread");
+        }
+        token
+    }
+
+    pub fn send_forgettable_data(&mut self, remote_id: RemoteID, data: O) -> MessageToken {
+        self.send_data(remote_id, data, MessageType::Forgettable, 0)
+    }
+
+    /// Returns the data received by any thread
+    #[allow(clippy::result_unit_err)]
+    pub fn receive_data(&mut self) -> ::std::result::Result<Option<InData>, ()> {
+        match self.incoming_data_receiver.try_recv() {
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(()),
+            Ok(m) => Ok(Some(m))
+        }
+    }
+
+    /// Returns the events of any thread
+    #[allow(clippy::result_unit_err)]
+    pub fn receive_event(&mut self) -> ::std::result::Result<Option<InEvent>, ()> {
+        if let Some(event) = self.local_events.pop_front() {
+            return Ok(Some(event));
+        }
+        match self.incoming_event_receiver.try_recv() {
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(()),
+            Ok(m) => Ok(Some(m))
+        }
+    }
+
+    /// Exec a request, on the thread it is about or on every thread
+    pub fn send_request(&mut self, event: OutEvent) {
+        let shards = self.shards.len();
+        let shard = match event {
+            OutEvent::NewConnection(addr) | OutEvent::NewConnectionWithToken(addr, _) => {
+                self.local_events.push_back(InEvent::ConnectFailed(addr, ErrorKind::Unsupported));
+                return;
+            },
+            OutEvent::Disconnect(remote_id)
+            | OutEvent::RequestRemoteStats(remote_id)
+            | OutEvent::BanRemote(remote_id, _)
+            | OutEvent::JoinGroup(_, remote_id)
+            | OutEvent::LeaveGroup(_, remote_id) => Some(self.shard_of(remote_id)),
+            OutEvent::RequestStats
+            | OutEvent::BanIp(..)
+            | OutEvent::UnbanIp(_)
+            | OutEvent::SetRateLimits(_)
+            | OutEvent::SetPreSharedKey(_)
+            | OutEvent::SetChecksum(_)
+            | OutEvent::SetMaxRemotes(_)
+            | OutEvent::SetResumptionGrace(_) => None,
+        };
+        if let Some(shard) = shard {
+            self.shards[shard].outgoing_event_sender.send(event).expect("could not connect to remote thread");
+            return;
+        }
+        let event = match event {
+            // every thread accepts its share of the remotes
+            OutEvent::SetMaxRemotes(max_remotes) => OutEvent::SetMaxRemotes(max_remotes.div_ceil(shards)),
+            event => event,
+        };
+        for shard in &self.shards {
+            shard.outgoing_event_sender.send(event.clone()).expect("could not connect to remote thread");
+        }
+    }
+
+    /// Asks every thread for the statistics of its socket, each answers with an `InEvent::Stats`.
+    ///
+    /// Returns Error when a thread was killed
+    #[allow(clippy::result_unit_err)]
+    pub fn request_stats(&mut self) -> ::std::result::Result<(), ()> {
+        for shard in &self.shards {
+            shard.outgoing_event_sender.send(OutEvent::RequestStats).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    /// Asks for the statistics of one remote. They will be received as `InEvent::RemoteStats`.
+    ///
+    /// Returns Error when the thread of the remote was killed
+    #[allow(clippy::result_unit_err)]
+    pub fn request_remote_stats(&mut self, remote_id: RemoteID) -> ::std::result::Result<(), ()> {
+        let shard = self.shard_of(remote_id);
+        self.shards[shard].outgoing_event_sender.send(OutEvent::RequestRemoteStats(remote_id)).map_err(|_| ())
+    }
+}
+
+#[test]
+fn sharded_connection() {
+    use std::collections::BTreeSet;
+    use std::time::{Duration, Instant};
+    use pool::Payload;
+    use socket::SocketEvent;
+
+    let mut server = ShardedConnection::<Box<[u8]>>::new("127.0.0.1:0", 4).unwrap();
+    let mut clients: Vec<(Socket, RemoteID)> = (0..8).map(|_| {
+        let mut client = Socket::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let remote_id = client.connect_to(server.local_addr());
+        (client, remote_id)
+    }).collect();
+
+    // every client connects, and says which one it is
+    let mut remote_ids = BTreeSet::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while remote_ids.len() < clients.len() && Instant::now() < deadline {
+        for (i, &mut (ref mut client, remote_id)) in clients.iter_mut().enumerate() {
+            client.prepare_iteration();
+            while let Some(event) = client.next_event() {
+                if event == SocketEvent::Connected(remote_id) {
+                    client.send_key_message(remote_id, &[i as u8], 0).unwrap();
+                }
+            }
+        }
+        while let Some(InEvent::NewConnectionFrom(_, remote_id, true, _)) = server.receive_event().unwrap() {
+            assert!(remote_ids.insert(remote_id));
+        }
+        ::std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(remote_ids.len(), clients.len());
+
+    // the server answers each client through the thread owning its remote
+    let mut answered = 0;
+    while answered < clients.len() && Instant::now() < deadline {
+        for (i, &mut (ref mut client, _)) in clients.iter_mut().enumerate() {
+            client.prepare_iteration();
+            if client.receive_all_messages().into_iter().any(|(_, messages)| messages.contains(&Payload::from(vec![i as u8 * 2]))) {
+                answered += 1;
+            }
+        }
+        while let Some(InData(remote_id, data)) = server.receive_data().unwrap() {
+            assert!(remote_ids.contains(&remote_id));
+            server.send_data(remote_id, vec![data[0] * 2].into_boxed_slice(), MessageType::KeyMessage, 0);
+        }
+        ::std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(answered, clients.len());
+
+    server.send_request(OutEvent::NewConnection("127.0.0.1:1".parse().unwrap()));
+    match server.receive_event().unwrap() {
+        Some(InEvent::ConnectFailed(_, ErrorKind::Unsupported)) => {},
+        e => panic!("expected a ConnectFailed event, got {:?}", e),
+    }
+    server.shutdown().unwrap();
+}
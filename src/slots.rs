@@ -0,0 +1,219 @@
+//! The table holding the remotes of a Socket.
+//!
+//! Slots of removed remotes are reused, and each reuse bumps the generation of the slot: a
+//! RemoteID kept after its remote was removed never designates the remote that took its place.
+
+use std::fmt;
+
+/// Identifies a remote of a Socket: the index of its slot, and the generation of the slot when
+/// the remote was added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RemoteID {
+    index: u32,
+    generation: u32,
+}
+
+impl RemoteID {
+    pub (crate) fn new(index: u32, generation: u32) -> RemoteID {
+        RemoteID { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for RemoteID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.index, self.generation)
+    }
+}
+
+#[derive(Debug)]
+struct Slot<V> {
+    generation: u32,
+    value: Option<V>,
+}
+
+/// Values indexed by RemoteIDs, in a Vec. The indexes of removed values are reused.
+///
+/// The slot at position `p` in the Vec has the index `first + p * stride`: the tables of several
+/// sockets can hand out ids that never collide.
+#[derive(Debug)]
+pub (crate) struct Slots<V> {
+    slots: Vec<Slot<V>>,
+    /// indexes of the empty slots, the last one is reused first
+    free: Vec<u32>,
+    len: usize,
+    first: u32,
+    stride: u32,
+}
+
+impl<V> Slots<V> {
+    pub fn new() -> Slots<V> {
+        Slots::sharded(0, 1)
+    }
+
+    /// Slots whose indexes are all equal to `shard` modulo `shards`
+    pub fn sharded(shard: u32, shards: u32) -> Slots<V> {
+        assert!(shard < shards, "Slots: shard {} out of {} shards", shard, shards);
+        Slots {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            first: shard,
+            stride: shards,
+        }
+    }
+
+    fn position(&self, index: u32) -> Option<usize> {
+        match index.checked_sub(self.first) {
+            Some(offset) if offset % self.stride == 0 => Some((offset / self.stride) as usize),
+            _ => None,
+        }
+    }
+
+    fn index(&self, position: usize) -> u32 {
+        self.first + position as u32 * self.stride
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Inserts the value created by `f` from its id
+    pub fn insert_with<F: FnOnce(RemoteID) -> V>(&mut self, f: F) -> RemoteID {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot { generation: 0, value: None });
+                self.index(self.slots.len() - 1)
+            }
+        };
+        let position = self.position(index).expect("Slots: free index out of this table");
+        let slot = &mut self.slots[position];
+        let id = RemoteID { index, generation: slot.generation };
+        slot.value = Some(f(id));
+        self.len += 1;
+        id
+    }
+
+    /// Returns None if `id` was removed, even if its slot holds another value since
+    pub fn get(&self, id: RemoteID) -> Option<&V> {
+        self.position(id.index)
+            .and_then(|position| self.slots.get(position))
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: RemoteID) -> Option<&mut V> {
+        let position = self.position(id.index)?;
+        self.slots.get_mut(position)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.value.as_mut())
+    }
+
+    /// Takes a value out of its slot, which stays reserved for it until `put_back` is called.
+    ///
+    /// Meanwhile, `get` doesn't find it.
+    pub fn take(&mut self, id: RemoteID) -> Option<V> {
+        let position = self.position(id.index)?;
+        self.slots.get_mut(position)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.value.take())
+    }
+
+    /// Puts back a value taken by `take`
+    pub fn put_back(&mut self, id: RemoteID, value: V) {
+        let position = self.position(id.index).expect("Slots: put back an id of another table");
+        let slot = &mut self.slots[position];
+        assert!(slot.generation == id.generation && slot.value.is_none(), "Slots: {} was not taken", id);
+        slot.value = Some(value);
+    }
+
+    pub fn remove(&mut self, id: RemoteID) -> Option<V> {
+        let position = self.position(id.index)?;
+        let slot = self.slots.get_mut(position).filter(|slot| slot.generation == id.generation)?;
+        let value = slot.value.take()?;
+        self.len -= 1;
+        // a slot whose generation can't be bumped anymore is never used again
+        if let Some(generation) = slot.generation.checked_add(1) {
+            slot.generation = generation;
+            self.free.push(id.index);
+        }
+        Some(value)
+    }
+
+    /// Iterates over the values in the order of their indexes
+    pub fn iter(&self) -> impl Iterator<Item = (RemoteID, &V)> {
+        self.slots.iter().enumerate().filter_map(move |(position, slot)| {
+            slot.value.as_ref().map(|value| (RemoteID { index: self.index(position), generation: slot.generation }, value))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (RemoteID, &mut V)> {
+        let (first, stride) = (self.first, self.stride);
+        self.slots.iter_mut().enumerate().filter_map(move |(position, slot)| {
+            let id = RemoteID { index: first + position as u32 * stride, generation: slot.generation };
+            slot.value.as_mut().map(|value| (id, value))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    /// Removes the values for which `f` returns false
+    pub fn retain<F: FnMut(&V) -> bool>(&mut self, mut f: F) {
+        let removed: Vec<RemoteID> = self.iter().filter(|&(_, value)| !f(value)).map(|(id, _)| id).collect();
+        for id in removed {
+            self.remove(id);
+        }
+    }
+}
+
+#[test]
+fn slots_reuse() {
+    let mut slots = Slots::new();
+    let a = slots.insert_with(|_| "a");
+    let b = slots.insert_with(|_| "b");
+    assert_eq!(slots.remove(a), Some("a"));
+    assert_eq!(slots.remove(a), None);
+    let c = slots.insert_with(|_| "c");
+    // same slot, but the old id doesn't designate the new value
+    assert_eq!(c.index(), a.index());
+    assert_ne!(c, a);
+    assert_eq!(slots.get(a), None);
+    assert_eq!(slots.get(c), Some(&"c"));
+    assert_eq!(slots.len(), 2);
+    slots.retain(|value| *value != "b");
+    assert_eq!(slots.get(b), None);
+    assert_eq!(slots.iter().collect::<Vec<_>>(), vec![(c, &"c")]);
+    // a taken slot is not reused
+    assert_eq!(slots.take(c), Some("c"));
+    assert_eq!(slots.get(c), None);
+    assert_ne!(slots.insert_with(|_| "d").index(), c.index());
+    slots.put_back(c, "c");
+    assert_eq!(slots.get(c), Some(&"c"));
+}
+
+#[test]
+fn sharded_slots() {
+    let mut shard1 = Slots::sharded(1, 3);
+    let mut shard2 = Slots::sharded(2, 3);
+    let a = shard1.insert_with(|_| "a");
+    let b = shard1.insert_with(|_| "b");
+    let c = shard2.insert_with(|_| "c");
+    assert_eq!((a.index(), b.index(), c.index()), (1, 4, 2));
+    assert_eq!(shard1.get(b), Some(&"b"));
+    // the ids of another shard are never found
+    assert_eq!(shard1.get(c), None);
+    assert_eq!(shard1.remove(c), None);
+    assert_eq!(shard1.remove(a), Some("a"));
+    assert_eq!(shard1.insert_with(|_| "d").index(), 1);
+    assert_eq!(shard1.iter().map(|(id, _)| id.index()).collect::<Vec<_>>(), vec![1, 4]);
+}
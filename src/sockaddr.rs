@@ -0,0 +1,56 @@
+//! Conversions between `SocketAddr` and the addresses of the libc socket functions.
+
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use libc;
+
+pub (crate) fn to_socket_addr(name: &libc::sockaddr_storage) -> Result<SocketAddr> {
+    match name.ss_family as libc::c_int {
+        libc::AF_INET => {
+            // sockaddr_storage is large enough and aligned for every kind of address
+            let name = unsafe { &*(name as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(name.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(name.sin_port))))
+        },
+        libc::AF_INET6 => {
+            let name = unsafe { &*(name as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(name.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(name.sin6_port), name.sin6_flowinfo, name.sin6_scope_id)))
+        },
+        family => Err(Error::new(ErrorKind::InvalidData, format!("unexpected address family {}", family))),
+    }
+}
+
+pub (crate) fn from_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let name = unsafe { &mut *(&mut name as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            name.sin_family = libc::AF_INET as libc::sa_family_t;
+            name.sin_port = addr.port().to_be();
+            name.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(ref addr) => {
+            let name = unsafe { &mut *(&mut name as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            name.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            name.sin6_port = addr.port().to_be();
+            name.sin6_addr.s6_addr = addr.ip().octets();
+            name.sin6_flowinfo = addr.flowinfo();
+            name.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (name, len as libc::socklen_t)
+}
+
+#[test]
+fn socket_addr_conversions() {
+    let addrs: [SocketAddr; 2] = ["127.0.0.1:4242".parse().unwrap(), "[::1]:4243".parse().unwrap()];
+    for addr in &addrs {
+        let (name, _) = from_socket_addr(addr);
+        assert_eq!(to_socket_addr(&name).unwrap(), *addr);
+    }
+}
@@ -1,21 +1,34 @@
 use std::net::UdpSocket;
-use std::net::{ToSocketAddrs, SocketAddr};
-use std::rc::Rc;
-use std::cell::{Cell, UnsafeCell};
+use std::net::{ToSocketAddrs, SocketAddr, IpAddr};
+use std::cell::Cell;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::Arc;
+use std::collections::vec_deque::Drain;
 use fnv::FnvHashMap as HashMap;
-use failure::Fail;
 use std::ops::Deref;
-use std::collections::VecDeque;
+use std::collections::{VecDeque, BTreeSet};
 
 use std::io::{Error, ErrorKind};
 
 use consts::*;
-use misc::*;
 use udp_message::*;
 use fragment::*;
 use fragment_combiner::*;
-
-pub type RemoteID = u32;
+use stats::{RemoteStats, SocketStats, RateEstimator};
+use ack::{AckTracker, SentMessages, MessageToken};
+use transport::DatagramTransport;
+use clock::{Clock, SystemClock};
+use cookie::{Cookie, CookieGenerator};
+use token::{ConnectToken, ConnectTokenData, ConnectTokenValidator};
+use crypto::{self, PreSharedKey, SessionKeys, Salt, HandshakeMac, RESPONSE_LABEL, ACCEPT_LABEL};
+use limiter::{RateLimits, RateLimiter, BanList};
+use replay::ReplayProtection;
+use slots::Slots;
+use migration::{ConnectionId, PathToken, PathValidation, random_connection_id, CONNECTION_ID_SIZE};
+use resumption::{SessionTicket, random_session_ticket, verify_session_ticket};
+use pool::{BufferPool, Payload};
+use checksum::Checksum;
+pub use slots::RemoteID;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RemoteStatus {
@@ -24,18 +37,24 @@ pub enum RemoteStatus {
     /// Trying to connect to remote.
     ///
     /// Parameter is the
-    /// number of loops we've had internally without response.
+    /// moment the connection attempt started.
     ///
-    /// If this parameter reaches the equivalent of 10 seconds,
+    /// If no response comes in the next 10 seconds,
     /// the connection becomes disconnected
-    Connecting(u32),
+    Connecting(Instant),
     /// Connection request from remote accepted, waiting
     /// for connection acknowlegment
     ///
     /// See Connecting(_) for an explanation on the parameter
-    AckConnecting(u32),
+    AckConnecting(Instant),
     /// Connected to remote
     Connected,
+    /// Nothing was received from the remote for too long, but the session is kept so that it can be
+    /// resumed, see `Socket::set_resumption_grace`.
+    ///
+    /// The parameter is the moment the remote was suspended. Messages sent to it are sent once it
+    /// is resumed.
+    Suspended(Instant),
     /// Disconnected from remote. Remote may be destroyed anytime soon
     Disconnected,
 }
@@ -48,36 +67,218 @@ impl Default for RemoteStatus {
 }
 
 #[derive(Debug)]
-struct Remote {
+struct Remote<A> {
     pub (self) id: RemoteID,
-    pub (self) remote_socket_addr: SocketAddr,
-    pub (self) status: Cell<RemoteStatus>,
-    pub (self) next_seq_id: Cell<u32>,
-    fragment_combiner: UnsafeCell<FragmentCombiner<StrippedBoxedSlice<u8>>>,
-}
-
-impl Remote {
-    pub fn push_udp_message(&self, udp_message: UdpMessage<Box<[u8]>>) {
-        unsafe {
-            let ref mut fragment_combiner = *self.fragment_combiner.get();
-            match udp_message.into_fragment() {
-                Ok(fragment) => {
-                    fragment_combiner.push(fragment);
-                },
-                Err(_) => {
-                    // TODO handle the error
-                    // maybe log something?
+    /// changes when the remote moves to another address, see `migration`
+    pub (self) remote_socket_addr: A,
+    pub (self) status: RemoteStatus,
+    pub (self) next_seq_id: u32,
+    fragment_combiner: FragmentCombiner<Payload>,
+    ack_tracker: AckTracker,
+    /// the acks being sent, kept to send the next ones without allocating
+    acks: Vec<Ack>,
+    sent_messages: SentMessages,
+    stats: RemoteStats,
+    send_rate: RateEstimator,
+    /// last time a valid packet was received from this remote
+    last_received: Instant,
+    /// last time a packet was sent to this remote
+    last_sent: Instant,
+    /// messages waiting for the connection to be established
+    send_queue: Vec<QueuedMessage>,
+    /// the cookie the remote challenged us with, while connecting
+    cookie: Option<Cookie>,
+    /// our salt for the keys of this connection
+    salt: Salt,
+    /// the salt the remote sent during the handshake
+    remote_salt: Salt,
+    /// the keys of the connection, once the handshake is done if the socket has a pre-shared key
+    session: Option<SessionKeys>,
+    /// what the keys of the connection are derived from: the pre-shared key, or the session key of
+    /// the connect token. With one, unencrypted packets other than the handshake are dropped
+    key: Option<PreSharedKey>,
+    /// sequence number of the next encrypted packet
+    next_packet_seq: u32,
+    /// sequence numbers of the encrypted packets received
+    replay_protection: ReplayProtection,
+    /// the token we send in our ConnectResponse, while connecting
+    connect_token: Option<ConnectToken>,
+    /// what the connect token of the remote held, if we required one
+    token_data: Option<ConnectTokenData>,
+    /// the connection id we picked, the remote appends it to its packets
+    connection_id: ConnectionId,
+    /// the connection id the remote picked, sent during the handshake
+    remote_connection_id: Option<ConnectionId>,
+    /// the challenge sent to the new address of the remote, if it moved
+    path_validation: PathValidation<A>,
+    /// the ticket we give in our ConnectAccept, to resume the session
+    session_ticket: SessionTicket,
+    /// the ticket the remote gave us if it accepted our connection
+    remote_session_ticket: Option<SessionTicket>,
+    /// true if the remote connected to us, only those count against `max_remotes`
+    accepted: bool,
+    /// sealed datagrams the transport had no room for, sent before the next ones
+    unsent: Vec<Vec<u8>>,
+}
+
+/// A message sent to several remotes: it is copied once, and fragmented once for the remotes it
+/// is sent to right away. Each of them only gets its own seq_id, seal and connection id.
+struct SharedMessage {
+    data: Arc<[u8]>,
+    /// the fragments with a seq_id of 0, empty if the message was not fragmented yet
+    fragments: Vec<UdpMessage<Vec<u8>>>,
+}
+
+/// A message sent before the connection was established
+#[derive(Debug)]
+struct QueuedMessage {
+    /// shared by every remote the message was broadcast to
+    data: Arc<[u8]>,
+    message_type: MessageType,
+    priority: i8,
+    token: MessageToken,
+}
+
+impl<A: ::std::fmt::Debug + Clone + PartialEq> Remote<A> {
+    fn new(id: RemoteID, remote_socket_addr: A, status: RemoteStatus, key: Option<PreSharedKey>, connection_id: ConnectionId, now: Instant) -> Remote<A> {
+        Remote {
+            id,
+            remote_socket_addr,
+            status,
+            next_seq_id: 0,
+            fragment_combiner: FragmentCombiner::new(),
+            ack_tracker: AckTracker::new(),
+            acks: Vec::new(),
+            sent_messages: SentMessages::new(),
+            stats: Default::default(),
+            send_rate: RateEstimator::new(now),
+            last_received: now,
+            last_sent: now,
+            send_queue: Vec::new(),
+            cookie: None,
+            salt: crypto::random_salt(),
+            remote_salt: Default::default(),
+            session: None,
+            key,
+            next_packet_seq: 0,
+            replay_protection: ReplayProtection::new(),
+            connect_token: None,
+            token_data: None,
+            connection_id,
+            remote_connection_id: None,
+            path_validation: PathValidation::new(),
+            session_ticket: random_session_ticket(),
+            remote_session_ticket: None,
+            accepted: false,
+            unsent: Vec::new(),
+        }
+    }
+
+    fn set_status(&mut self, status: RemoteStatus) {
+        debug!("remote {}: {:?} -> {:?}", self.id, self.status, status);
+        self.status = status;
+    }
+
+    /// Handles a message received from this remote: fragments are pushed into the FragmentCombiner,
+    /// acks are applied to the messages we sent. The type of the packet and the packet itself if it
+    /// is a handshake or a path packet are returned, so that the Socket can handle the other packets.
+    ///
+    /// Encrypted packets are decrypted first, the others are checked with `checksum`. If the
+    /// message is not a valid packet, it is dropped and the reason is returned. The buffer of the
+    /// message is given back to `pool`, unless a fragment keeps it.
+    pub fn push_udp_message(&mut self, udp_message: UdpMessage<Vec<u8>>, checksum: Checksum, now: Instant, events: &mut VecDeque<SocketEvent>, pool: &mut BufferPool) -> Result<(PacketType, Option<OwnedPacket>), UdpMessageError> {
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += udp_message.as_bytes().len() as u64;
+        // the connection id was only needed to find us
+        let packet = match udp_message.check(checksum).and_then(UdpMessage::strip_connection_id) {
+            Err(e) => Err(e),
+            Ok(udp_message) => if udp_message.is_encrypted() {
+                match self.session {
+                    Some(ref keys) => udp_message.open(keys).and_then(|(seq, udp_message)| {
+                        // only once the packet is authenticated, or anyone could move the window
+                        if self.replay_protection.record(seq) {
+                            udp_message.into_packet(pool)
+                        } else {
+                            Err(UdpMessageError::Replayed)
+                        }
+                    }),
+                    None => Err(UdpMessageError::Unauthenticated),
+                }
+            } else {
+                match udp_message.into_packet(pool) {
+                    Ok(Packet::Handshake(handshake)) => Ok(Packet::Handshake(handshake)),
+                    // anyone could have sent it
+                    Ok(_) if self.key.is_some() => Err(UdpMessageError::Unauthenticated),
+                    r => r,
+                }
+            },
+        };
+        match packet {
+            Ok(Packet::Fragment(fragment)) => {
+                trace!("remote {}: received fragment {}/{} of seq_id {}", self.id, fragment.frag_id, fragment.frag_total, fragment.seq_id);
+                self.last_received = now;
+                let is_new = self.ack_tracker.record(fragment.seq_id, fragment.frag_id);
+                for seq_id in self.ack_tracker.drain_forgotten() {
+                    // the rest of its fragments would be ignored
+                    self.fragment_combiner.forget(seq_id, pool);
                 }
+                if is_new {
+                    self.fragment_combiner.push(fragment, pool);
+                } else {
+                    // sent again because our ack was lost, the message must not be received twice
+                    trace!("remote {}: ignoring duplicate fragment {} of seq_id {}", self.id, fragment.frag_id, fragment.seq_id);
+                    pool.give_back(fragment.data.into_buffer());
+                }
+                Ok((PacketType::Fragment, None))
+            },
+            Ok(Packet::Ack(ack)) => {
+                trace!("remote {}: received ack for seq_id {}: {:#b}", self.id, ack.seq_id, ack.received_frags);
+                self.last_received = now;
+                if let Some(token) = self.sent_messages.on_ack(ack, now) {
+                    events.push_back(SocketEvent::Acked(self.id, token));
+                }
+                Ok((PacketType::Ack, None))
+            },
+            Ok(Packet::Control(packet_type)) => {
+                trace!("remote {}: received {:?}", self.id, packet_type);
+                self.last_received = now;
+                Ok((packet_type, None))
+            },
+            Ok(packet) => {
+                // a handshake, path or resume packet
+                let packet_type = packet.packet_type();
+                trace!("remote {}: received {:?}", self.id, packet_type);
+                self.last_received = now;
+                Ok((packet_type, Some(packet)))
+            },
+            Err(e) => {
+                debug!("remote {}: dropping invalid packet from {:?}: {:?}", self.id, self.remote_socket_addr, e);
+                self.stats.dropped_fragments.count(e);
+                Err(e)
             }
         }
     }
 
     /// calls FragmentCombiner::extract_out_messages
-    pub fn extract_out_messages(&self) -> VecDeque<Box<[u8]>> {
-        unsafe {
-            let fragment_combiner_ptr = self.fragment_combiner.get();
-            let ref mut fragment_combiner = *fragment_combiner_ptr;
-            fragment_combiner.extract_out_messages()
+    pub fn extract_out_messages(&mut self) -> VecDeque<Payload> {
+        let messages = self.fragment_combiner.extract_out_messages();
+        self.stats.messages_received += messages.len() as u64;
+        messages
+    }
+
+    fn record_sent_packet(&mut self, bytes: usize, now: Instant) {
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += bytes as u64;
+        self.send_rate.record(bytes, now);
+        self.last_sent = now;
+    }
+
+    fn stats(&mut self, now: Instant) -> RemoteStats {
+        RemoteStats {
+            send_rate: self.send_rate.rate(now),
+            rtt: self.sent_messages.rtt(),
+            packet_loss: self.sent_messages.packet_loss(),
+            .. self.stats
         }
     }
 }
@@ -112,12 +313,55 @@ pub enum MessageType {
     KeyMessage,
 }
 
-#[derive(Debug, Fail)]
+#[derive(Debug)]
 pub enum SocketError {
-    #[fail(display = "Invalid Remote ID: {:?}", _0)]
     InvalidRemoteId(RemoteID),
-    #[fail(display = "IO error: {}", _0)]
+    MessageTooLarge(usize),
+    RemoteUnreachable(RemoteID),
     IoError(::std::io::Error),
+    ChecksumNeedsEncryption,
+    /// Every packet the keys of the connection can encrypt was sent, the remote is disconnected
+    KeysExhausted(RemoteID),
+}
+
+impl ::std::fmt::Display for SocketError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            SocketError::InvalidRemoteId(ref remote_id) => write!(f, "Invalid Remote ID: {:?}", remote_id),
+            SocketError::MessageTooLarge(size) => write!(f, "Message of {} bytes is too large to be fragmented", size),
+            SocketError::RemoteUnreachable(ref remote_id) => write!(f, "Remote {:?} is unreachable", remote_id),
+            SocketError::IoError(ref e) => write!(f, "IO error: {}", e),
+            SocketError::ChecksumNeedsEncryption => write!(f, "Packets can't go unchecked without encryption"),
+            SocketError::KeysExhausted(ref remote_id) => write!(f, "No packet can be encrypted for remote {:?} anymore", remote_id),
+        }
+    }
+}
+
+impl ::std::error::Error for SocketError {}
+
+/// A lightweight, `Copy` version of `SocketError`, mostly used to forward errors
+/// through events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketErrorKind {
+    InvalidRemoteId,
+    MessageTooLarge,
+    RemoteUnreachable,
+    Io(ErrorKind),
+    ChecksumNeedsEncryption,
+    KeysExhausted,
+}
+
+impl SocketError {
+    pub fn kind(&self) -> SocketErrorKind {
+        match *self {
+            SocketError::InvalidRemoteId(_) => SocketErrorKind::InvalidRemoteId,
+            SocketError::MessageTooLarge(_) => SocketErrorKind::MessageTooLarge,
+            SocketError::RemoteUnreachable(_) => SocketErrorKind::RemoteUnreachable,
+            SocketError::IoError(ref e) => SocketErrorKind::Io(e.kind()),
+            SocketError::ChecksumNeedsEncryption => SocketErrorKind::ChecksumNeedsEncryption,
+            SocketError::KeysExhausted(_) => SocketErrorKind::KeysExhausted,
+        }
+    }
 }
 
 impl From<::std::io::Error> for SocketError {
@@ -126,131 +370,2501 @@ impl From<::std::io::Error> for SocketError {
     }
 }
 
+/// Something that happened inside the Socket that could not be returned
+/// directly to the caller, usually because it happened while receiving.
+///
+/// Events are queued inside the Socket, use `Socket::next_event` to retrieve them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketEvent {
+    /// Receiving from the udp socket failed with this error.
+    ///
+    /// The socket is still usable, but some incoming datagrams may have been lost.
+    ReceiveFailed(ErrorKind),
+    /// Sending a packet that was not directly requested by the user (an ack for instance) failed.
+    SendFailed(RemoteID, SocketErrorKind),
+    /// The message with this token was entirely received by the remote
+    Acked(RemoteID, MessageToken),
+    /// The message with this token was not acknowledged in time by the remote, and is considered lost
+    Lost(RemoteID, MessageToken),
+    /// The remote we tried to connect to accepted the connection
+    Connected(RemoteID),
+    /// A new remote connected to us
+    NewRemote(RemoteID),
+    /// The remote we tried to connect to did not answer in time
+    ConnectFailed(RemoteID),
+    /// The remote we tried to connect to refused the connection, it is now disconnected
+    ConnectRejected(RemoteID, RejectReason),
+    /// The remote disconnected itself
+    Disconnected(RemoteID),
+    /// The remote moved to another address, given by `Socket::remote_addr`
+    Migrated(RemoteID),
+    /// Nothing was received from the remote for too long, its session is kept for the resumption
+    /// grace period
+    Suspended(RemoteID),
+    /// The session of a suspended remote was resumed
+    Resumed(RemoteID),
+    /// Nothing was received from the remote for too long, it is now disconnected
+    TimedOut(RemoteID),
+    /// Every packet the keys of the connection can encrypt was sent, the remote is now disconnected
+    KeysExhausted(RemoteID),
+}
+
+/// Returns true if this error is how the OS reports an ICMP "port unreachable"
+/// for one of our previous datagrams.
+///
+/// Depending on the platform, this may show up on `send_to` or on the next `recv_from`
+/// (Windows reports it as `ConnectionReset` on `recv_from`).
+fn is_unreachable_error(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset)
+}
+
+/// Proves that we know the key of the connection. Without key, the mac is only zeroes.
+fn handshake_mac(key: Option<&PreSharedKey>, label: &[u8], parts: &[&[u8]]) -> HandshakeMac {
+    match key {
+        Some(key) => crypto::handshake_mac(key, label, parts),
+        None => Default::default(),
+    }
+}
+
+fn verify_handshake_mac(key: Option<&PreSharedKey>, label: &[u8], parts: &[&[u8]], mac: &HandshakeMac) -> bool {
+    match key {
+        Some(key) => crypto::verify_handshake_mac(key, label, parts, mac),
+        None => true,
+    }
+}
+
+/// The error returned when sending a datagram of `size` bytes to a remote failed
+fn send_error(remote_id: RemoteID, size: usize, e: Error) -> SocketError {
+    warn!("remote {}: sending a packet of {} bytes failed: {}", remote_id, size, e);
+    if is_unreachable_error(e.kind()) {
+        SocketError::RemoteUnreachable(remote_id)
+    } else {
+        SocketError::IoError(e)
+    }
+}
+
+/// Sends and receives messages to and from remotes, over a `DatagramTransport`.
+///
+/// By default, the transport is a `UdpSocket`.
 #[derive(Debug)]
-pub struct Socket {
-    next_remote_id: RemoteID,
-    udp_socket: UdpSocket,
-    remotes: HashMap<RemoteID, Rc<Remote>>,
-    remotes_by_addr: HashMap<SocketAddr, Rc<Remote>>,
+pub struct Socket<T: DatagramTransport = UdpSocket> {
+    transport: T,
+    remotes: Slots<Remote<T::Addr>>,
+    remotes_by_addr: HashMap<T::Addr, RemoteID>,
+    remotes_by_connection_id: HashMap<ConnectionId, RemoteID>,
+    events: VecDeque<SocketEvent>,
+    stats: SocketStats,
+    send_rate: RateEstimator,
+    next_token: u64,
+    clock: Box<dyn Clock>,
+    /// the time given to the last `update`, used instead of the clock until `prepare_iteration`
+    time: Option<Instant>,
+    cookies: CookieGenerator,
+    rate_limiter: RateLimiter<T::Addr>,
+    bans: BanList<T::Addr>,
+    psk: Option<PreSharedKey>,
+    checksum: Checksum,
+    token_validator: Option<ConnectTokenValidator>,
+    max_remotes: usize,
+    resumption_grace: Option<Duration>,
+    /// named groups of remotes, see `join_group`
+    groups: HashMap<String, BTreeSet<RemoteID>>,
+    /// buffers to receive and send datagrams in
+    pool: BufferPool,
+    /// buffers and source addresses of the next batch of received datagrams
+    receive_buffers: Vec<Vec<u8>>,
+    receive_addrs: Vec<T::Addr>,
+    /// sealed datagrams waiting to be sent to a remote in one batch, see `flush_outgoing`
+    outgoing: Vec<Vec<u8>>,
+    /// the remotes being updated by `prepare_iteration`
+    remote_ids: Vec<RemoteID>,
+}
+
+impl Socket<UdpSocket> {
+    pub fn try_connect<A: ToSocketAddrs>(&mut self, remote_addr: A) -> ::std::io::Result<RemoteID> {
+        let remote_addr = remote_addr.to_socket_addrs()?.next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no address to connect to"))?;
+        Ok(self.connect_to(remote_addr))
+    }
 }
 
-impl Socket {
-    pub fn new(udp_socket: UdpSocket) -> Socket {
-        udp_socket.set_nonblocking(true).unwrap();
+impl<T: DatagramTransport> Socket<T> {
+    /// Creates a Socket over `transport`, which is set as non-blocking.
+    pub fn new(transport: T) -> Socket<T> {
+        Socket::with_clock(transport, Box::new(SystemClock))
+    }
+
+    /// Same as `new`, but time is read from `clock` instead of the system's monotonic clock.
+    pub fn with_clock(transport: T, clock: Box<dyn Clock>) -> Socket<T> {
+        transport.set_nonblocking().unwrap();
         Socket {
-            next_remote_id: 0,
-            udp_socket,
-            remotes: Default::default(),
+            transport,
+            remotes: Slots::new(),
             remotes_by_addr: Default::default(),
+            remotes_by_connection_id: Default::default(),
+            events: VecDeque::new(),
+            stats: Default::default(),
+            send_rate: RateEstimator::new(clock.now()),
+            next_token: 0,
+            cookies: CookieGenerator::new(clock.now()),
+            rate_limiter: RateLimiter::new(Default::default(), clock.now()),
+            bans: BanList::new(),
+            psk: None,
+            checksum: Checksum::Crc32,
+            token_validator: None,
+            max_remotes: DEFAULT_MAX_REMOTES,
+            resumption_grace: None,
+            groups: Default::default(),
+            pool: BufferPool::new(),
+            receive_buffers: Vec::new(),
+            receive_addrs: Vec::new(),
+            outgoing: Vec::new(),
+            remote_ids: Vec::new(),
+            clock,
+            time: None,
         }
     }
 
-    pub fn try_connect<A: ToSocketAddrs>(&mut self, remote_addr: A) -> ::std::io::Result<RemoteID> {
-        let remote_addr = remote_addr.to_socket_addrs()?.next().unwrap();
-        let remote = Remote {
-            id: self.next_remote_id,
-            remote_socket_addr: remote_addr,
-            status: Default::default(),
-            next_seq_id: Cell::new(0),
-            fragment_combiner: UnsafeCell::new(FragmentCombiner::new()),
-        };
-        // TODO send a message here
-        // TODO change the status here
-        
-        let remote_id = self.next_remote_id;
-        let remote = Rc::new(remote);
-        self.remotes.insert(remote_id, remote.clone());
-        self.remotes_by_addr.insert(remote_addr, remote);
+    pub fn local_addr(&self) -> ::std::io::Result<T::Addr> {
+        self.transport.local_addr()
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Makes the index of every RemoteID equal to `shard` modulo `shards`, so that the remotes of
+    /// the sockets of a sharded server all have different ids. There must not be any remote yet.
+    pub (crate) fn set_shard(&mut self, shard: u32, shards: u32) {
+        assert_eq!(self.remotes.len(), 0, "the shard of a Socket must be set before it has remotes");
+        self.remotes = Slots::sharded(shard, shards);
+    }
+
+    fn add_remote(&mut self, remote_addr: T::Addr, status: RemoteStatus, key: Option<PreSharedKey>, now: Instant) -> RemoteID {
+        let mut connection_id = random_connection_id();
+        while self.remotes_by_connection_id.contains_key(&connection_id) {
+            connection_id = random_connection_id();
+        }
+        let remote_id = self.remotes.insert_with(|remote_id| Remote::new(remote_id, remote_addr.clone(), status, key, connection_id, now));
+        info!("remote {}: new remote at {:?} ({:?})", remote_id, remote_addr, status);
+        self.remotes_by_addr.insert(remote_addr, remote_id);
+        self.remotes_by_connection_id.insert(connection_id, remote_id);
+        remote_id
+    }
+
+    fn remote(&self, remote_id: RemoteID) -> Result<&Remote<T::Addr>, SocketError> {
+        self.remotes.get(remote_id).ok_or(SocketError::InvalidRemoteId(remote_id))
+    }
+
+    fn remote_mut(&mut self, remote_id: RemoteID) -> Result<&mut Remote<T::Addr>, SocketError> {
+        self.remotes.get_mut(remote_id).ok_or(SocketError::InvalidRemoteId(remote_id))
+    }
+
+    /// Takes a remote out of its slot while `f` works on it and on the rest of the socket, then
+    /// puts it back. Until then, the remote can't be found by its id.
+    fn with_remote<R, F: FnOnce(&mut Self, &mut Remote<T::Addr>) -> R>(&mut self, remote_id: RemoteID, f: F) -> Result<R, SocketError> {
+        let mut remote = self.remotes.take(remote_id).ok_or(SocketError::InvalidRemoteId(remote_id))?;
+        let r = f(self, &mut remote);
+        self.remotes.put_back(remote_id, remote);
+        Ok(r)
+    }
+
+    /// Starts connecting to the remote at address `remote_addr`.
+    ///
+    /// A `SocketEvent::Connected` or a `SocketEvent::ConnectFailed` event tells how it went.
+    /// Messages can be sent to the remote right away, they are sent once the connection is
+    /// established.
+    pub fn connect_to(&mut self, remote_addr: T::Addr) -> RemoteID {
+        self.connect(remote_addr, None)
+    }
+
+    /// Same as `connect_to`, for a server that requires connect tokens.
+    ///
+    /// The token is only sent once the server proved it is at `remote_addr`. Without a pre-shared
+    /// key, the connection is encrypted with the session key of the token.
+    pub fn connect_with_token(&mut self, remote_addr: T::Addr, token: ConnectToken) -> RemoteID {
+        self.connect(remote_addr, Some(token))
+    }
+
+    fn connect(&mut self, remote_addr: T::Addr, token: Option<ConnectToken>) -> RemoteID {
+        if let Some(remote) = self.remotes_by_addr.get(&remote_addr).and_then(|remote_id| self.remotes.get(*remote_id)) {
+            if remote.status != RemoteStatus::Disconnected {
+                return remote.id;
+            }
+        }
+        let now = self.now();
+        let key = self.psk.or_else(|| token.as_ref().and_then(ConnectToken::session_key));
+        let remote_id = self.add_remote(remote_addr, RemoteStatus::Connecting(now), key, now);
+        let _r = self.with_remote(remote_id, |socket, remote| {
+            remote.connect_token = token;
+            socket.send_handshake(remote, now);
+        });
+        remote_id
+    }
+
+    /// Disconnects a remote, and tells it so.
+    ///
+    /// Messages to this remote that were not acknowledged yet are reported as lost.
+    pub fn disconnect(&mut self, remote_id: RemoteID) -> Result<(), SocketError> {
+        let now = self.now();
+        self.with_remote(remote_id, |socket, remote| {
+            if remote.status == RemoteStatus::Disconnected {
+                return Err(SocketError::InvalidRemoteId(remote_id));
+            }
+            info!("remote {}: disconnecting", remote_id);
+            socket.send_control(remote, PacketType::Disconnect, now);
+            socket.disconnect_remote(remote, None);
+            Ok(())
+        })?
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limiter.limits()
+    }
+
+    /// Changes the limits applied to every source address
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter.set_limits(limits);
+    }
+
+    /// Sets the key shared with the remotes, or removes it.
+    ///
+    /// With a key, remotes must prove they know it during the handshake, and every packet is
+    /// encrypted with keys derived from it, different for every connection. It only applies to
+    /// the connections established after this call.
+    ///
+    /// Removing the key while the checksum is `Checksum::None` puts `Checksum::Crc32` back.
+    pub fn set_pre_shared_key(&mut self, psk: Option<PreSharedKey>) {
+        self.psk = psk;
+        if self.checksum == Checksum::None && !self.encrypts() {
+            self.checksum = Checksum::Crc32;
+        }
+    }
+
+    /// true if the packets of the new connections are encrypted
+    fn encrypts(&self) -> bool {
+        self.psk.is_some()
+    }
+
+    pub fn checksum(&self) -> Checksum {
+        self.checksum
+    }
+
+    /// Changes how the packets that are not encrypted are checked for corruption, `Checksum::Crc32`
+    /// by default.
+    ///
+    /// Remotes must use the same: we are rejected by the servers that don't, and we reject the
+    /// clients that don't. The remotes already connected expect the previous one, so it must be
+    /// changed before connecting or accepting connections.
+    ///
+    /// `Checksum::None` is refused with `SocketError::ChecksumNeedsEncryption` unless a pre-shared
+    /// key is set: nothing would detect the corrupted packets otherwise.
+    pub fn set_checksum(&mut self, checksum: Checksum) -> Result<(), SocketError> {
+        if checksum == Checksum::None && !self.encrypts() {
+            return Err(SocketError::ChecksumNeedsEncryption);
+        }
+        self.checksum = checksum;
+        Ok(())
+    }
+
+    pub fn max_remotes(&self) -> usize {
+        self.max_remotes
+    }
+
+    /// Changes how many remotes can be connected at once. Past this, new connections are refused
+    /// with `RejectReason::ServerFull`, but we can still connect to remotes ourselves.
+    pub fn set_max_remotes(&mut self, max_remotes: usize) {
+        self.max_remotes = max_remotes;
+    }
+
+    pub fn resumption_grace(&self) -> Option<Duration> {
+        self.resumption_grace
+    }
+
+    /// Keeps the remotes that time out for `grace`, so that their session can be resumed, or
+    /// disconnects them right away if it is None.
+    ///
+    /// A suspended remote keeps its seq_ids and the messages it did not acknowledge yet. If it
+    /// accepted our connection, we try to resume the session with the ticket it gave us;
+    /// otherwise we wait for it to do so. Once `grace` is over, it is disconnected with a
+    /// `SocketEvent::TimedOut`.
+    pub fn set_resumption_grace(&mut self, grace: Option<Duration>) {
+        self.resumption_grace = grace;
+    }
+
+    /// Requires a valid connect token from every remote that connects to us, or stops requiring them.
+    ///
+    /// What the token of a remote holds is then given by `connect_token_data`.
+    pub fn require_connect_tokens(&mut self, validator: Option<ConnectTokenValidator>) {
+        self.token_validator = validator;
+    }
+
+    /// Returns what the connect token of a remote held, if we required one when it connected
+    pub fn connect_token_data(&self, remote_id: RemoteID) -> Result<Option<ConnectTokenData>, SocketError> {
+        let remote = self.remote(remote_id)?;
+        Ok(remote.token_data.clone())
+    }
+
+    /// Bans an IP for `duration`, or forever if it is None: everything it sends is dropped.
+    ///
+    /// Its remotes are disconnected. Transports without IPs never match an IP ban.
+    pub fn ban_ip(&mut self, ip: IpAddr, duration: Option<Duration>) {
+        info!("banning {} for {:?}", ip, duration);
+        let now = self.now();
+        self.bans.ban_ip(ip, duration, now);
+        self.disconnect_banned_remotes(now);
+    }
+
+    /// Returns false if this IP was not banned
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        self.bans.unban_ip(&ip)
+    }
+
+    /// Same as `ban_ip`, for a single address
+    pub fn ban_addr(&mut self, addr: T::Addr, duration: Option<Duration>) {
+        info!("banning {:?} for {:?}", addr, duration);
+        let now = self.now();
+        self.bans.ban_addr(addr, duration, now);
+        self.disconnect_banned_remotes(now);
+    }
+
+    /// Returns false if this address was not banned
+    pub fn unban_addr(&mut self, addr: &T::Addr) -> bool {
+        self.bans.unban_addr(addr)
+    }
+
+    /// Bans the IP of a remote, or its address if the transport has no IPs, and disconnects it.
+    pub fn ban_remote(&mut self, remote_id: RemoteID, duration: Option<Duration>) -> Result<(), SocketError> {
+        let addr = self.remote_addr(remote_id)?;
+        match T::ip_addr(&addr) {
+            Some(ip) => self.ban_ip(ip, duration),
+            None => self.ban_addr(addr, duration),
+        }
+        Ok(())
+    }
+
+    pub fn is_banned(&self, addr: &T::Addr) -> bool {
+        self.bans.is_banned(addr, T::ip_addr(addr), self.now())
+    }
+
+    fn disconnect_banned_remotes(&mut self, now: Instant) {
+        let banned: Vec<RemoteID> = self.remotes.values()
+            .filter(|remote| remote.status != RemoteStatus::Disconnected)
+            .filter(|remote| {
+                let addr = &remote.remote_socket_addr;
+                self.bans.is_banned(addr, T::ip_addr(addr), now)
+            })
+            .map(|remote| remote.id)
+            .collect();
+        for remote_id in banned {
+            let _r = self.disconnect(remote_id);
+        }
+    }
+
+    /// Returns the address of a remote
+    pub fn remote_addr(&self, remote_id: RemoteID) -> Result<T::Addr, SocketError> {
+        let remote = self.remote(remote_id)?;
+        Ok(remote.remote_socket_addr.clone())
+    }
+
+    pub fn remote_status(&self, remote_id: RemoteID) -> Result<RemoteStatus, SocketError> {
+        let remote = self.remote(remote_id)?;
+        Ok(remote.status)
+    }
 
-        self.next_remote_id += 1;
-        Ok(remote_id)
+    /// Runs one iteration of the protocol at time `now`, and returns the events that happened
+    /// since the last call.
+    ///
+    /// Pending datagrams are received, acks are sent, messages that were not acknowledged in time are
+    /// sent again or reported as lost, heartbeats are sent to idle remotes, remotes that timed out
+    /// are disconnected, and messages waiting for a connection to be established are sent.
+    ///
+    /// Received messages are then available through `received_messages` or `receive_all_messages_from`.
+    ///
+    /// Until the next iteration, `now` is the time of the Socket: messages sent, connections,
+    /// bans and statistics use it instead of the clock, so that a Socket driven with a fixed
+    /// timestep behaves the same every time.
+    pub fn update(&mut self, now: Instant) -> Drain<'_, SocketEvent> {
+        self.time = Some(now);
+        self.run_iteration(now);
+        self.events.drain(..)
     }
 
+    /// Same as `update` at the time given by the Socket's clock, but the events are kept in
+    /// the Socket, to be retrieved with `next_event`.
     pub fn prepare_iteration(&mut self) {
-        let mut done = false;
-        while !done {
-            match UdpMessage::<Box<[u8]>>::from_udp_socket(&self.udp_socket) {
-                Ok((udp_message, socket_addr)) => {
-                    let remote = self.remotes_by_addr.get(&socket_addr);
-                    match remote {
-                        None => {
-                            // maybe it's someone who tries to connect? Let's try to make contact
-                            unimplemented!()
-                        },
-                        Some(ref remote) => {
-                            // remote is valid, let's push the message into this remote
-                            remote.push_udp_message(udp_message);
+        self.time = None;
+        let now = self.clock.now();
+        self.run_iteration(now);
+    }
+
+    /// The time given to the last `update`, or the time of the clock if the Socket is driven by
+    /// `prepare_iteration`
+    fn now(&self) -> Instant {
+        self.time.unwrap_or_else(|| self.clock.now())
+    }
+
+    fn run_iteration(&mut self, now: Instant) {
+        // they were disconnected during the last iteration, the events about them have been seen
+        self.remotes.retain(|remote| remote.status != RemoteStatus::Disconnected);
+        let remotes = &self.remotes;
+        self.remotes_by_addr.retain(|_, remote_id| remotes.get(*remote_id).is_some());
+        self.remotes_by_connection_id.retain(|_, remote_id| remotes.get(*remote_id).is_some());
+        self.groups.retain(|_, members| {
+            members.retain(|remote_id| remotes.get(*remote_id).is_some());
+            !members.is_empty()
+        });
+        self.rate_limiter.maybe_prune(now);
+        self.bans.prune(now);
+        self.receive_pending(now);
+        let mut remote_ids = ::std::mem::take(&mut self.remote_ids);
+        remote_ids.extend(self.remotes.iter().map(|(remote_id, _)| remote_id));
+        for remote_id in remote_ids.drain(..) {
+            let _r = self.with_remote(remote_id, |socket, remote| socket.update_remote(remote, now));
+        }
+        self.remote_ids = remote_ids;
+    }
+
+    fn update_remote(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        match remote.status {
+            RemoteStatus::NotStarted | RemoteStatus::Disconnected => {},
+            RemoteStatus::Connecting(since) => {
+                if now.duration_since(since) >= CONNECT_TIMEOUT {
+                    info!("remote {}: connection attempt timed out", remote.id);
+                    self.disconnect_remote(remote, Some(SocketEvent::ConnectFailed(remote.id)));
+                } else if now.duration_since(remote.last_sent) >= CONNECT_RETRY_INTERVAL {
+                    self.send_handshake(remote, now);
+                }
+            },
+            RemoteStatus::Suspended(since) => {
+                if now.duration_since(since) >= self.resumption_grace.unwrap_or_default() {
+                    info!("remote {}: timed out, its session was not resumed", remote.id);
+                    self.disconnect_remote(remote, Some(SocketEvent::TimedOut(remote.id)));
+                } else if let Some(ticket) = remote.remote_session_ticket {
+                    if now.duration_since(remote.last_sent) >= CONNECT_RETRY_INTERVAL {
+                        trace!("remote {}: sending ResumeRequest", remote.id);
+                        let udp_message = UdpMessage::resume_request(self.pool.take(), &ticket);
+                        if let Err(e) = self.send_udp_message(remote, udp_message, now) {
+                            self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
                         }
                     }
-                },
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::WouldBlock => {done = true},
-                        e => {
-                            panic!("Prepare iteration failed when receiving udp message: {:?}", e);
-                        }
+                }
+            },
+            RemoteStatus::AckConnecting(_) | RemoteStatus::Connected => {
+                if now.duration_since(remote.last_received) >= REMOTE_TIMEOUT {
+                    if self.resumption_grace.is_some() {
+                        info!("remote {}: timed out, suspending its session", remote.id);
+                        remote.set_status(RemoteStatus::Suspended(now));
+                        self.events.push_back(SocketEvent::Suspended(remote.id));
+                    } else {
+                        info!("remote {}: timed out", remote.id);
+                        self.disconnect_remote(remote, Some(SocketEvent::TimedOut(remote.id)));
+                    }
+                    return;
+                }
+                let mut acks = ::std::mem::take(&mut remote.acks);
+                remote.ack_tracker.drain_acks(&mut acks);
+                for ack in acks.drain(..) {
+                    let udp_message = UdpMessage::ack(self.pool.take(), &ack);
+                    if let Err(e) = self.push_outgoing(remote, udp_message) {
+                        self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
                     }
                 }
+                remote.acks = acks;
+                // expired messages are lost, not sent again
+                let lost_tokens = remote.sent_messages.expire(now);
+                for token in lost_tokens {
+                    debug!("remote {}: message {:?} was lost", remote.id, token);
+                    self.events.push_back(SocketEvent::Lost(remote.id, token));
+                }
+                self.retransmit(remote, now);
+                if now.duration_since(remote.last_sent) >= HEARTBEAT_INTERVAL {
+                    self.send_control(remote, PacketType::Heartbeat, now);
+                }
+            },
+        }
+    }
+
+    /// Sends again the fragments of reliable messages that were not acknowledged in time, and
+    /// the acks queued before.
+    fn retransmit(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        let retransmissions = remote.sent_messages.retransmissions(now);
+        for retransmission in retransmissions {
+            let fragments = match build_fragments_from_data(&retransmission.data, retransmission.seq_id) {
+                Ok(fragments) => fragments,
+                // it was fragmented successfully the first time
+                Err(()) => continue,
+            };
+            let acked_frags = retransmission.acked_frags;
+            for fragment in fragments.filter(|f| acked_frags & (1u64 << f.frag_id) == 0) {
+                trace!("remote {}: sending fragment {}/{} of seq_id {} again", remote.id, fragment.frag_id, fragment.frag_total, fragment.seq_id);
+                remote.stats.retransmissions += 1;
+                self.stats.retransmissions += 1;
+                let udp_message = UdpMessage::fragment(self.pool.take(), &fragment);
+                if let Err(e) = self.push_outgoing(remote, udp_message) {
+                    self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+                    break;
+                }
             }
         }
+        // sent along with the acks
+        if let Err(e) = self.flush_outgoing(remote, now) {
+            self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+        }
     }
 
-    // TODO when impl Trait is done, replace VecDeque by impl Trait
-    /// Returns all received messages for a remote_id. The messages are in the order they *arrived*,
-    /// but it may be different from the order the messages were *sent* from remote.
+    /// Marks a remote as disconnected: it is removed during the next iteration.
     ///
-    /// You *must* call `prepare_iteration` right before calling this function if you want to receive the messages
-    /// properly; otherwise incoming messages will be kept in the queue and you will have no way to have access
-    /// to the new messages.
-    pub fn receive_all_messages_from(&mut self, remote_id: RemoteID) -> Result<VecDeque<Box<[u8]>>, SocketError> {
-        let remote = self.remotes.get(&remote_id).ok_or(SocketError::InvalidRemoteId(remote_id))?;
-        Ok(remote.extract_out_messages())
+    /// Every message that was not acknowledged yet is reported as lost, then `event` is queued.
+    fn disconnect_remote(&mut self, remote: &mut Remote<T::Addr>, event: Option<SocketEvent>) {
+        remote.set_status(RemoteStatus::Disconnected);
+        for buffer in remote.unsent.drain(..) {
+            self.pool.give_back(buffer);
+        }
+        let mut lost_tokens = remote.sent_messages.drain();
+        lost_tokens.extend(remote.send_queue.drain(..).map(|m| m.token));
+        for token in lost_tokens {
+            self.events.push_back(SocketEvent::Lost(remote.id, token));
+        }
+        self.events.extend(event);
     }
 
-    /// Returns all received messages from all remotes
+    /// Sends a packet without payload, errors are reported as events
+    fn send_control(&mut self, remote: &mut Remote<T::Addr>, packet_type: PacketType, now: Instant) {
+        trace!("remote {}: sending {:?}", remote.id, packet_type);
+        let udp_message = UdpMessage::control(self.pool.take(), packet_type);
+        self.send_handshake_message(remote, udp_message, now);
+    }
+
+    /// Sends the next step of the handshake to a remote we are connecting to: a ConnectRequest,
+    /// or a ConnectResponse once it challenged us.
+    fn send_handshake(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        let handshake = match remote.cookie {
+            Some(cookie) => Handshake::Response {
+                cookie,
+                salt: remote.salt,
+                connection_id: remote.connection_id,
+                checksum: self.checksum,
+                mac: handshake_mac(remote.key.as_ref(), RESPONSE_LABEL, &[&cookie, &remote.salt, &remote.connection_id, &[self.checksum as u8]]),
+                token: remote.connect_token.clone(),
+            },
+            None => Handshake::Request,
+        };
+        trace!("remote {}: sending {:?}", remote.id, handshake.packet_type());
+        let udp_message = UdpMessage::handshake(self.pool.take(), &handshake);
+        self.send_handshake_message(remote, udp_message, now);
+    }
+
+    /// Sends a ConnectChallenge with a cookie for `remote`
+    fn send_challenge(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        trace!("remote {}: sending ConnectChallenge", remote.id);
+        let cookie = self.cookies.generate(&remote.remote_socket_addr, now);
+        let udp_message = UdpMessage::handshake(self.pool.take(), &Handshake::Challenge(cookie));
+        self.send_handshake_message(remote, udp_message, now);
+    }
+
+    /// Sends a ConnectAccept to a remote whose ConnectResponse was valid
+    fn send_accept(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        trace!("remote {}: sending ConnectAccept", remote.id);
+        let mac = handshake_mac(remote.key.as_ref(), ACCEPT_LABEL, &[&remote.remote_salt, &remote.salt, &remote.connection_id, &remote.session_ticket]);
+        let accept = Handshake::Accept { salt: remote.salt, connection_id: remote.connection_id, session_ticket: remote.session_ticket, mac };
+        let udp_message = UdpMessage::handshake(self.pool.take(), &accept);
+        self.send_handshake_message(remote, udp_message, now);
+    }
+
+    /// Checks the cookie, the mac and the connect token of a ConnectResponse from `addr`.
+    /// `new_remote` is true if accepting it would add a remote.
     ///
-    /// You don't have to call `prepare_iteration`, it is automatically being done here.
-    pub fn receive_all_messages(&mut self) -> Vec<(RemoteID, VecDeque<Box<[u8]>>)> {
-        self.prepare_iteration();
-        self.remotes
-            .iter()
-            .map(|(remote_id, remote)| {
-                (*remote_id, remote.extract_out_messages())
-            })
-            .collect()
+    /// Anything else than a ConnectResponse is refused.
+    ///
+    /// Returns what the token holds if we require one, and the key of the connection: the
+    /// pre-shared key, or the session key of the token. If the response is refused, the reason
+    /// is returned when the client must be told with a ConnectReject, instead of a new challenge.
+    /// The token is only checked once the cookie proved that `addr` is genuine, and only used up
+    /// once the response is accepted.
+    fn verify_response(&mut self, addr: &T::Addr, response: &Handshake, new_remote: bool, now: Instant) -> Result<(Option<ConnectTokenData>, Option<PreSharedKey>), Option<RejectReason>> {
+        let (cookie, salt, connection_id, checksum, mac, token) = match *response {
+            Handshake::Response { ref cookie, ref salt, ref connection_id, checksum, ref mac, ref token } => (cookie, salt, connection_id, checksum, mac, token.as_ref()),
+            _ => return Err(None),
+        };
+        if !self.cookies.verify(cookie, addr, now) {
+            // forged, or too old: a genuine client will answer a new challenge
+            debug!("invalid cookie from {:?}", addr);
+            self.stats.rejected_cookies += 1;
+            return Err(None);
+        }
+        let opened = match (self.token_validator.as_mut(), token) {
+            (Some(validator), Some(token)) => validator.open(token, SystemTime::now()),
+            _ => None,
+        };
+        let key = self.psk.or_else(|| opened.as_ref().map(|&(_, session_key)| session_key));
+        if !verify_handshake_mac(key.as_ref(), RESPONSE_LABEL, &[cookie, salt, connection_id, &[checksum as u8]], mac) {
+            debug!("connect response from {:?} without a valid proof of the key of the connection", addr);
+            self.stats.unauthenticated_handshakes += 1;
+            return Err(None);
+        }
+        if checksum != self.checksum || (checksum == Checksum::None && key.is_none()) {
+            debug!("rejecting connection from {:?}: it uses {:?} instead of {:?}", addr, checksum, self.checksum);
+            self.stats.rejected_connections += 1;
+            return Err(Some(RejectReason::ChecksumMismatch));
+        }
+        if new_remote && self.connected_remotes() >= self.max_remotes {
+            debug!("rejecting connection from {:?}: {} remotes already", addr, self.max_remotes);
+            self.stats.rejected_connections += 1;
+            return Err(Some(RejectReason::ServerFull));
+        }
+        match (self.token_validator.as_mut(), token, opened) {
+            (None, _, _) => Ok((None, key)),
+            (Some(validator), Some(token), Some((token_data, _))) => {
+                validator.use_up(token);
+                Ok((Some(token_data), key))
+            },
+            _ => {
+                debug!("connect response from {:?} without a valid connect token", addr);
+                self.stats.rejected_tokens += 1;
+                Err(Some(RejectReason::InvalidConnectToken))
+            },
+        }
     }
 
-    pub fn send_message(&mut self, remote_id: RemoteID, message: &[u8], t: MessageType, priority: i8) -> Result<(), SocketError> {
-        let remote = self.remotes.get(&remote_id).ok_or(SocketError::InvalidRemoteId(remote_id))?;
-        let seq_id = remote.deref().next_seq_id.get();
-        let fragments = build_fragments_from_data(&message, seq_id).expect("TODO");
-        for fragment in fragments {
-            let udp_message = UdpMessage::from(&fragment);
-            // TODO remove unwrap
-            let _r = self.udp_socket.send_to(udp_message.as_bytes(), remote.remote_socket_addr).unwrap();
-            // TODO log the error if any
+    /// Remotes that connected to us and are not disconnected, whether they are connected yet or not
+    fn connected_remotes(&self) -> usize {
+        self.remotes.values().filter(|remote| remote.accepted && remote.status != RemoteStatus::Disconnected).count()
+    }
+
+    /// Derives the keys of the connection once both salts are known
+    fn start_session(&self, remote: &mut Remote<T::Addr>, remote_salt: Salt, remote_connection_id: ConnectionId) {
+        remote.remote_salt = remote_salt;
+        remote.remote_connection_id = Some(remote_connection_id);
+        if let Some(ref key) = remote.key {
+            remote.session = Some(SessionKeys::derive(key, &remote.salt, &remote_salt));
         }
-        remote.deref().next_seq_id.set(seq_id + 1);
-        Ok(())
     }
 
-    #[inline]
-    pub fn send_key_message(&mut self, remote_id: RemoteID, message: &[u8], priority: i8) -> Result<(), SocketError> {
-        self.send_message(remote_id, message, MessageType::KeyMessage, priority)
+    fn send_handshake_message(&mut self, remote: &mut Remote<T::Addr>, udp_message: UdpMessage<Vec<u8>>, now: Instant) {
+        if let Err(e) = self.send_udp_message(remote, udp_message, now) {
+            self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+        }
     }
-    
-    #[inline]
-    pub fn send_key_expirable_message(&mut self, remote_id: RemoteID, message: &[u8], expiration_ms: u32, priority: i8) -> Result<(), SocketError> {
-        self.send_message(remote_id, message, MessageType::KeyExpirableMessage(expiration_ms), priority)
+
+    /// Answers a datagram from an address that is not one of our remotes.
+    ///
+    /// Nobody knows whether the address is genuine, so the failures are only logged.
+    fn send_to_unknown(&mut self, handshake: &Handshake, addr: &T::Addr, now: Instant) {
+        let udp_message = UdpMessage::handshake(self.pool.take(), handshake);
+        match self.transport.send_to(udp_message.as_bytes(), addr) {
+            Ok(sent_bytes) => self.record_sent_packet(sent_bytes, now),
+            Err(e) => debug!("sending a packet of {} bytes to unknown address {:?} failed: {}", udp_message.as_bytes().len(), addr, e),
+        }
+        self.pool.give_back(udp_message.into_buffer());
     }
 
-    #[inline]
-    pub fn send_forgettable_message(&mut self, remote_id: RemoteID, message: &[u8], priority: i8) -> Result<(), SocketError> {
-        self.send_message(remote_id, message, MessageType::Forgettable, priority)
+    fn record_sent_packet(&mut self, bytes: usize, now: Instant) {
+        self.stats.packets_sent += 1;
+        self.stats.bytes_sent += bytes as u64;
+        self.send_rate.record(bytes, now);
     }
 
-    #[inline]
-    pub fn send_droppable_message(&mut self, remote_id: RemoteID, message: &[u8], priority: i8) -> Result<(), SocketError> {
-        self.send_message(remote_id, message, MessageType::Droppable, priority)
+    /// Handles a packet from a remote that has nothing to do with messages. `from` is the address
+    /// it came from, which is not the address of the remote if it is moving.
+    fn on_packet(&mut self, remote: &mut Remote<T::Addr>, packet_type: PacketType, packet: Option<OwnedPacket>, from: &T::Addr, now: Instant) {
+        match (remote.status, packet) {
+            (RemoteStatus::Disconnected, _) => {},
+            (_, _) if packet_type == PacketType::Disconnect => {
+                info!("remote {}: disconnected by remote", remote.id);
+                self.disconnect_remote(remote, Some(SocketEvent::Disconnected(remote.id)));
+            },
+            (_, Some(Packet::Handshake(handshake))) => self.on_handshake(remote, handshake, now),
+            (_, Some(Packet::PathChallenge(token))) => {
+                // answered from where it came, that's the address being validated
+                trace!("remote {}: sending PathResponse to {:?}", remote.id, from);
+                let udp_message = UdpMessage::path(self.pool.take(), PacketType::PathResponse, &token);
+                if let Err(e) = self.send_udp_message_to(remote, udp_message, from, now) {
+                    self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+                }
+            },
+            (_, Some(Packet::PathResponse(token))) => self.on_path_response(remote, &token, from),
+            (_, Some(Packet::ResumeRequest(ticket))) => self.on_resume_request(remote, &ticket, from, now),
+            (RemoteStatus::Suspended(_), _) if packet_type == PacketType::ResumeAccept => self.on_resumed(remote, now),
+            (RemoteStatus::Suspended(_), _) => {
+                // the session is only resumed with a ticket, the remote will send one once it timed out too
+            },
+            (RemoteStatus::NotStarted, _) | (RemoteStatus::Connecting(_), _) => {
+                // whatever else the remote sends, it knows about us: the connection is established.
+                self.on_connected(remote, now);
+            },
+            (RemoteStatus::AckConnecting(_), _) => {
+                remote.set_status(RemoteStatus::Connected);
+            },
+            (RemoteStatus::Connected, _) => {},
+        }
+    }
+
+    /// Resumes the session of a remote if `ticket` is the one we gave it, and tells it so.
+    ///
+    /// The remote may not have been suspended yet, if it is the only one that stopped receiving.
+    fn on_resume_request(&mut self, remote: &mut Remote<T::Addr>, ticket: &SessionTicket, from: &T::Addr, now: Instant) {
+        if !verify_session_ticket(&remote.session_ticket, ticket) {
+            debug!("remote {}: resume request with an invalid ticket from {:?}", remote.id, from);
+            return;
+        }
+        if let RemoteStatus::Suspended(_) = remote.status {
+            self.on_resumed(remote, now);
+        }
+        trace!("remote {}: sending ResumeAccept to {:?}", remote.id, from);
+        let udp_message = UdpMessage::control(self.pool.take(), PacketType::ResumeAccept);
+        if let Err(e) = self.send_udp_message_to(remote, udp_message, from, now) {
+            self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+        }
+    }
+
+    fn on_resumed(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        info!("remote {}: session resumed", remote.id);
+        remote.set_status(RemoteStatus::Connected);
+        self.events.push_back(SocketEvent::Resumed(remote.id));
+        self.send_queued_messages(remote, now);
+    }
+
+    /// Moves a remote to `from` if `token` answers the path challenge we sent there
+    fn on_path_response(&mut self, remote: &mut Remote<T::Addr>, token: &PathToken, from: &T::Addr) {
+        if !remote.path_validation.validate(from, token) {
+            debug!("remote {}: ignoring path response from {:?} that doesn't answer our challenge", remote.id, from);
+            return;
+        }
+        if self.remotes_by_addr.contains_key(from) {
+            // it is already our address for this remote, or the address of another remote
+            return;
+        }
+        let old_addr = ::std::mem::replace(&mut remote.remote_socket_addr, from.clone());
+        info!("remote {}: moved from {:?} to {:?}", remote.id, old_addr, from);
+        self.remotes_by_addr.remove(&old_addr);
+        self.remotes_by_addr.insert(from.clone(), remote.id);
+        self.stats.migrations += 1;
+        self.events.push_back(SocketEvent::Migrated(remote.id));
+    }
+
+    fn on_handshake(&mut self, remote: &mut Remote<T::Addr>, handshake: Handshake, now: Instant) {
+        let connecting = matches!(remote.status, RemoteStatus::NotStarted | RemoteStatus::Connecting(_));
+        match handshake {
+            Handshake::Request if connecting => {
+                // we both try to connect at the same time: it has to prove its address like anyone else
+                self.send_challenge(remote, now);
+            },
+            Handshake::Challenge(cookie) if connecting => {
+                remote.cookie = Some(cookie);
+                self.send_handshake(remote, now);
+            },
+            Handshake::Response { cookie, salt, connection_id, .. } if connecting => {
+                match self.verify_response(&remote.remote_socket_addr, &handshake, false, now) {
+                    Ok((token_data, key)) => {
+                        remote.token_data = token_data;
+                        remote.key = key;
+                        self.start_session(remote, salt, connection_id);
+                        self.send_accept(remote, now);
+                        self.on_connected(remote, now);
+                    },
+                    Err(Some(reason)) => {
+                        trace!("remote {}: sending ConnectReject", remote.id);
+                        let udp_message = UdpMessage::handshake(self.pool.take(), &Handshake::Reject { cookie, reason });
+                        self.send_handshake_message(remote, udp_message, now);
+                    },
+                    Err(None) => self.send_challenge(remote, now),
+                }
+            },
+            Handshake::Accept { salt, connection_id, session_ticket, mac } if connecting => {
+                if verify_handshake_mac(remote.key.as_ref(), ACCEPT_LABEL, &[&remote.salt, &salt, &connection_id, &session_ticket], &mac) {
+                    self.start_session(remote, salt, connection_id);
+                    remote.remote_session_ticket = Some(session_ticket);
+                    self.on_connected(remote, now);
+                } else {
+                    debug!("remote {}: connect accept without a valid proof of the key of the connection", remote.id);
+                    self.stats.unauthenticated_handshakes += 1;
+                }
+            },
+            Handshake::Reject { cookie, reason } if connecting => {
+                // anyone could send a reject, but only the server knows the cookie it gave us
+                if remote.cookie == Some(cookie) {
+                    info!("remote {}: connection rejected: {:?}", remote.id, reason);
+                    self.disconnect_remote(remote, Some(SocketEvent::ConnectRejected(remote.id, reason)));
+                }
+            },
+            Handshake::Response { cookie, salt, .. } => {
+                // our ConnectAccept was lost. Only a response with a valid cookie is answered: a
+                // ConnectRequest is smaller than a ConnectAccept, and anyone could send one for
+                // the address of a remote.
+                if self.cookies.verify(&cookie, &remote.remote_socket_addr, now) && salt == remote.remote_salt {
+                    self.send_accept(remote, now);
+                } else {
+                    debug!("remote {}: ignoring connect response with an invalid cookie", remote.id);
+                    self.stats.rejected_cookies += 1;
+                }
+            },
+            Handshake::Request | Handshake::Challenge(_) | Handshake::Accept { .. } | Handshake::Reject { .. } => {},
+        }
+    }
+
+    fn on_connected(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        info!("remote {}: connected", remote.id);
+        remote.set_status(RemoteStatus::Connected);
+        remote.cookie = None;
+        remote.connect_token.take();
+        self.events.push_back(SocketEvent::Connected(remote.id));
+        self.send_queued_messages(remote, now);
+    }
+
+    /// Handles a datagram from an address that is not one of our remotes.
+    ///
+    /// A ConnectRequest is answered with a cookie, and the remote is only created once the cookie
+    /// comes back: nothing is allocated for a client that may have spoofed its address.
+    fn receive_from_unknown(&mut self, udp_message: UdpMessage<Vec<u8>>, addr: T::Addr, now: Instant) {
+        let size = udp_message.as_bytes().len();
+        match udp_message.check(self.checksum).and_then(|udp_message| udp_message.into_packet(&mut self.pool)) {
+            Ok(Packet::Handshake(Handshake::Request)) => {
+                trace!("sending ConnectChallenge to unknown address {:?}", addr);
+                let cookie = self.cookies.generate(&addr, now);
+                self.send_to_unknown(&Handshake::Challenge(cookie), &addr, now);
+            },
+            Ok(Packet::Handshake(ref response @ Handshake::Response { cookie, salt, connection_id, .. })) => {
+                match self.verify_response(&addr, response, true, now) {
+                    Ok((token_data, key)) => {
+                        let remote_id = self.add_remote(addr, RemoteStatus::AckConnecting(now), key, now);
+                        let _r = self.with_remote(remote_id, |socket, remote| {
+                            remote.token_data = token_data;
+                            remote.accepted = true;
+                            socket.start_session(remote, salt, connection_id);
+                            socket.send_accept(remote, now);
+                        });
+                        self.events.push_back(SocketEvent::NewRemote(remote_id));
+                    },
+                    Err(Some(reason)) => {
+                        trace!("sending ConnectReject to unknown address {:?}", addr);
+                        self.send_to_unknown(&Handshake::Reject { cookie, reason }, &addr, now);
+                    },
+                    Err(None) => {
+                        let cookie = self.cookies.generate(&addr, now);
+                        self.send_to_unknown(&Handshake::Challenge(cookie), &addr, now);
+                    },
+                }
+            },
+            Ok(packet) => {
+                debug!("dropping {:?} packet of {} bytes from unknown address {:?}", packet.packet_type(), size, addr);
+                self.stats.unknown_packets += 1;
+            },
+            Err(e) => {
+                debug!("dropping invalid packet of {} bytes from unknown address {:?}: {:?}", size, addr, e);
+                self.stats.unknown_packets += 1;
+            },
+        }
     }
-}
\ No newline at end of file
+
+    /// Handles a datagram from an unknown address that holds the connection id of `remote`: the
+    /// remote may have moved there.
+    ///
+    /// The packet is handled like any packet from the remote, but the remote only moves once it
+    /// answered a path challenge sent to the new address. Until then, everything is still sent
+    /// to its previous address.
+    fn receive_from_new_address(&mut self, remote: &mut Remote<T::Addr>, udp_message: UdpMessage<Vec<u8>>, addr: T::Addr, now: Instant) {
+        match remote.push_udp_message(udp_message, self.checksum, now, &mut self.events, &mut self.pool) {
+            Ok((packet_type, packet)) => self.on_packet(remote, packet_type, packet, &addr, now),
+            Err(e) => {
+                self.stats.dropped_fragments.count(e);
+                return;
+            },
+        }
+        if remote.status == RemoteStatus::Disconnected || remote.remote_socket_addr == addr {
+            return;
+        }
+        let token = remote.path_validation.challenge(&addr, now);
+        if let Some(token) = token {
+            trace!("remote {}: sending PathChallenge to {:?}", remote.id, addr);
+            let udp_message = UdpMessage::path(self.pool.take(), PacketType::PathChallenge, &token);
+            if let Err(e) = self.send_udp_message_to(remote, udp_message, &addr, now) {
+                self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+            }
+        }
+    }
+
+    /// Reads every datagram waiting on the transport, as many at once as the transport allows
+    fn receive_pending(&mut self, now: Instant) {
+        let mut buffers = ::std::mem::take(&mut self.receive_buffers);
+        let mut addrs = ::std::mem::take(&mut self.receive_addrs);
+        let mut done = false;
+        while !done {
+            while buffers.len() < RECEIVE_BATCH_SIZE {
+                buffers.push(self.pool.take());
+            }
+            for buffer in &mut buffers {
+                buffer.resize(MAX_DATAGRAM_SIZE, 0);
+            }
+            match self.transport.recv_batch(&mut buffers, &mut addrs) {
+                Ok(received) => {
+                    for (buffer, addr) in buffers.drain(..received).zip(addrs.drain(..)) {
+                        self.receive_datagram(UdpMessage::new(buffer), addr, now);
+                    }
+                },
+                Err(e) => {
+                    // a failed batch may still have pushed some addresses
+                    addrs.clear();
+                    match e.kind() {
+                        ErrorKind::WouldBlock => {done = true},
+                        ErrorKind::Interrupted => {},
+                        kind if is_unreachable_error(kind) => {
+                            // one of our previous datagrams bounced, but we don't know which remote it was
+                            // for. The socket is still valid, so keep on receiving.
+                            debug!("a previous datagram was reported unreachable: {}", e);
+                            self.events.push_back(SocketEvent::ReceiveFailed(kind));
+                        },
+                        kind => {
+                            // don't loop forever on an error that may repeat itself, try again next iteration
+                            warn!("receiving udp message failed: {}", e);
+                            self.events.push_back(SocketEvent::ReceiveFailed(kind));
+                            done = true;
+                        }
+                    }
+                }
+            }
+        }
+        // the buffers that received nothing are kept for the next iteration
+        self.receive_buffers = buffers;
+        self.receive_addrs = addrs;
+    }
+
+    fn receive_datagram(&mut self, udp_message: UdpMessage<Vec<u8>>, addr: T::Addr, now: Instant) {
+        self.stats.packets_received += 1;
+        self.stats.bytes_received += udp_message.as_bytes().len() as u64;
+        let remote_id = self.remotes_by_addr.get(&addr).cloned().filter(|remote_id| self.remotes.get(*remote_id).is_some());
+        // a remote that moved is found by its connection id, and is limited like any remote
+        let moved_remote_id = match remote_id {
+            Some(_) => None,
+            None => udp_message.connection_id(self.checksum)
+                .and_then(|connection_id| self.remotes_by_connection_id.get(&connection_id))
+                .cloned()
+                .filter(|remote_id| self.remotes.get(*remote_id).is_some_and(|remote| remote.status != RemoteStatus::Disconnected)),
+        };
+        let moved_remote_addr = moved_remote_id.and_then(|remote_id| self.remotes.get(remote_id))
+            .map(|remote| remote.remote_socket_addr.clone());
+        let remote_addr = if remote_id.is_some() { Some(&addr) } else { moved_remote_addr.as_ref() };
+        if !self.accept_datagram(&addr, remote_addr, now) {
+            self.pool.give_back(udp_message.into_buffer());
+            return;
+        }
+        match (remote_id, moved_remote_id) {
+            (Some(remote_id), _) => {
+                // remote is valid, let's push the message into this remote
+                let _r = self.with_remote(remote_id, |socket, remote| {
+                    match remote.push_udp_message(udp_message, socket.checksum, now, &mut socket.events, &mut socket.pool) {
+                        Ok((packet_type, packet)) => socket.on_packet(remote, packet_type, packet, &addr, now),
+                        Err(e) => socket.stats.dropped_fragments.count(e),
+                    }
+                });
+            },
+            (None, Some(remote_id)) => {
+                let _r = self.with_remote(remote_id, |socket, remote| socket.receive_from_new_address(remote, udp_message, addr, now));
+            },
+            (None, None) => self.receive_from_unknown(udp_message, addr, now),
+        }
+    }
+
+    /// Checks the ban list and the rate limits, before any work is done on a received datagram.
+    ///
+    /// A datagram from a remote takes a token from the packet bucket of `remote_addr`, the
+    /// address the remote is known by, the others a token from the connection attempts of `addr`.
+    fn accept_datagram(&mut self, addr: &T::Addr, remote_addr: Option<&T::Addr>, now: Instant) -> bool {
+        if self.bans.is_banned(addr, T::ip_addr(addr), now) {
+            trace!("dropping packet from banned address {:?}", addr);
+            self.stats.banned_packets += 1;
+            false
+        } else if !self.rate_limiter.allow(remote_addr.unwrap_or(addr), remote_addr.is_some(), now) {
+            trace!("dropping packet from {:?}, over its rate limit", addr);
+            self.stats.rate_limited_packets += 1;
+            false
+        } else {
+            true
+        }
+    }
+
+    // TODO when impl Trait is done, replace VecDeque by impl Trait
+    /// Returns all received messages for a remote_id. The messages are in the order they *arrived*,
+    /// but it may be different from the order the messages were *sent* from remote.
+    ///
+    /// You *must* call `prepare_iteration` right before calling this function if you want to receive the messages
+    /// properly; otherwise incoming messages will be kept in the queue and you will have no way to have access
+    /// to the new messages.
+    pub fn receive_all_messages_from(&mut self, remote_id: RemoteID) -> Result<VecDeque<Payload>, SocketError> {
+        let remote = self.remote_mut(remote_id)?;
+        let messages = remote.extract_out_messages();
+        self.stats.messages_received += messages.len() as u64;
+        Ok(messages)
+    }
+
+    /// Returns all the messages received from all remotes during the previous iterations
+    pub fn received_messages(&mut self) -> Vec<(RemoteID, VecDeque<Payload>)> {
+        let messages: Vec<(RemoteID, VecDeque<Payload>)> = self.remotes
+            .iter_mut()
+            .map(|(remote_id, remote)| {
+                (remote_id, remote.extract_out_messages())
+            })
+            .collect();
+        self.stats.messages_received += messages.iter().map(|(_, m)| m.len() as u64).sum::<u64>();
+        messages
+    }
+
+    /// Returns all received messages from all remotes
+    ///
+    /// You don't have to call `prepare_iteration`, it is automatically being done here.
+    pub fn receive_all_messages(&mut self) -> Vec<(RemoteID, VecDeque<Payload>)> {
+        self.prepare_iteration();
+        self.received_messages()
+    }
+
+    /// Gives the buffer of a received message back to the socket, to receive another datagram in it.
+    ///
+    /// Messages that are not given back are simply dropped, but then a new buffer is allocated
+    /// for every message received.
+    pub fn recycle(&mut self, message: Payload) {
+        self.pool.give_back(message.into_buffer());
+    }
+
+    /// Returns the next event that happened inside the socket, if any.
+    pub fn next_event(&mut self) -> Option<SocketEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns a snapshot of the statistics of the whole socket
+    pub fn stats(&mut self) -> SocketStats {
+        SocketStats {
+            remotes: self.remotes.len(),
+            send_rate: self.send_rate.rate(self.now()),
+            .. self.stats
+        }
+    }
+
+    /// Returns a snapshot of the statistics of one remote
+    pub fn remote_stats(&mut self, remote_id: RemoteID) -> Result<RemoteStats, SocketError> {
+        let now = self.now();
+        let remote = self.remote_mut(remote_id)?;
+        Ok(remote.stats(now))
+    }
+
+    /// Sends one datagram to a remote and records it in the stats.
+    ///
+    /// Once the keys of the connection are known, everything but the handshake is encrypted.
+    fn send_udp_message(&mut self, remote: &mut Remote<T::Addr>, udp_message: UdpMessage<Vec<u8>>, now: Instant) -> Result<(), SocketError> {
+        let addr = remote.remote_socket_addr.clone();
+        self.send_udp_message_to(remote, udp_message, &addr, now)
+    }
+
+    /// Same as `send_udp_message`, to another address than the remote's, while it is moving.
+    ///
+    /// The message is sealed in its own buffer, which is given back to the pool once sent.
+    fn send_udp_message_to(&mut self, remote: &mut Remote<T::Addr>, mut udp_message: UdpMessage<Vec<u8>>, addr: &T::Addr, now: Instant) -> Result<(), SocketError> {
+        if let Err(e) = self.seal_udp_message(remote, &mut udp_message) {
+            self.pool.give_back(udp_message.into_buffer());
+            return Err(e);
+        }
+        let r = match self.transport.send_to(udp_message.as_bytes(), addr) {
+            Ok(sent_bytes) => {
+                remote.record_sent_packet(sent_bytes, now);
+                self.record_sent_packet(sent_bytes, now);
+                Ok(())
+            },
+            Err(e) => Err(send_error(remote.id, udp_message.as_bytes().len(), e)),
+        };
+        self.pool.give_back(udp_message.into_buffer());
+        r
+    }
+
+    /// Once the keys of the connection are known, encrypts everything but the handshake.
+    ///
+    /// Everything but the handshake is followed by the connection id of the remote, once it is known,
+    /// then by the checksum if it was not encrypted.
+    ///
+    /// Once the keys can't encrypt anything more, the remote is disconnected.
+    fn seal_udp_message(&mut self, remote: &mut Remote<T::Addr>, udp_message: &mut UdpMessage<Vec<u8>>) -> Result<(), SocketError> {
+        if udp_message.is_handshake() {
+            return Ok(());
+        }
+        if let Some(ref keys) = remote.session {
+            let seq = remote.next_packet_seq;
+            if seq == u32::MAX {
+                // a nonce can't be used twice with the same key
+                warn!("remote {}: every packet its keys can encrypt was sent, disconnecting", remote.id);
+                self.disconnect_remote(remote, Some(SocketEvent::KeysExhausted(remote.id)));
+                return Err(SocketError::KeysExhausted(remote.id));
+            }
+            remote.next_packet_seq = seq + 1;
+            udp_message.seal(keys, seq);
+        }
+        if let Some(ref connection_id) = remote.remote_connection_id {
+            udp_message.append_connection_id(connection_id);
+        }
+        if !udp_message.is_encrypted() {
+            udp_message.append_checksum(self.checksum);
+        }
+        Ok(())
+    }
+
+    /// Seals a message for a remote and keeps it to be sent with the others by `flush_outgoing`
+    fn push_outgoing(&mut self, remote: &mut Remote<T::Addr>, mut udp_message: UdpMessage<Vec<u8>>) -> Result<(), SocketError> {
+        match self.seal_udp_message(remote, &mut udp_message) {
+            Ok(()) => {
+                self.outgoing.push(udp_message.into_buffer());
+                Ok(())
+            },
+            Err(e) => {
+                self.pool.give_back(udp_message.into_buffer());
+                Err(e)
+            },
+        }
+    }
+
+    /// Sends every message kept by `push_outgoing` to a remote, in as few calls to the transport
+    /// as it allows, after the ones it had no room for before.
+    ///
+    /// If the transport would block once some of the new messages are sent, the others are kept
+    /// to be sent first next time. Otherwise if it fails, the new messages left are dropped and the
+    /// error is returned.
+    fn flush_outgoing(&mut self, remote: &mut Remote<T::Addr>, now: Instant) -> Result<(), SocketError> {
+        let addr = remote.remote_socket_addr.clone();
+        let mut outgoing = ::std::mem::take(&mut self.outgoing);
+        let unsent = remote.unsent.len();
+        if unsent > 0 {
+            outgoing.splice(0..0, remote.unsent.drain(..));
+        }
+        let mut sent = 0;
+        let mut r = Ok(());
+        while sent < outgoing.len() {
+            match self.transport.send_batch(&outgoing[sent..], &addr) {
+                Ok(count) if count > 0 => {
+                    for datagram in &outgoing[sent..sent + count] {
+                        remote.record_sent_packet(datagram.len(), now);
+                        self.record_sent_packet(datagram.len(), now);
+                    }
+                    sent += count;
+                },
+                Ok(_) => {
+                    r = Err(SocketError::IoError(ErrorKind::WriteZero.into()));
+                    break;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    // sending the rest of a message under a new seq_id would make it a new one
+                    if sent > unsent {
+                        trace!("remote {}: keeping {} datagrams until the transport has room", remote.id, outgoing.len() - sent);
+                        remote.unsent.extend(outgoing.drain(sent..));
+                    } else {
+                        remote.unsent.extend(outgoing.drain(sent..unsent));
+                        r = Err(SocketError::IoError(ErrorKind::WouldBlock.into()));
+                    }
+                    break;
+                },
+                Err(e) => {
+                    r = Err(send_error(remote.id, outgoing[sent].len(), e));
+                    break;
+                },
+            }
+        }
+        for buffer in outgoing.drain(..) {
+            self.pool.give_back(buffer);
+        }
+        self.outgoing = outgoing;
+        r
+    }
+
+    /// Sends a message to a remote.
+    ///
+    /// The returned token will be given back by a `SocketEvent::Acked` event once the remote
+    /// received the whole message, or by a `SocketEvent::Lost` event if it didn't in time.
+    ///
+    /// If the connection to the remote is not established yet, or is suspended, the message is
+    /// sent once it is established or resumed, after the messages with a higher priority.
+    ///
+    /// If the OS reports that the remote is unreachable, `SocketError::RemoteUnreachable` is returned,
+    /// other IO errors are returned as `SocketError::IoError`. In both cases the socket can still be used.
+    /// Errors are only returned if nothing was sent: when the transport has no room for the rest of
+    /// a message, it is sent during the next iterations, and when it fails midway, the error is
+    /// reported by a `SocketEvent::SendFailed` event and the message is still tracked like any other.
+    pub fn send_message(&mut self, remote_id: RemoteID, message: &[u8], t: MessageType, priority: i8) -> Result<MessageToken, SocketError> {
+        let token = MessageToken(self.next_token);
+        self.send_message_with_token(remote_id, message, t, priority, token)?;
+        self.next_token += 1;
+        Ok(token)
+    }
+
+    /// Same as `send_message`, but with a token chosen by the caller.
+    pub (crate) fn send_message_with_token(&mut self, remote_id: RemoteID, message: &[u8], t: MessageType, priority: i8, token: MessageToken) -> Result<(), SocketError> {
+        let now = self.now();
+        self.with_remote(remote_id, |socket, remote| socket.send_or_queue(remote, message, None, t, priority, token, now))?
+    }
+
+    /// Sends a message to a remote, or queues it until the connection is established or resumed.
+    ///
+    /// `shared` is the message already copied and fragmented, when it is sent to several remotes.
+    #[allow(clippy::too_many_arguments)]
+    fn send_or_queue(&mut self, remote: &mut Remote<T::Addr>, message: &[u8], shared: Option<&SharedMessage>, t: MessageType, priority: i8, token: MessageToken, now: Instant) -> Result<(), SocketError> {
+        match remote.status {
+            RemoteStatus::Disconnected => Err(SocketError::InvalidRemoteId(remote.id)),
+            RemoteStatus::NotStarted | RemoteStatus::Connecting(_) | RemoteStatus::Suspended(_) => {
+                // check the size now, it would be too late to report it once connected
+                build_fragments_from_data(&message, 0).map_err(|_| SocketError::MessageTooLarge(message.len()))?;
+                remote.send_queue.push(QueuedMessage {
+                    data: shared.map_or_else(|| Arc::from(message), |shared| shared.data.clone()),
+                    message_type: t,
+                    priority,
+                    token,
+                });
+                Ok(())
+            },
+            RemoteStatus::AckConnecting(_) | RemoteStatus::Connected => {
+                self.send_message_now(remote, message, shared, t, token, now)
+            },
+        }
+    }
+
+    /// Sends a message to every remote whose connection is established. Suspended remotes get
+    /// it once they are resumed.
+    ///
+    /// The message is copied and fragmented once, and shared by every remote that keeps it to send
+    /// it again.
+    /// The token is given back by one `SocketEvent::Acked` or `SocketEvent::Lost` event per remote.
+    /// Failing to send to a remote is reported by a `SocketEvent::SendFailed` event, the other
+    /// remotes still get the message.
+    pub fn broadcast_message(&mut self, message: &[u8], t: MessageType, priority: i8) -> Result<MessageToken, SocketError> {
+        let token = MessageToken(self.next_token);
+        self.broadcast_message_with_token(message, t, priority, token)?;
+        self.next_token += 1;
+        Ok(token)
+    }
+
+    /// Same as `broadcast_message`, but with a token chosen by the caller.
+    pub (crate) fn broadcast_message_with_token(&mut self, message: &[u8], t: MessageType, priority: i8, token: MessageToken) -> Result<(), SocketError> {
+        let remote_ids: Vec<RemoteID> = self.remotes.values()
+            .filter(|remote| matches!(remote.status, RemoteStatus::AckConnecting(_) | RemoteStatus::Connected | RemoteStatus::Suspended(_)))
+            .map(|remote| remote.id)
+            .collect();
+        self.send_shared_message(&remote_ids, message, t, priority, token)
+    }
+
+    /// Same as `broadcast_message`, to the remotes of a group. Like with `send_message`, the
+    /// members that are not connected yet get the message once they are.
+    ///
+    /// Nothing is sent if the group doesn't exist.
+    pub fn send_group_message(&mut self, group: &str, message: &[u8], t: MessageType, priority: i8) -> Result<MessageToken, SocketError> {
+        let token = MessageToken(self.next_token);
+        self.send_group_message_with_token(group, message, t, priority, token)?;
+        self.next_token += 1;
+        Ok(token)
+    }
+
+    /// Same as `send_group_message`, but with a token chosen by the caller.
+    pub (crate) fn send_group_message_with_token(&mut self, group: &str, message: &[u8], t: MessageType, priority: i8, token: MessageToken) -> Result<(), SocketError> {
+        let remote_ids = self.group_members(group);
+        self.send_shared_message(&remote_ids, message, t, priority, token)
+    }
+
+    fn send_shared_message(&mut self, remote_ids: &[RemoteID], message: &[u8], t: MessageType, priority: i8, token: MessageToken) -> Result<(), SocketError> {
+        let fragments = build_fragments_from_data(&message, 0).map_err(|_| SocketError::MessageTooLarge(message.len()))?;
+        let shared = SharedMessage {
+            data: Arc::from(message),
+            fragments: fragments.map(|fragment| UdpMessage::fragment(self.pool.take(), &fragment)).collect(),
+        };
+        let now = self.now();
+        for remote_id in remote_ids {
+            let _r = self.with_remote(*remote_id, |socket, remote| {
+                if remote.status == RemoteStatus::Disconnected {
+                    return;
+                }
+                if let Err(e) = socket.send_or_queue(remote, message, Some(&shared), t, priority, token, now) {
+                    socket.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+                    socket.events.push_back(SocketEvent::Lost(remote.id, token));
+                }
+            });
+        }
+        for udp_message in shared.fragments {
+            self.pool.give_back(udp_message.into_buffer());
+        }
+        Ok(())
+    }
+
+    /// Adds a remote to a group, which is created if it doesn't exist.
+    ///
+    /// A remote can be in any number of groups, and leaves them all once it is disconnected.
+    pub fn join_group(&mut self, group: &str, remote_id: RemoteID) -> Result<(), SocketError> {
+        let remote = self.remote(remote_id)?;
+        if remote.status == RemoteStatus::Disconnected {
+            return Err(SocketError::InvalidRemoteId(remote_id));
+        }
+        self.groups.entry(group.to_owned()).or_default().insert(remote_id);
+        Ok(())
+    }
+
+    /// Returns false if the remote was not in this group. A group is forgotten once it is empty.
+    pub fn leave_group(&mut self, group: &str, remote_id: RemoteID) -> bool {
+        let members = match self.groups.get_mut(group) {
+            Some(members) => members,
+            None => return false,
+        };
+        let removed = members.remove(&remote_id);
+        if members.is_empty() {
+            self.groups.remove(group);
+        }
+        removed
+    }
+
+    /// Returns the remotes in a group, in the order of their ids
+    pub fn group_members(&self, group: &str) -> Vec<RemoteID> {
+        self.groups.get(group).map(|members| members.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Sends the messages that were waiting for the connection, the ones with the highest priority first
+    fn send_queued_messages(&mut self, remote: &mut Remote<T::Addr>, now: Instant) {
+        let mut queue = ::std::mem::take(&mut remote.send_queue);
+        // the sort is stable, messages with the same priority keep their order
+        queue.sort_by_key(|m| ::std::cmp::Reverse(m.priority));
+        for message in queue {
+            let shared = SharedMessage { data: message.data, fragments: Vec::new() };
+            if let Err(e) = self.send_message_now(remote, &shared.data, Some(&shared), message.message_type, message.token, now) {
+                self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+                self.events.push_back(SocketEvent::Lost(remote.id, message.token));
+            }
+        }
+    }
+
+    /// `shared` is `message` already copied, and maybe fragmented, if it is
+    fn send_message_now(&mut self, remote: &mut Remote<T::Addr>, message: &[u8], shared: Option<&SharedMessage>, t: MessageType, token: MessageToken, now: Instant) -> Result<(), SocketError> {
+        let seq_id = remote.next_seq_id;
+        let fragments = build_fragments_from_data(&message, seq_id).map_err(|_| SocketError::MessageTooLarge(message.len()))?;
+        let serialized = shared.map_or(&[][..], |shared| &shared.fragments[..]);
+        let udp_messages: Vec<UdpMessage<Vec<u8>>> = if serialized.is_empty() {
+            fragments.map(|fragment| UdpMessage::fragment(self.pool.take(), &fragment)).collect()
+        } else {
+            serialized.iter().map(|fragment| UdpMessage::fragment_with_seq_id(self.pool.take(), fragment, seq_id)).collect()
+        };
+        let frag_total = (udp_messages.len() - 1) as u8;
+        let mut udp_messages = udp_messages.into_iter();
+        while let Some(udp_message) = udp_messages.next() {
+            if let Err(e) = self.push_outgoing(remote, udp_message) {
+                for udp_message in udp_messages {
+                    self.pool.give_back(udp_message.into_buffer());
+                }
+                for buffer in self.outgoing.drain(..) {
+                    self.pool.give_back(buffer);
+                }
+                return Err(e);
+            }
+        }
+        // only key messages are kept to be sent again
+        let kept = || shared.map_or_else(|| Arc::from(message), |shared| shared.data.clone());
+        let (data, expires_at) = match t {
+            MessageType::KeyMessage => (Some(kept()), None),
+            MessageType::KeyExpirableMessage(expiration_ms) if expiration_ms > 0 => {
+                (Some(kept()), Some(now + Duration::from_millis(expiration_ms as u64)))
+            },
+            _ => (None, None),
+        };
+        // tracked before it is sent: if the transport fails midway, the fragments it didn't send
+        // are sent again, or the message is reported lost, like any other
+        remote.next_seq_id = seq_id.wrapping_add(1);
+        remote.sent_messages.insert(seq_id, token, frag_total, now, data, expires_at);
+        let unsent = remote.unsent.len() as u64;
+        let packets_sent = remote.stats.packets_sent;
+        if let Err(e) = self.flush_outgoing(remote, now) {
+            if remote.stats.packets_sent - packets_sent <= unsent {
+                // none of its fragments were sent, as if it never was
+                remote.sent_messages.remove(seq_id);
+                remote.next_seq_id = seq_id;
+                return Err(e);
+            }
+            self.events.push_back(SocketEvent::SendFailed(remote.id, e.kind()));
+        }
+        remote.stats.messages_sent += 1;
+        self.stats.messages_sent += 1;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn send_key_message(&mut self, remote_id: RemoteID, message: &[u8], priority: i8) -> Result<MessageToken, SocketError> {
+        self.send_message(remote_id, message, MessageType::KeyMessage, priority)
+    }
+    
+    #[inline]
+    pub fn send_key_expirable_message(&mut self, remote_id: RemoteID, message: &[u8], expiration_ms: u32, priority: i8) -> Result<MessageToken, SocketError> {
+        self.send_message(remote_id, message, MessageType::KeyExpirableMessage(expiration_ms), priority)
+    }
+
+    #[inline]
+    pub fn send_forgettable_message(&mut self, remote_id: RemoteID, message: &[u8], priority: i8) -> Result<MessageToken, SocketError> {
+        self.send_message(remote_id, message, MessageType::Forgettable, priority)
+    }
+
+    #[inline]
+    pub fn send_droppable_message(&mut self, remote_id: RemoteID, message: &[u8], priority: i8) -> Result<MessageToken, SocketError> {
+        self.send_message(remote_id, message, MessageType::Droppable, priority)
+    }
+}
+#[test]
+fn socket_ignores_unknown_sender() {
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket_addr = udp_socket.local_addr().unwrap();
+    let mut socket = Socket::new(udp_socket);
+    let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+    stranger.send_to(&[0u8; 20], socket_addr).unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(10));
+    socket.prepare_iteration();
+    assert!(socket.next_event().is_none());
+}
+
+/// Connects socket1 to socket2, and returns the id of socket2 in socket1 and the id of socket1 in socket2
+#[cfg(test)]
+fn connect_sockets<T: DatagramTransport, U: DatagramTransport<Addr = T::Addr>>(socket1: &mut Socket<T>, socket2: &mut Socket<U>) -> (RemoteID, RemoteID) {
+    connect_sockets_with(socket1, socket2, || {})
+}
+
+/// Same as connect_sockets, but leaves time for each packet to go through the loopback interface
+#[cfg(all(test, feature = "gso", target_os = "linux"))]
+fn connect_udp_sockets<T: DatagramTransport, U: DatagramTransport<Addr = T::Addr>>(socket1: &mut Socket<T>, socket2: &mut Socket<U>) -> (RemoteID, RemoteID) {
+    connect_sockets_with(socket1, socket2, || ::std::thread::sleep(::std::time::Duration::from_millis(10)))
+}
+
+#[cfg(test)]
+fn connect_sockets_with<T, U, F>(socket1: &mut Socket<T>, socket2: &mut Socket<U>, wait: F) -> (RemoteID, RemoteID)
+where T: DatagramTransport, U: DatagramTransport<Addr = T::Addr>, F: Fn() {
+    let remote1 = socket1.connect_to(socket2.local_addr().unwrap());
+    // ConnectRequest, ConnectChallenge, ConnectResponse and ConnectAccept
+    wait();
+    socket2.prepare_iteration();
+    wait();
+    socket1.prepare_iteration();
+    wait();
+    socket2.prepare_iteration();
+    let remote2 = match socket2.next_event() {
+        Some(SocketEvent::NewRemote(remote2)) => remote2,
+        e => panic!("expected a NewRemote event, got {:?}", e),
+    };
+    wait();
+    socket1.prepare_iteration();
+    assert_eq!(socket1.next_event(), Some(SocketEvent::Connected(remote1)));
+    (remote1, remote2)
+}
+
+/// Several ChannelTransports behind one Socket, like a udp socket talking to several peers
+#[cfg(test)]
+#[derive(Debug)]
+struct ChannelHub(Vec<::transport::ChannelTransport>);
+
+#[cfg(test)]
+impl DatagramTransport for ChannelHub {
+    type Addr = ::transport::ChannelAddr;
+    fn send_to(&self, buf: &[u8], addr: &Self::Addr) -> ::std::io::Result<usize> {
+        match self.0.iter().find(|transport| transport.peer_addr() == *addr) {
+            Some(transport) => transport.send_to(buf, addr),
+            None => Err(ErrorKind::ConnectionRefused.into()),
+        }
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> ::std::io::Result<(usize, Self::Addr)> {
+        for transport in &self.0 {
+            match transport.recv_from(buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                r => return r,
+            }
+        }
+        Err(ErrorKind::WouldBlock.into())
+    }
+    /// The address of the first peer's end
+    fn local_addr(&self) -> ::std::io::Result<Self::Addr> { self.0[0].local_addr() }
+    fn set_nonblocking(&self) -> ::std::io::Result<()> { Ok(()) }
+}
+
+/// A ChannelTransport that can run out of room, fail, or hide its peer behind a NAT
+#[cfg(test)]
+#[derive(Debug)]
+struct TestTransport {
+    inner: ::transport::ChannelTransport,
+    /// How many more datagrams can be sent before failing with `error`, or `None` for no limit
+    send_budget: Cell<Option<usize>>,
+    error: ErrorKind,
+    /// Fails the next batch received, after pushing the address of a datagram it did not receive
+    fail_next_batch: Cell<bool>,
+    /// Where every datagram seems to come from, as if the peer was behind a NAT
+    received_from: Option<::transport::ChannelAddr>,
+    sent_batches: Cell<usize>,
+    received_batches: Cell<usize>,
+}
+
+#[cfg(test)]
+impl TestTransport {
+    fn new(inner: ::transport::ChannelTransport) -> TestTransport {
+        TestTransport {
+            inner,
+            send_budget: Cell::new(None),
+            error: ErrorKind::WouldBlock,
+            fail_next_batch: Cell::new(false),
+            received_from: None,
+            sent_batches: Cell::new(0),
+            received_batches: Cell::new(0),
+        }
+    }
+}
+
+#[cfg(test)]
+impl DatagramTransport for TestTransport {
+    type Addr = ::transport::ChannelAddr;
+    /// Everything goes to the other end of the channel, whatever address the peer is known by
+    fn send_to(&self, buf: &[u8], _addr: &Self::Addr) -> ::std::io::Result<usize> {
+        match self.send_budget.get() {
+            Some(0) => Err(self.error.into()),
+            budget => {
+                self.send_budget.set(budget.map(|budget| budget - 1));
+                self.inner.send_to(buf, &self.inner.peer_addr())
+            },
+        }
+    }
+    fn recv_from(&self, buf: &mut [u8]) -> ::std::io::Result<(usize, Self::Addr)> {
+        self.inner.recv_from(buf).map(|(size, addr)| (size, self.received_from.unwrap_or(addr)))
+    }
+    fn local_addr(&self) -> ::std::io::Result<Self::Addr> { self.inner.local_addr() }
+    fn set_nonblocking(&self) -> ::std::io::Result<()> { Ok(()) }
+    fn recv_batch(&self, buffers: &mut [Vec<u8>], addrs: &mut Vec<Self::Addr>) -> ::std::io::Result<usize> {
+        self.received_batches.set(self.received_batches.get() + 1);
+        if self.fail_next_batch.replace(false) {
+            addrs.push(self.inner.peer_addr());
+            return Err(ErrorKind::Other.into());
+        }
+        for (received, buffer) in buffers.iter_mut().enumerate() {
+            match self.recv_from(buffer.as_mut_slice()) {
+                Ok((size, addr)) => {
+                    buffer.truncate(size);
+                    addrs.push(addr);
+                },
+                Err(e) if received == 0 => return Err(e),
+                Err(_) => return Ok(received),
+            }
+        }
+        Ok(buffers.len())
+    }
+    fn send_batch<B: AsRef<[u8]>>(&self, datagrams: &[B], addr: &Self::Addr) -> ::std::io::Result<usize> {
+        self.sent_batches.set(self.sent_batches.get() + 1);
+        for (sent, datagram) in datagrams.iter().enumerate() {
+            match self.send_to(datagram.as_ref(), addr) {
+                Ok(_) => {},
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => return Ok(sent),
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+#[test]
+fn socket_stats() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(transport1, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    // the handshake is already counted
+    let sender_stats_before = socket1.remote_stats(remote1).unwrap();
+    let receiver_stats_before = socket2.remote_stats(remote2).unwrap();
+    socket1.send_forgettable_message(remote1, &[1u8; 2000], 0).unwrap();
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    let messages = socket2.received_messages();
+    assert_eq!(messages[0].1.len(), 1);
+
+    let sender_stats = socket1.remote_stats(remote1).unwrap();
+    assert_eq!(sender_stats.messages_sent, 1);
+    assert_eq!(sender_stats.packets_sent - sender_stats_before.packets_sent, 2);
+    let bytes_sent = sender_stats.bytes_sent - sender_stats_before.bytes_sent;
+    assert_eq!(bytes_sent, 2000 + 2 * (PACKET_TYPE_SIZE + FRAG_HEADER_SIZE + CONNECTION_ID_SIZE + Checksum::Crc32.size()) as u64);
+    let receiver_stats = socket2.remote_stats(remote2).unwrap();
+    assert_eq!(receiver_stats.packets_received - receiver_stats_before.packets_received, 2);
+    assert_eq!(receiver_stats.bytes_received - receiver_stats_before.bytes_received, bytes_sent);
+    assert_eq!(receiver_stats.messages_received, 1);
+    assert_eq!(receiver_stats.dropped_fragments.total(), 0);
+    let socket_stats = socket2.stats();
+    assert_eq!(socket_stats.remotes, 1);
+    assert_eq!(socket_stats.messages_received, 1);
+}
+
+#[test]
+fn socket_message_acked() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(transport1, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, _) = connect_sockets(&mut socket1, &mut socket2);
+    let token = socket1.send_key_message(remote1, &[1u8; 3000], 0).unwrap();
+    // receives the fragments and sends the acks back
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    clock.advance(Duration::from_millis(20));
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Acked(remote1, token)]);
+    assert_eq!(socket1.remote_stats(remote1).unwrap().rtt, Some(Duration::from_millis(20)));
+}
+
+#[test]
+fn socket_broadcast_and_group_messages() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, client_transport1) = ChannelTransport::pair();
+    let (transport2, client_transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut server = Socket::with_clock(ChannelHub(vec![transport1, transport2]), Box::new(clock.clone()));
+    let mut client1 = Socket::with_clock(client_transport1, Box::new(clock.clone()));
+    let mut client2 = Socket::with_clock(client_transport2, Box::new(clock.clone()));
+    let (remote1, from_server1) = connect_sockets(&mut server, &mut client1);
+    let (remote2, from_server2) = connect_sockets(&mut server, &mut client2);
+
+    // the fragments are built once, and sent to each remote under its own seq_id
+    server.send_forgettable_message(remote1, &[0u8], 0).unwrap();
+    let token = server.broadcast_message(&[1u8; 3000], MessageType::KeyMessage, 0).unwrap();
+    assert_eq!(client1.update(clock.now()).next(), None);
+    assert_eq!(client2.update(clock.now()).next(), None);
+    assert_eq!(client1.received_messages(), vec![(from_server1, vec![Payload::from(vec![0u8]), Payload::from(vec![1u8; 3000])].into())]);
+    assert_eq!(client2.received_messages(), vec![(from_server2, vec![Payload::from(vec![1u8; 3000])].into())]);
+    // each remote acks its own copy
+    let acked: Vec<SocketEvent> = server.update(clock.now()).collect();
+    assert!(acked.contains(&SocketEvent::Acked(remote1, token)));
+    assert!(acked.contains(&SocketEvent::Acked(remote2, token)));
+
+    server.join_group("room", remote2).unwrap();
+    assert_eq!(server.group_members("room"), vec![remote2]);
+    server.send_group_message("room", &[2u8; 10], MessageType::Forgettable, 0).unwrap();
+    assert_eq!(client1.update(clock.now()).next(), None);
+    assert_eq!(client2.update(clock.now()).next(), None);
+    assert_eq!(client1.received_messages(), vec![(from_server1, VecDeque::new())]);
+    assert_eq!(client2.received_messages(), vec![(from_server2, vec![Payload::from(vec![2u8; 10])].into())]);
+    assert!(server.leave_group("room", remote2));
+    assert!(server.group_members("room").is_empty());
+
+    assert!(matches!(server.broadcast_message(&vec![0u8; MAX_UDP_MESSAGE_SIZE * MAX_FRAGMENTS_IN_MESSAGE], MessageType::Forgettable, 0), Err(SocketError::MessageTooLarge(_))));
+    server.disconnect(remote1).unwrap();
+    assert!(server.join_group("room", remote1).is_err());
+}
+
+#[test]
+fn socket_over_channel_transport() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    let token = socket1.send_key_message(remote1, &[1u8; 3000], 0).unwrap();
+    let messages = socket2.receive_all_messages();
+    assert_eq!(messages, vec![(remote2, vec![Payload::from(vec![1u8; 3000])].into())]);
+    socket1.prepare_iteration();
+    assert_eq!(socket1.next_event(), Some(SocketEvent::Acked(remote1, token)));
+}
+
+#[test]
+fn socket_moves_between_threads() {
+    use transport::ChannelTransport;
+    fn assert_send<S: Send>(socket: S) -> S {
+        socket
+    }
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = assert_send(Socket::new(transport2));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.send_forgettable_message(remote1, &[1u8, 2, 3], 0).unwrap();
+    let mut socket2 = ::std::thread::spawn(move || {
+        let messages = socket2.receive_all_messages();
+        assert_eq!(messages, vec![(remote2, vec![Payload::from(vec![1u8, 2, 3])].into())]);
+        socket2
+    }).join().unwrap();
+    socket2.send_forgettable_message(remote2, &[4u8], 0).unwrap();
+    assert_eq!(socket1.receive_all_messages(), vec![(remote1, vec![Payload::from(vec![4u8])].into())]);
+}
+
+#[test]
+fn socket_recycles_buffers() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, _) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.send_forgettable_message(remote1, &[1u8, 2, 3], 0).unwrap();
+    let message = socket2.receive_all_messages().pop().unwrap().1.pop_front().unwrap();
+    assert_eq!(&*message, &[1u8, 2, 3]);
+    // still in the buffer of its datagram, which can receive another one
+    let pooled = socket2.pool.len();
+    socket2.recycle(message);
+    assert_eq!(socket2.pool.len(), pooled + 1);
+    // buffers that never held a datagram are not kept
+    socket2.recycle(Payload::from(vec![1u8, 2, 3]));
+    assert_eq!(socket2.pool.len(), pooled + 1);
+}
+
+#[test]
+fn socket_sends_and_receives_batches() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(TestTransport::new(transport1));
+    let mut socket2 = Socket::new(TestTransport::new(transport2));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    let sends = socket1.transport_mut().sent_batches.get();
+    // 3 fragments in one batch
+    socket1.send_forgettable_message(remote1, &[1u8; 3000], 0).unwrap();
+    assert_eq!(socket1.transport_mut().sent_batches.get(), sends + 1);
+    // received in one batch, the next one finds nothing
+    let receives = socket2.transport_mut().received_batches.get();
+    let messages = socket2.receive_all_messages();
+    assert_eq!(messages, vec![(remote2, vec![Payload::from(vec![1u8; 3000])].into())]);
+    assert_eq!(socket2.transport_mut().received_batches.get(), receives + 2);
+}
+
+#[test]
+fn socket_sends_the_rest_of_a_message_when_the_transport_has_room() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(TestTransport::new(transport1));
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.transport_mut().send_budget.set(Some(0));
+    match socket1.send_forgettable_message(remote1, &[1u8; 3000], 0) {
+        Err(SocketError::IoError(ref e)) if e.kind() == ErrorKind::WouldBlock => {},
+        r => panic!("expected WouldBlock, got {:?}", r),
+    }
+    // 1 of the 3 fragments is sent, the others wait for the transport
+    socket1.transport_mut().send_budget.set(Some(1));
+    socket1.send_forgettable_message(remote1, &[2u8; 3000], 0).unwrap();
+    assert!(socket2.receive_all_messages()[0].1.is_empty());
+    socket1.transport_mut().send_budget.set(None);
+    socket1.prepare_iteration();
+    let messages = socket2.receive_all_messages();
+    assert_eq!(messages, vec![(remote2, vec![Payload::from(vec![2u8; 3000])].into())]);
+}
+
+#[test]
+fn socket_sends_messages_across_the_seq_id_wraparound() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(transport1, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.with_remote(remote1, |_, remote| remote.next_seq_id = u32::MAX - 1).unwrap();
+    let tokens: Vec<MessageToken> = (0..4u8).map(|i| socket1.send_key_message(remote1, &[i; 2000], 0).unwrap()).collect();
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    let expected: VecDeque<Payload> = (0..4u8).map(|i| Payload::from(vec![i; 2000])).collect();
+    assert_eq!(socket2.received_messages(), vec![(remote2, expected)]);
+    let acked: Vec<SocketEvent> = socket1.update(clock.now()).collect();
+    assert_eq!(acked, tokens.into_iter().map(|token| SocketEvent::Acked(remote1, token)).collect::<Vec<_>>());
+}
+
+#[test]
+fn socket_tracks_messages_the_transport_failed_to_send_entirely() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(TestTransport { error: ErrorKind::Other, ..TestTransport::new(transport1) }, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    // 1 of the 3 fragments is sent
+    socket1.transport_mut().send_budget.set(Some(1));
+    let token = socket1.send_key_message(remote1, &[1u8; 3000], 0).unwrap();
+    assert_eq!(socket1.next_event(), Some(SocketEvent::SendFailed(remote1, SocketErrorKind::Io(ErrorKind::Other))));
+    socket1.transport_mut().send_budget.set(None);
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert!(socket2.received_messages()[0].1.is_empty());
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    // the others are sent again like the fragments of any message that wasn't acknowledged
+    clock.advance(DEFAULT_LOST_TIMEOUT);
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    assert_eq!(socket1.remote_stats(remote1).unwrap().retransmissions, 2);
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.received_messages(), vec![(remote2, vec![Payload::from(vec![1u8; 3000])].into())]);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Acked(remote1, token)]);
+
+    // nothing is sent: the message is forgotten, and the next one takes its seq_id
+    socket1.transport_mut().send_budget.set(Some(0));
+    assert!(socket1.send_key_message(remote1, &[2u8; 10], 0).is_err());
+    socket1.transport_mut().send_budget.set(None);
+    let token = socket1.send_forgettable_message(remote1, &[3u8; 10], 0).unwrap();
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.received_messages(), vec![(remote2, vec![Payload::from(vec![3u8; 10])].into())]);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Acked(remote1, token)]);
+    clock.advance(DEFAULT_LOST_TIMEOUT);
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    assert_eq!(socket1.remote_stats(remote1).unwrap().retransmissions, 2);
+}
+
+#[test]
+fn socket_forgets_the_addresses_of_failed_batches() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(TestTransport::new(transport2));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket2.transport_mut().fail_next_batch.set(true);
+    socket2.prepare_iteration();
+    assert_eq!(socket2.next_event(), Some(SocketEvent::ReceiveFailed(ErrorKind::Other)));
+    // the next datagram is not taken for one from the failed batch, whose remote would have moved
+    socket1.send_forgettable_message(remote1, &[1, 2, 3], 0).unwrap();
+    let messages = socket2.receive_all_messages();
+    assert_eq!(messages, vec![(remote2, vec![Payload::from(vec![1u8, 2, 3])].into())]);
+    assert_eq!(socket2.next_event(), None);
+}
+
+#[cfg(all(feature = "gso", target_os = "linux"))]
+#[test]
+fn socket_over_offload_udp_socket() {
+    use offload::OffloadUdpSocket;
+    let mut socket1 = Socket::new(OffloadUdpSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap());
+    let mut socket2 = Socket::new(OffloadUdpSocket::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap());
+    let (remote1, remote2) = connect_udp_sockets(&mut socket1, &mut socket2);
+    // 3 fragments sent in one segmented buffer, and split back when received
+    let token = socket1.send_key_message(remote1, &[1u8; 3000], 0).unwrap();
+    ::std::thread::sleep(::std::time::Duration::from_millis(10));
+    let messages = socket2.receive_all_messages();
+    assert_eq!(messages, vec![(remote2, vec![Payload::from(vec![1u8; 3000])].into())]);
+    ::std::thread::sleep(::std::time::Duration::from_millis(10));
+    socket1.prepare_iteration();
+    assert_eq!(socket1.next_event(), Some(SocketEvent::Acked(remote1, token)));
+}
+
+#[test]
+fn socket_drops_corrupt_packets() {
+    use transport::ChannelTransport;
+    use conditioner::{LinkConditioner, LinkConditions};
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(LinkConditioner::new(transport1, Default::default(), 42));
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.transport_mut().set_conditions(LinkConditions { corruption: 1.0, .. Default::default() });
+    socket1.send_forgettable_message(remote1, &[1u8; 100], 0).unwrap();
+    assert!(socket2.receive_all_messages()[0].1.is_empty());
+    assert_eq!(socket2.remote_stats(remote2).unwrap().dropped_fragments.invalid_crc, 1);
+}
+
+#[test]
+fn socket_message_lost_with_mock_clock() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(transport1, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, _) = connect_sockets(&mut socket1, &mut socket2);
+    // socket2 isn't updated anymore, the message is never acknowledged
+    let token = socket1.send_forgettable_message(remote1, &[1u8; 10], 0).unwrap();
+    clock.advance(DEFAULT_LOST_TIMEOUT - Duration::from_millis(1));
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Lost(remote1, token)]);
+}
+
+#[test]
+fn socket_driven_with_fixed_timestep() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    // far from the time of the clocks, which must not matter
+    let mut now = Instant::now() + Duration::from_secs(3600);
+    socket1.update(now);
+    socket2.update(now);
+    let remote1 = socket1.connect_to(socket2.local_addr().unwrap());
+    for _ in 0..2 {
+        now += Duration::from_millis(10);
+        socket2.update(now);
+        socket1.update(now);
+    }
+    assert_eq!(socket1.remote_status(remote1).unwrap(), RemoteStatus::Connected);
+    // socket2 isn't updated anymore, the message is never acknowledged
+    let token = socket1.send_forgettable_message(remote1, &[1u8; 10], 0).unwrap();
+    assert_eq!(socket1.update(now + DEFAULT_LOST_TIMEOUT - Duration::from_millis(1)).next(), None);
+    assert_eq!(socket1.update(now + DEFAULT_LOST_TIMEOUT).collect::<Vec<_>>(), vec![SocketEvent::Lost(remote1, token)]);
+}
+
+#[test]
+fn socket_retransmits_key_messages() {
+    use transport::ChannelTransport;
+    use conditioner::{LinkConditioner, LinkConditions};
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(LinkConditioner::new(transport1, Default::default(), 42), Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.transport_mut().set_conditions(LinkConditions { loss: 1.0, .. Default::default() });
+    let token = socket1.send_key_message(remote1, &[1u8; 3000], 0).unwrap();
+    socket1.transport_mut().set_conditions(Default::default());
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert!(socket2.received_messages()[0].1.is_empty());
+
+    clock.advance(DEFAULT_LOST_TIMEOUT);
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    assert_eq!(socket1.remote_stats(remote1).unwrap().retransmissions, 3);
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.received_messages(), vec![(remote2, vec![Payload::from(vec![1u8; 3000])].into())]);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Acked(remote1, token)]);
+}
+
+#[test]
+fn socket_connect_timeout() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket = Socket::with_clock(transport1, Box::new(clock.clone()));
+    // nobody reads transport2, the connection is never accepted
+    let remote_id = socket.connect_to(transport2.local_addr().unwrap());
+    let token = socket.send_key_message(remote_id, &[1u8; 10], 0).unwrap();
+    clock.advance(CONNECT_RETRY_INTERVAL);
+    assert_eq!(socket.update(clock.now()).next(), None);
+    assert_eq!(socket.remote_stats(remote_id).unwrap().packets_sent, 2);
+    clock.advance(CONNECT_TIMEOUT);
+    assert_eq!(socket.update(clock.now()).collect::<Vec<_>>(), vec![
+        SocketEvent::Lost(remote_id, token),
+        SocketEvent::ConnectFailed(remote_id),
+    ]);
+    assert_eq!(socket.remote_status(remote_id).unwrap(), RemoteStatus::Disconnected);
+    socket.update(clock.now());
+    assert!(socket.remote_status(remote_id).is_err());
+}
+
+#[test]
+fn socket_heartbeat_and_timeout() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(transport1, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(transport2, Box::new(clock.clone()));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    let packets_sent = socket1.remote_stats(remote1).unwrap().packets_sent;
+    clock.advance(HEARTBEAT_INTERVAL);
+    socket1.update(clock.now());
+    assert_eq!(socket1.remote_stats(remote1).unwrap().packets_sent, packets_sent + 1);
+    // socket2 hasn't been updated, so socket1 never received its heartbeats
+    clock.advance(REMOTE_TIMEOUT - HEARTBEAT_INTERVAL);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::TimedOut(remote1)]);
+    // but the heartbeat of socket1 kept it alive in socket2
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.remote_status(remote2).unwrap(), RemoteStatus::Connected);
+}
+
+#[test]
+fn socket_resumes_suspended_sessions() {
+    use transport::ChannelTransport;
+    use conditioner::{LinkConditioner, LinkConditions};
+    use clock::MockClock;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(LinkConditioner::new(transport1, Default::default(), 42), Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(LinkConditioner::new(transport2, Default::default(), 43), Box::new(clock.clone()));
+    let grace = Duration::from_secs(30);
+    socket1.set_resumption_grace(Some(grace));
+    socket2.set_resumption_grace(Some(grace));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    let lost_link = LinkConditions { loss: 1.0, .. Default::default() };
+    socket1.transport_mut().set_conditions(lost_link);
+    socket2.transport_mut().set_conditions(lost_link);
+    let token1 = socket1.send_key_message(remote1, &[1, 2, 3], 0).unwrap();
+
+    clock.advance(REMOTE_TIMEOUT);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Suspended(remote1)]);
+    assert_eq!(socket2.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Suspended(remote2)]);
+    assert_eq!(socket1.remote_status(remote1).unwrap(), RemoteStatus::Suspended(clock.now()));
+    // sent once the session is resumed
+    let token2 = socket1.send_key_message(remote1, &[4], 0).unwrap();
+
+    socket1.transport_mut().set_conditions(Default::default());
+    socket2.transport_mut().set_conditions(Default::default());
+    clock.advance(CONNECT_RETRY_INTERVAL);
+    // socket1 sends its ticket back, socket2 accepts it
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    assert_eq!(socket2.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Resumed(remote2)]);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Resumed(remote1)]);
+    // the message sent before the suspension keeps its seq_id, and is sent again
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.received_messages(), vec![(remote2, vec![Payload::from(&[4u8][..]), Payload::from(&[1u8, 2, 3][..])].into())]);
+    assert_eq!(socket1.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Acked(remote1, token2), SocketEvent::Acked(remote1, token1)]);
+
+    // past the grace period, the remote times out
+    socket2.transport_mut().set_conditions(lost_link);
+    clock.advance(REMOTE_TIMEOUT);
+    assert_eq!(socket2.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::Suspended(remote2)]);
+    clock.advance(grace);
+    assert_eq!(socket2.update(clock.now()).collect::<Vec<_>>(), vec![SocketEvent::TimedOut(remote2)]);
+}
+
+#[test]
+fn socket_disconnect() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.disconnect(remote1).unwrap();
+    assert!(socket1.send_forgettable_message(remote1, &[1], 0).is_err());
+    socket2.prepare_iteration();
+    assert_eq!(socket2.next_event(), Some(SocketEvent::Disconnected(remote2)));
+}
+
+#[test]
+fn socket_challenges_connect_requests() {
+    use transport::ChannelTransport;
+    use cookie::COOKIE_SIZE;
+    let (client, transport) = ChannelTransport::pair();
+    let server_addr = client.peer_addr();
+    let mut socket = Socket::new(transport);
+    let mut buffer = [0u8; MAX_UDP_MESSAGE_SIZE];
+
+    let request = UdpMessage::from(&Handshake::Request);
+    client.send_to(request.as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    // nothing is allocated until the cookie comes back
+    assert_eq!(socket.next_event(), None);
+    assert_eq!(socket.stats().remotes, 0);
+    let (size, _) = client.recv_from(&mut buffer).unwrap();
+    assert!(size <= request.as_bytes().len());
+    let cookie = match UdpMessage::new(&buffer[..size]).into_packet(Checksum::Crc32).unwrap() {
+        Packet::Handshake(Handshake::Challenge(cookie)) => cookie,
+        p => panic!("expected a challenge, got {:?}", p),
+    };
+
+    let response = |cookie| UdpMessage::from(&Handshake::Response { cookie, salt: Default::default(), connection_id: Default::default(), checksum: Checksum::Crc32, mac: Default::default(), token: None });
+    client.send_to(response([0u8; COOKIE_SIZE]).as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    assert_eq!(socket.next_event(), None);
+    assert_eq!(socket.stats().remotes, 0);
+    assert_eq!(socket.stats().rejected_cookies, 1);
+    // a new challenge is sent in case the cookie was only too old
+    assert!(client.recv_from(&mut buffer).is_ok());
+
+    client.send_to(response(cookie).as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    assert_eq!(socket.next_event(), Some(SocketEvent::NewRemote(RemoteID::new(0, 0))));
+}
+
+#[test]
+fn socket_never_answers_connected_remotes_with_more_bytes() {
+    use transport::ChannelTransport;
+    let (client, transport) = ChannelTransport::pair();
+    let server_addr = client.peer_addr();
+    let mut socket = Socket::new(transport);
+    let mut buffer = [0u8; MAX_UDP_MESSAGE_SIZE];
+
+    let request = UdpMessage::from(&Handshake::Request);
+    client.send_to(request.as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    let (size, _) = client.recv_from(&mut buffer).unwrap();
+    let cookie = match UdpMessage::new(&buffer[..size]).into_packet(Checksum::Crc32).unwrap() {
+        Packet::Handshake(Handshake::Challenge(cookie)) => cookie,
+        p => panic!("expected a challenge, got {:?}", p),
+    };
+    let response = UdpMessage::from(&Handshake::Response { cookie, salt: Default::default(), connection_id: Default::default(), checksum: Checksum::Crc32, mac: Default::default(), token: None });
+    client.send_to(response.as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    assert_eq!(socket.next_event(), Some(SocketEvent::NewRemote(RemoteID::new(0, 0))));
+    assert!(client.recv_from(&mut buffer).is_ok());
+
+    // anyone could send a request for the address of the remote: it isn't answered
+    client.send_to(request.as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    assert!(client.recv_from(&mut buffer).is_err());
+    // the response is, in case our accept was lost
+    client.send_to(response.as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    let (size, _) = client.recv_from(&mut buffer).unwrap();
+    assert!(size <= response.as_bytes().len());
+    match UdpMessage::new(&buffer[..size]).into_packet(Checksum::Crc32).unwrap() {
+        Packet::Handshake(Handshake::Accept { .. }) => {},
+        p => panic!("expected an accept, got {:?}", p),
+    }
+}
+
+#[test]
+fn socket_rejects_unchecked_handshakes_without_encryption() {
+    use transport::ChannelTransport;
+    let (client, transport) = ChannelTransport::pair();
+    let server_addr = client.peer_addr();
+    let mut socket = Socket::new(transport);
+    // only a socket that lost its pre-shared key could be left like this
+    socket.checksum = Checksum::None;
+    let mut buffer = [0u8; MAX_UDP_MESSAGE_SIZE];
+    client.send_to(UdpMessage::from(&Handshake::Request).as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    let (size, _) = client.recv_from(&mut buffer).unwrap();
+    let cookie = match UdpMessage::new(&buffer[..size]).into_packet(Checksum::Crc32).unwrap() {
+        Packet::Handshake(Handshake::Challenge(cookie)) => cookie,
+        p => panic!("expected a challenge, got {:?}", p),
+    };
+    let response = UdpMessage::from(&Handshake::Response { cookie, salt: Default::default(), connection_id: Default::default(), checksum: Checksum::None, mac: Default::default(), token: None });
+    client.send_to(response.as_bytes(), &server_addr).unwrap();
+    socket.prepare_iteration();
+    assert_eq!(socket.next_event(), None);
+    let (size, _) = client.recv_from(&mut buffer).unwrap();
+    match UdpMessage::new(&buffer[..size]).into_packet(Checksum::Crc32).unwrap() {
+        Packet::Handshake(Handshake::Reject { reason, .. }) => assert_eq!(reason, RejectReason::ChecksumMismatch),
+        p => panic!("expected a reject, got {:?}", p),
+    }
+}
+
+#[test]
+fn socket_encrypted_connection() {
+    use transport::ChannelTransport;
+    use conditioner::{LinkConditioner, LinkConditions};
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(LinkConditioner::new(transport1, Default::default(), 42));
+    let mut socket2 = Socket::new(transport2);
+    assert!(matches!(socket1.set_checksum(Checksum::None), Err(SocketError::ChecksumNeedsEncryption)));
+    socket1.set_pre_shared_key(Some([5u8; 32]));
+    socket2.set_pre_shared_key(Some([5u8; 32]));
+    socket1.set_checksum(Checksum::None).unwrap();
+    socket2.set_checksum(Checksum::None).unwrap();
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.send_forgettable_message(remote1, &[1, 2, 3], 0).unwrap();
+    let received = socket2.receive_all_messages();
+    assert_eq!(&*received[0].1[0], &[1, 2, 3]);
+
+    // corruption can't go unnoticed, even without checksum
+    socket1.transport_mut().set_conditions(LinkConditions { corruption: 1.0, .. Default::default() });
+    socket1.send_forgettable_message(remote1, &[1u8; 100], 0).unwrap();
+    assert!(socket2.receive_all_messages()[0].1.is_empty());
+    assert_eq!(socket2.remote_stats(remote2).unwrap().dropped_fragments.unauthenticated, 1);
+}
+
+#[test]
+fn socket_disconnects_remotes_whose_keys_are_exhausted() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    socket1.set_pre_shared_key(Some([5u8; 32]));
+    socket2.set_pre_shared_key(Some([5u8; 32]));
+    let (remote1, _) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.with_remote(remote1, |_, remote| remote.next_packet_seq = u32::MAX).unwrap();
+    assert!(matches!(socket1.send_forgettable_message(remote1, &[1, 2, 3], 0), Err(SocketError::KeysExhausted(id)) if id == remote1));
+    assert_eq!(socket1.next_event(), Some(SocketEvent::KeysExhausted(remote1)));
+    assert_eq!(socket1.remote_status(remote1).unwrap(), RemoteStatus::Disconnected);
+    assert!(matches!(socket1.send_forgettable_message(remote1, &[1, 2, 3], 0), Err(SocketError::InvalidRemoteId(_))));
+}
+
+#[test]
+fn socket_drops_replayed_packets() {
+    use transport::ChannelTransport;
+    use conditioner::{LinkConditioner, LinkConditions};
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(LinkConditioner::new(transport1, Default::default(), 42));
+    let mut socket2 = Socket::new(transport2);
+    socket1.set_pre_shared_key(Some([5u8; 32]));
+    socket2.set_pre_shared_key(Some([5u8; 32]));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket1.transport_mut().set_conditions(LinkConditions { duplication: 1.0, .. Default::default() });
+    socket1.send_forgettable_message(remote1, &[1, 2, 3], 0).unwrap();
+    let received = socket2.receive_all_messages();
+    assert_eq!(received[0].1.len(), 1);
+    assert_eq!(socket2.remote_stats(remote2).unwrap().dropped_fragments.replayed, 1);
+    assert_eq!(socket2.stats().dropped_fragments.replayed, 1);
+}
+
+#[test]
+fn socket_rejects_wrong_pre_shared_key() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    socket1.set_pre_shared_key(Some([5u8; 32]));
+    socket2.set_pre_shared_key(Some([6u8; 32]));
+    socket1.connect_to(socket2.local_addr().unwrap());
+    for _ in 0..2 {
+        socket2.prepare_iteration();
+        socket1.prepare_iteration();
+    }
+    assert_eq!(socket2.next_event(), None);
+    assert_eq!(socket2.stats().remotes, 0);
+    assert_eq!(socket2.stats().unauthenticated_handshakes, 1);
+}
+
+#[test]
+fn socket_requires_connect_tokens() {
+    use transport::ChannelTransport;
+    use token::{ConnectTokenGenerator, ConnectTokenValidator};
+    let server_addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    let generator = ConnectTokenGenerator::new(&[1u8; 32]);
+    let token = generator.generate(42, &[server_addr], SystemTime::now() + Duration::from_secs(30), b"admin").unwrap();
+    let connect = |token: Option<ConnectToken>| {
+        let (transport1, transport2) = ChannelTransport::pair();
+        let mut client = Socket::new(transport1);
+        let mut server = Socket::new(transport2);
+        server.require_connect_tokens(Some(ConnectTokenValidator::new(&[1u8; 32], vec![server_addr])));
+        match token {
+            Some(token) => client.connect_with_token(server.local_addr().unwrap(), token),
+            None => client.connect_to(server.local_addr().unwrap()),
+        };
+        for _ in 0..2 {
+            server.prepare_iteration();
+            client.prepare_iteration();
+        }
+        server
+    };
+
+    let mut server = connect(None);
+    assert_eq!(server.next_event(), None);
+    assert_eq!(server.stats().rejected_tokens, 1);
+
+    let mut server = connect(Some(token));
+    let remote_id = match server.next_event() {
+        Some(SocketEvent::NewRemote(remote_id)) => remote_id,
+        e => panic!("expected a NewRemote event, got {:?}", e),
+    };
+    let token_data = server.connect_token_data(remote_id).unwrap().unwrap();
+    assert_eq!(token_data.client_id, 42);
+    assert_eq!(&token_data.user_data[..5], b"admin");
+}
+
+#[test]
+fn socket_encrypts_connections_with_the_key_of_the_token() {
+    use transport::ChannelTransport;
+    use token::{ConnectTokenGenerator, ConnectTokenValidator};
+    let server_addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    let generator = ConnectTokenGenerator::new(&[1u8; 32]);
+    let connect = |token: ConnectToken| {
+        let (transport1, transport2) = ChannelTransport::pair();
+        let mut client = Socket::new(transport1);
+        let mut server = Socket::new(transport2);
+        server.require_connect_tokens(Some(ConnectTokenValidator::new(&[1u8; 32], vec![server_addr])));
+        let remote_id = client.connect_with_token(server.local_addr().unwrap(), token);
+        for _ in 0..2 {
+            server.prepare_iteration();
+            client.prepare_iteration();
+        }
+        (client, server, remote_id)
+    };
+
+    // a stolen token is useless without its session key
+    let token = generator.generate(42, &[server_addr], SystemTime::now() + Duration::from_secs(30), &[]).unwrap();
+    let mut stolen = token.as_bytes().to_vec();
+    let last = stolen.len() - 1;
+    stolen[last] ^= 1;
+    let (_, mut server, _) = connect(ConnectToken::from_bytes(&stolen).unwrap());
+    assert_eq!(server.next_event(), None);
+    assert_eq!(server.stats().unauthenticated_handshakes, 1);
+
+    let (mut client, mut server, remote1) = connect(token);
+    let remote2 = match server.next_event() {
+        Some(SocketEvent::NewRemote(remote_id)) => remote_id,
+        e => panic!("expected a NewRemote event, got {:?}", e),
+    };
+    assert_eq!(client.next_event(), Some(SocketEvent::Connected(remote1)));
+    assert!(client.remote(remote1).unwrap().session.is_some());
+    assert!(server.remote(remote2).unwrap().session.is_some());
+    client.send_forgettable_message(remote1, &[1, 2, 3], 0).unwrap();
+    let received = server.receive_all_messages();
+    assert_eq!(&*received[0].1[0], &[1, 2, 3]);
+}
+
+#[test]
+fn socket_rejects_connections_when_full() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut client = Socket::new(transport1);
+    let mut server = Socket::new(transport2);
+    server.set_max_remotes(0);
+    let remote_id = client.connect_to(server.local_addr().unwrap());
+    for _ in 0..2 {
+        server.prepare_iteration();
+        client.prepare_iteration();
+    }
+    assert_eq!(server.next_event(), None);
+    assert_eq!(server.stats().rejected_connections, 1);
+    assert_eq!(client.next_event(), Some(SocketEvent::ConnectRejected(remote_id, RejectReason::ServerFull)));
+    assert_eq!(client.remote_status(remote_id).unwrap(), RemoteStatus::Disconnected);
+}
+
+#[test]
+fn socket_max_remotes_ignores_outgoing_connections() {
+    use transport::ChannelTransport;
+    let (client_transport, server_transport1) = ChannelTransport::pair();
+    let (server_transport2, peer_transport) = ChannelTransport::pair();
+    let mut server = Socket::new(ChannelHub(vec![server_transport1, server_transport2]));
+    let mut client = Socket::new(client_transport);
+    let mut peer = Socket::new(peer_transport);
+    server.set_max_remotes(1);
+    // the connection the server made itself leaves room for one client
+    let _ = connect_sockets(&mut server, &mut peer);
+    let remote_id = client.connect_to(server.local_addr().unwrap());
+    for _ in 0..2 {
+        server.prepare_iteration();
+        client.prepare_iteration();
+    }
+    assert_eq!(server.stats().rejected_connections, 0);
+    assert_eq!(client.next_event(), Some(SocketEvent::Connected(remote_id)));
+}
+
+#[test]
+fn socket_checksums() {
+    use transport::ChannelTransport;
+    use conditioner::{LinkConditioner, LinkConditions};
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(LinkConditioner::new(transport1, Default::default(), 42));
+    let mut socket2 = Socket::new(transport2);
+    socket1.set_checksum(Checksum::Hash64).unwrap();
+    socket2.set_checksum(Checksum::Hash64).unwrap();
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    let stats_before = socket1.remote_stats(remote1).unwrap();
+    socket1.send_forgettable_message(remote1, &[1, 2, 3], 0).unwrap();
+    let received = socket2.receive_all_messages();
+    assert_eq!(&*received[0].1[0], &[1, 2, 3]);
+    let bytes_sent = socket1.remote_stats(remote1).unwrap().bytes_sent - stats_before.bytes_sent;
+    assert_eq!(bytes_sent, (3 + PACKET_TYPE_SIZE + FRAG_HEADER_SIZE + CONNECTION_ID_SIZE + Checksum::Hash64.size()) as u64);
+    socket1.transport_mut().set_conditions(LinkConditions { corruption: 1.0, .. Default::default() });
+    socket1.send_forgettable_message(remote1, &[1u8; 100], 0).unwrap();
+    assert!(socket2.receive_all_messages()[0].1.is_empty());
+    assert_eq!(socket2.remote_stats(remote2).unwrap().dropped_fragments.invalid_crc, 1);
+
+    // the server refuses the clients that check packets differently
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut client = Socket::new(transport1);
+    let mut server = Socket::new(transport2);
+    client.set_checksum(Checksum::Crc32c).unwrap();
+    let remote_id = client.connect_to(server.local_addr().unwrap());
+    for _ in 0..2 {
+        server.prepare_iteration();
+        client.prepare_iteration();
+    }
+    assert_eq!(server.next_event(), None);
+    assert_eq!(server.stats().rejected_connections, 1);
+    assert_eq!(client.next_event(), Some(SocketEvent::ConnectRejected(remote_id, RejectReason::ChecksumMismatch)));
+}
+
+#[test]
+fn socket_rejects_stale_remote_ids() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket = Socket::new(transport1);
+    let remote_addr = transport2.local_addr().unwrap();
+    let old_remote = socket.connect_to(remote_addr);
+    socket.disconnect(old_remote).unwrap();
+    // the disconnected remote is removed, its slot is reused
+    socket.prepare_iteration();
+    let new_remote = socket.connect_to(remote_addr);
+    assert_eq!(new_remote.index(), old_remote.index());
+    assert_ne!(new_remote, old_remote);
+    match socket.send_forgettable_message(old_remote, &[1], 0) {
+        Err(SocketError::InvalidRemoteId(remote_id)) => assert_eq!(remote_id, old_remote),
+        r => panic!("expected an invalid remote id, got {:?}", r),
+    }
+    assert!(socket.send_forgettable_message(new_remote, &[1], 0).is_ok());
+}
+
+#[test]
+fn socket_remote_migration() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (old_transport, server_transport1) = ChannelTransport::pair();
+    let (new_transport, server_transport2) = ChannelTransport::pair();
+    let server_addr = old_transport.peer_addr();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(TestTransport { received_from: Some(server_addr), ..TestTransport::new(old_transport) }, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(ChannelHub(vec![server_transport1, server_transport2]), Box::new(clock.clone()));
+    socket1.set_pre_shared_key(Some([5u8; 32]));
+    socket2.set_pre_shared_key(Some([5u8; 32]));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    // the NAT of socket1 gives it another port, what is still sent to the old one is lost
+    let new_addr = new_transport.local_addr().unwrap();
+    let _old_transport = ::std::mem::replace(socket1.transport_mut(), TestTransport { received_from: Some(server_addr), ..TestTransport::new(new_transport) });
+
+    socket1.send_key_message(remote1, &[1, 2, 3], 0).unwrap();
+    // the message is received from the new address, which is challenged
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.received_messages(), vec![(remote2, vec![Payload::from(&[1u8, 2, 3][..])].into())]);
+    assert_ne!(socket2.remote_addr(remote2).unwrap(), new_addr);
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    assert!(socket2.update(clock.now()).any(|e| e == SocketEvent::Migrated(remote2)));
+    assert_eq!(socket2.remote_addr(remote2).unwrap(), new_addr);
+    assert_eq!(socket2.stats().migrations, 1);
+    // and is now where everything is sent
+    socket2.send_key_message(remote2, &[4, 5], 0).unwrap();
+    assert!(socket1.update(clock.now()).all(|e| matches!(e, SocketEvent::Acked(..))));
+    assert_eq!(socket1.received_messages(), vec![(remote1, vec![Payload::from(&[4u8, 5][..])].into())]);
+}
+
+#[test]
+fn socket_rate_limits_a_moving_remote_as_a_remote() {
+    use transport::ChannelTransport;
+    use clock::MockClock;
+    let (old_transport, server_transport1) = ChannelTransport::pair();
+    let (new_transport, server_transport2) = ChannelTransport::pair();
+    let server_addr = old_transport.peer_addr();
+    let clock = MockClock::new();
+    let mut socket1 = Socket::with_clock(TestTransport { received_from: Some(server_addr), ..TestTransport::new(old_transport) }, Box::new(clock.clone()));
+    let mut socket2 = Socket::with_clock(ChannelHub(vec![server_transport1, server_transport2]), Box::new(clock.clone()));
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    let _old_transport = ::std::mem::replace(socket1.transport_mut(), TestTransport { received_from: Some(server_addr), ..TestTransport::new(new_transport) });
+    // more packets than the connection attempts of an address, before its path is validated
+    for i in 0..20u8 {
+        socket1.send_forgettable_message(remote1, &[i], 0).unwrap();
+    }
+    assert_eq!(socket2.update(clock.now()).next(), None);
+    assert_eq!(socket2.stats().rate_limited_packets, 0);
+    let expected: VecDeque<Payload> = (0..20u8).map(|i| Payload::from(vec![i])).collect();
+    assert_eq!(socket2.received_messages(), vec![(remote2, expected)]);
+    assert_eq!(socket1.update(clock.now()).next(), None);
+    assert!(socket2.update(clock.now()).any(|e| e == SocketEvent::Migrated(remote2)));
+}
+
+#[test]
+fn socket_simultaneous_connect() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    let remote1 = socket1.connect_to(socket2.local_addr().unwrap());
+    let remote2 = socket2.connect_to(socket1.local_addr().unwrap());
+    for _ in 0..3 {
+        socket1.prepare_iteration();
+        socket2.prepare_iteration();
+    }
+    assert_eq!(socket1.next_event(), Some(SocketEvent::Connected(remote1)));
+    assert_eq!(socket2.next_event(), Some(SocketEvent::Connected(remote2)));
+}
+
+#[test]
+fn socket_rate_limits() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, _) = connect_sockets(&mut socket1, &mut socket2);
+    socket2.set_rate_limits(RateLimits { packets_per_second: 1, packet_burst: 5, .. Default::default() });
+    for _ in 0..10 {
+        socket1.send_forgettable_message(remote1, &[1u8; 10], 0).unwrap();
+    }
+    let messages = socket2.receive_all_messages();
+    assert_eq!(messages[0].1.len(), 5);
+    assert_eq!(socket2.stats().rate_limited_packets, 5);
+}
+
+#[test]
+fn socket_ban_remote() {
+    use transport::ChannelTransport;
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut socket1 = Socket::new(transport1);
+    let mut socket2 = Socket::new(transport2);
+    let (remote1, remote2) = connect_sockets(&mut socket1, &mut socket2);
+    socket2.ban_remote(remote2, None).unwrap();
+    assert_eq!(socket2.remote_status(remote2).unwrap(), RemoteStatus::Disconnected);
+    assert!(socket2.is_banned(&socket1.local_addr().unwrap()));
+    socket1.prepare_iteration();
+    assert_eq!(socket1.next_event(), Some(SocketEvent::Disconnected(remote1)));
+
+    // it can't even connect again
+    let remote1 = socket1.connect_to(socket2.local_addr().unwrap());
+    socket2.prepare_iteration();
+    assert_eq!(socket2.stats().banned_packets, 1);
+    assert_eq!(socket2.stats().remotes, 0);
+    assert!(socket2.unban_addr(&socket1.local_addr().unwrap()));
+    socket1.disconnect(remote1).unwrap();
+    connect_sockets(&mut socket1, &mut socket2);
+}
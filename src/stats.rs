@@ -0,0 +1,168 @@
+use std::time::{Duration, Instant};
+
+use udp_message::UdpMessageError;
+
+/// Length of the window the send rate is computed on.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Received fragments that were dropped, sorted by the reason they were dropped for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DroppedFragments {
+    /// The datagram was too small to hold a fragment header
+    pub not_big_enough: u64,
+    /// The checksum at the end of the datagram did not match its content
+    pub invalid_crc: u64,
+    /// frag_id was higher than frag_total
+    pub invalid_frag_info: u64,
+    /// frag_total was higher than the maximum amount of fragments for one message
+    pub frag_total_too_large: u64,
+    /// The datagram had a valid checksum, but was not a kind of packet we know of
+    pub unknown_packet_type: u64,
+    /// The datagram should have been encrypted with the keys of the connection, but was not
+    pub unauthenticated: u64,
+    /// The encrypted datagram was already received, or was too old to tell
+    pub replayed: u64,
+}
+
+impl DroppedFragments {
+    pub fn total(&self) -> u64 {
+        self.not_big_enough + self.invalid_crc + self.invalid_frag_info + self.frag_total_too_large + self.unknown_packet_type + self.unauthenticated + self.replayed
+    }
+
+    pub (crate) fn count(&mut self, error: UdpMessageError) {
+        match error {
+            UdpMessageError::NotBigEnough => self.not_big_enough += 1,
+            UdpMessageError::InvalidCrc => self.invalid_crc += 1,
+            UdpMessageError::InvalidFragInfo => self.invalid_frag_info += 1,
+            UdpMessageError::FragTotalTooLarge => self.frag_total_too_large += 1,
+            UdpMessageError::UnknownPacketType => self.unknown_packet_type += 1,
+            UdpMessageError::Unauthenticated => self.unauthenticated += 1,
+            UdpMessageError::Replayed => self.replayed += 1,
+        }
+    }
+}
+
+/// A snapshot of the network statistics of one remote.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RemoteStats {
+    /// Datagrams sent to this remote
+    pub packets_sent: u64,
+    /// Bytes sent to this remote, headers included
+    pub bytes_sent: u64,
+    /// Datagrams received from this remote, including the ones that were dropped
+    pub packets_received: u64,
+    /// Bytes received from this remote, headers included
+    pub bytes_received: u64,
+    /// Messages given to the socket to be sent to this remote
+    pub messages_sent: u64,
+    /// Messages that were fully reassembled and handed back to the user
+    pub messages_received: u64,
+    /// Fragments received from this remote that were dropped
+    pub dropped_fragments: DroppedFragments,
+    /// Datagrams that had to be sent again because they were not acknowledged in time
+    pub retransmissions: u64,
+    /// Estimated packet loss towards this remote, between 0.0 and 100.0
+    pub packet_loss: f32,
+    /// Estimated round trip time. `None` as long as nothing has been acknowledged by this remote.
+    pub rtt: Option<Duration>,
+    /// Bytes per second sent to this remote over the last second
+    pub send_rate: f32,
+}
+
+/// A snapshot of the network statistics of a whole Socket.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SocketStats {
+    /// Number of remotes currently known by the socket
+    pub remotes: usize,
+    /// Datagrams sent, to all remotes
+    pub packets_sent: u64,
+    /// Bytes sent, to all remotes
+    pub bytes_sent: u64,
+    /// Datagrams received, including the ones from unknown addresses
+    pub packets_received: u64,
+    /// Bytes received, including the ones from unknown addresses
+    pub bytes_received: u64,
+    /// Datagrams received from addresses that are not one of our remotes
+    pub unknown_packets: u64,
+    /// ConnectResponses whose cookie was forged, too old, or for another address
+    pub rejected_cookies: u64,
+    /// ConnectResponses and ConnectAccepts from ends that did not prove they know the pre-shared key
+    pub unauthenticated_handshakes: u64,
+    /// ConnectResponses without a valid connect token, while the socket requires one
+    pub rejected_tokens: u64,
+    /// ConnectResponses answered with a ConnectReject because we had too many remotes, or because
+    /// the client uses another checksum
+    pub rejected_connections: u64,
+    /// Remotes that moved to another address, once they answered a path challenge there
+    pub migrations: u64,
+    /// Datagrams dropped because their sender went over its rate limits
+    pub rate_limited_packets: u64,
+    /// Datagrams dropped because their sender is banned
+    pub banned_packets: u64,
+    /// Messages given to the socket to be sent, to all remotes
+    pub messages_sent: u64,
+    /// Messages fully reassembled, from all remotes
+    pub messages_received: u64,
+    /// Fragments received from all remotes that were dropped
+    pub dropped_fragments: DroppedFragments,
+    /// Datagrams that had to be sent again, to all remotes
+    pub retransmissions: u64,
+    /// Bytes per second sent over the last second, to all remotes
+    pub send_rate: f32,
+}
+
+/// Computes a rate of bytes per second over a fixed window.
+#[derive(Debug)]
+pub (crate) struct RateEstimator {
+    window_start: Instant,
+    window_bytes: u64,
+    last_rate: f32,
+}
+
+impl RateEstimator {
+    pub fn new(now: Instant) -> RateEstimator {
+        RateEstimator {
+            window_start: now,
+            window_bytes: 0,
+            last_rate: 0.0,
+        }
+    }
+
+    /// Closes the current window if it is over
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW * 2 {
+            // nothing has been recorded for a whole window, we are not sending anything anymore
+            self.last_rate = 0.0;
+            self.window_start = now;
+            self.window_bytes = 0;
+        } else if elapsed >= RATE_WINDOW {
+            self.last_rate = self.window_bytes as f32 / elapsed.as_secs_f32();
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize, now: Instant) {
+        self.roll(now);
+        self.window_bytes += bytes as u64;
+    }
+
+    /// Returns the rate of the last complete window, in bytes per second
+    pub fn rate(&mut self, now: Instant) -> f32 {
+        self.roll(now);
+        self.last_rate
+    }
+}
+
+#[test]
+fn rate_estimator_window() {
+    let start = Instant::now();
+    let mut rate_estimator = RateEstimator::new(start);
+    rate_estimator.record(500, start);
+    rate_estimator.record(500, start + Duration::from_millis(500));
+    assert_eq!(rate_estimator.rate(start + Duration::from_millis(900)), 0.0);
+    assert_eq!(rate_estimator.rate(start + Duration::from_millis(1000)), 1000.0);
+    // nothing sent for 2 windows: the rate drops to 0
+    assert_eq!(rate_estimator.rate(start + Duration::from_millis(3000)), 0.0);
+}
@@ -0,0 +1,337 @@
+//! Connect tokens: a backend that shares a private key with the servers decides who may connect.
+//!
+//! The backend generates a token for a client, which sends it to the server in its
+//! ConnectResponse. The server only accepts the connection if the token was generated for it, has
+//! not expired, and was not used before. Everything but the server addresses and the expiry is
+//! encrypted, so the client can't read or modify its own user data.
+//!
+//! Every token also holds a random key, which the client gets in clear along with its token: the
+//! connection is encrypted with keys derived from it, without a pre-shared key.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use fnv::FnvHashMap as HashMap;
+
+use byteorder::{BigEndian, ByteOrder};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+
+use crypto::PreSharedKey;
+
+/// The key shared by the backend and the servers
+pub type ConnectTokenKey = [u8; 32];
+
+/// Size of the user data of a connect token, shorter user data is padded with zeroes
+pub const USER_DATA_SIZE: usize = 256;
+/// Maximum amount of server addresses in a connect token
+pub const MAX_SERVER_ADDRESSES: usize = 8;
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+const SESSION_KEY_SIZE: usize = 32;
+/// 1 byte for the IP version (or 0 for no address), 16 for the IP, 2 for the port
+const ADDRESS_SIZE: usize = 1 + 16 + 2;
+/// Expiry, nonce, amount of addresses and the addresses, authenticated but not encrypted
+const PUBLIC_SIZE: usize = 8 + NONCE_SIZE + 1 + MAX_SERVER_ADDRESSES * ADDRESS_SIZE;
+/// Client id, session key and user data, encrypted
+const PRIVATE_SIZE: usize = 8 + SESSION_KEY_SIZE + USER_DATA_SIZE;
+
+/// Size of every connect token, as sent to a server
+pub (crate) const CONNECT_TOKEN_SIZE: usize = PUBLIC_SIZE + PRIVATE_SIZE + TAG_SIZE;
+/// Size of a connect token given to a client, followed by its session key
+const CLIENT_TOKEN_SIZE: usize = CONNECT_TOKEN_SIZE + SESSION_KEY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectTokenError {
+    TooManyAddresses,
+    UserDataTooLarge(usize),
+    InvalidSize,
+}
+
+impl ::std::fmt::Display for ConnectTokenError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            ConnectTokenError::TooManyAddresses => write!(f, "Too many server addresses for a connect token"),
+            ConnectTokenError::UserDataTooLarge(size) => write!(f, "User data of {} bytes is too large for a connect token", size),
+            ConnectTokenError::InvalidSize => write!(f, "Invalid connect token size"),
+        }
+    }
+}
+
+impl ::std::error::Error for ConnectTokenError {}
+
+/// A token allowing a client to connect to some servers, see `ConnectTokenGenerator`.
+///
+/// The client's copy ends with the key of its session, only the rest is sent to the server.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ConnectToken(Box<[u8]>);
+
+impl ::std::fmt::Debug for ConnectToken {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("ConnectToken")
+            .field("expires_at", &self.expires_at())
+            .field("server_addresses", &self.server_addresses())
+            .finish()
+    }
+}
+
+/// What a valid connect token tells a server about its client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectTokenData {
+    pub client_id: u64,
+    /// Always `USER_DATA_SIZE` bytes long
+    pub user_data: Box<[u8]>,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn write_address(buffer: &mut [u8], addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buffer[0] = 4;
+            buffer[1..5].copy_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            buffer[0] = 6;
+            buffer[1..17].copy_from_slice(&ip.octets());
+        },
+    }
+    BigEndian::write_u16(&mut buffer[17..19], addr.port());
+}
+
+fn read_address(buffer: &[u8]) -> Option<SocketAddr> {
+    let ip = match buffer[0] {
+        4 => IpAddr::V4(Ipv4Addr::new(buffer[1], buffer[2], buffer[3], buffer[4])),
+        6 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buffer[1..17]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        },
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, BigEndian::read_u16(&buffer[17..19])))
+}
+
+impl ConnectToken {
+    /// Reads a token received from the backend
+    pub fn from_bytes(bytes: &[u8]) -> Result<ConnectToken, ConnectTokenError> {
+        if bytes.len() != CLIENT_TOKEN_SIZE {
+            return Err(ConnectTokenError::InvalidSize);
+        }
+        Ok(ConnectToken(bytes.into()))
+    }
+
+    /// Reads a token received by a server, without the session key
+    pub (crate) fn from_handshake_bytes(bytes: &[u8]) -> Result<ConnectToken, ConnectTokenError> {
+        if bytes.len() != CONNECT_TOKEN_SIZE {
+            return Err(ConnectTokenError::InvalidSize);
+        }
+        Ok(ConnectToken(bytes.into()))
+    }
+
+    /// What the backend gives the client. It holds the session key in clear, so it must reach the
+    /// client over a secure channel.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// What the client sends to the server
+    pub (crate) fn handshake_bytes(&self) -> &[u8] {
+        &self.0[..CONNECT_TOKEN_SIZE]
+    }
+
+    /// The key the session is encrypted with, only known by the client and the server
+    pub (crate) fn session_key(&self) -> Option<PreSharedKey> {
+        if self.0.len() != CLIENT_TOKEN_SIZE {
+            return None;
+        }
+        let mut key = [0u8; SESSION_KEY_SIZE];
+        key.copy_from_slice(&self.0[CONNECT_TOKEN_SIZE..]);
+        Some(key)
+    }
+
+    /// When the token stops being accepted by the servers, in seconds since the unix epoch
+    pub fn expires_at(&self) -> u64 {
+        BigEndian::read_u64(&self.0[0..8])
+    }
+
+    /// The servers the client can connect to with this token, in order of preference
+    pub fn server_addresses(&self) -> Vec<SocketAddr> {
+        let offset = 8 + NONCE_SIZE;
+        let count = ::std::cmp::min(self.0[offset] as usize, MAX_SERVER_ADDRESSES);
+        self.0[offset + 1..PUBLIC_SIZE]
+            .chunks(ADDRESS_SIZE)
+            .take(count)
+            .filter_map(read_address)
+            .collect()
+    }
+
+    /// The authentication tag is different for every token
+    fn tag(&self) -> [u8; TAG_SIZE] {
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&self.0[CONNECT_TOKEN_SIZE - TAG_SIZE..CONNECT_TOKEN_SIZE]);
+        tag
+    }
+}
+
+/// Generates connect tokens, on the backend.
+#[derive(Clone)]
+pub struct ConnectTokenGenerator {
+    cipher: XChaCha20Poly1305,
+}
+
+impl ::std::fmt::Debug for ConnectTokenGenerator {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str("ConnectTokenGenerator { .. }")
+    }
+}
+
+impl ConnectTokenGenerator {
+    pub fn new(key: &ConnectTokenKey) -> ConnectTokenGenerator {
+        ConnectTokenGenerator {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+
+    /// Generates a token allowing client `client_id` to connect once to one of `server_addresses`,
+    /// until `expires_at`. Servers get `user_data` back once the client is connected.
+    pub fn generate(&self, client_id: u64, server_addresses: &[SocketAddr], expires_at: SystemTime, user_data: &[u8]) -> Result<ConnectToken, ConnectTokenError> {
+        if server_addresses.len() > MAX_SERVER_ADDRESSES {
+            return Err(ConnectTokenError::TooManyAddresses);
+        }
+        if user_data.len() > USER_DATA_SIZE {
+            return Err(ConnectTokenError::UserDataTooLarge(user_data.len()));
+        }
+        let mut session_key = [0u8; SESSION_KEY_SIZE];
+        getrandom::getrandom(&mut session_key).expect("failed to generate a random session key");
+        let mut buffer = vec!(0u8; PUBLIC_SIZE + PRIVATE_SIZE);
+        BigEndian::write_u64(&mut buffer[0..8], unix_time(expires_at));
+        getrandom::getrandom(&mut buffer[8..8 + NONCE_SIZE]).expect("failed to generate a random nonce");
+        buffer[8 + NONCE_SIZE] = server_addresses.len() as u8;
+        for (addr, chunk) in server_addresses.iter().zip(buffer[8 + NONCE_SIZE + 1..PUBLIC_SIZE].chunks_mut(ADDRESS_SIZE)) {
+            write_address(chunk, addr);
+        }
+        BigEndian::write_u64(&mut buffer[PUBLIC_SIZE..PUBLIC_SIZE + 8], client_id);
+        buffer[PUBLIC_SIZE + 8..PUBLIC_SIZE + 8 + SESSION_KEY_SIZE].copy_from_slice(&session_key);
+        let user_data_offset = PUBLIC_SIZE + 8 + SESSION_KEY_SIZE;
+        buffer[user_data_offset..user_data_offset + user_data.len()].copy_from_slice(user_data);
+
+        let (public, private) = buffer.split_at_mut(PUBLIC_SIZE);
+        let nonce = *XNonce::from_slice(&public[8..8 + NONCE_SIZE]);
+        let tag = self.cipher.encrypt_in_place_detached(&nonce, public, private)
+            .expect("connect tokens are small enough to be encrypted");
+        buffer.extend_from_slice(&tag);
+        buffer.extend_from_slice(&session_key);
+        Ok(ConnectToken(buffer.into_boxed_slice()))
+    }
+}
+
+/// Checks the connect tokens sent to a server, and remembers which ones were already used.
+pub struct ConnectTokenValidator {
+    cipher: XChaCha20Poly1305,
+    /// The addresses clients can reach this server at
+    public_addresses: Vec<SocketAddr>,
+    /// The tags of the tokens used so far, and when the tokens expire
+    used_tokens: HashMap<[u8; TAG_SIZE], u64>,
+    pruned_at: u64,
+}
+
+impl ::std::fmt::Debug for ConnectTokenValidator {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("ConnectTokenValidator")
+            .field("public_addresses", &self.public_addresses)
+            .field("used_tokens", &self.used_tokens.len())
+            .finish()
+    }
+}
+
+impl ConnectTokenValidator {
+    /// Only tokens listing one of `public_addresses` are accepted
+    pub fn new(key: &ConnectTokenKey, public_addresses: Vec<SocketAddr>) -> ConnectTokenValidator {
+        ConnectTokenValidator {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+            public_addresses,
+            used_tokens: Default::default(),
+            pruned_at: 0,
+        }
+    }
+
+    /// Returns what the token holds if it is valid, and marks it as used.
+    pub fn validate(&mut self, token: &ConnectToken, now: SystemTime) -> Option<ConnectTokenData> {
+        let (token_data, _session_key) = self.open(token, now)?;
+        self.use_up(token);
+        Some(token_data)
+    }
+
+    /// Returns what the token holds and its session key if it is valid, without marking it as used
+    pub (crate) fn open(&mut self, token: &ConnectToken, now: SystemTime) -> Option<(ConnectTokenData, PreSharedKey)> {
+        let now = unix_time(now);
+        if now > self.pruned_at {
+            self.used_tokens.retain(|_, expires_at| *expires_at >= now);
+            self.pruned_at = now;
+        }
+        if token.expires_at() < now || self.used_tokens.contains_key(&token.tag()) {
+            return None;
+        }
+        if !token.server_addresses().iter().any(|addr| self.public_addresses.contains(addr)) {
+            return None;
+        }
+        let mut buffer = token.handshake_bytes().to_vec();
+        let tag = *Tag::from_slice(&buffer[PUBLIC_SIZE + PRIVATE_SIZE..]);
+        buffer.truncate(PUBLIC_SIZE + PRIVATE_SIZE);
+        let (public, private) = buffer.split_at_mut(PUBLIC_SIZE);
+        let nonce = *XNonce::from_slice(&public[8..8 + NONCE_SIZE]);
+        self.cipher.decrypt_in_place_detached(&nonce, public, private, &tag).ok()?;
+        let mut session_key = [0u8; SESSION_KEY_SIZE];
+        session_key.copy_from_slice(&private[8..8 + SESSION_KEY_SIZE]);
+        let token_data = ConnectTokenData {
+            client_id: BigEndian::read_u64(&private[..8]),
+            user_data: private[8 + SESSION_KEY_SIZE..].into(),
+        };
+        Some((token_data, session_key))
+    }
+
+    /// Marks a token as used, it won't be accepted again
+    pub (crate) fn use_up(&mut self, token: &ConnectToken) {
+        self.used_tokens.insert(token.tag(), token.expires_at());
+    }
+}
+
+#[test]
+fn connect_token_validation() {
+    use std::time::Duration;
+    let now = SystemTime::now();
+    let server: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    let other_server: SocketAddr = "[::1]:4000".parse().unwrap();
+    let generator = ConnectTokenGenerator::new(&[3u8; 32]);
+    let token = generator.generate(7, &[other_server, server], now + Duration::from_secs(30), b"team blue").unwrap();
+    assert_eq!(token.as_bytes().len(), CLIENT_TOKEN_SIZE);
+    assert_eq!(token.server_addresses(), vec![other_server, server]);
+    // the server gets the session key of the client from the encrypted part of the token
+    let received = ConnectToken::from_handshake_bytes(token.handshake_bytes()).unwrap();
+    assert_eq!(received.session_key(), None);
+    let (_, session_key) = ConnectTokenValidator::new(&[3u8; 32], vec![server]).open(&received, now).unwrap();
+    assert_eq!(Some(session_key), token.session_key());
+
+    // another key, another server, or too late
+    assert_eq!(ConnectTokenValidator::new(&[4u8; 32], vec![server]).validate(&token, now), None);
+    assert_eq!(ConnectTokenValidator::new(&[3u8; 32], vec!["10.0.0.2:4000".parse().unwrap()]).validate(&token, now), None);
+    assert_eq!(ConnectTokenValidator::new(&[3u8; 32], vec![server]).validate(&token, now + Duration::from_secs(31)), None);
+    let mut tampered = token.as_bytes().to_vec();
+    tampered[PUBLIC_SIZE] ^= 1;
+    let tampered = ConnectToken::from_bytes(&tampered).unwrap();
+    assert_eq!(ConnectTokenValidator::new(&[3u8; 32], vec![server]).validate(&tampered, now), None);
+
+    let mut validator = ConnectTokenValidator::new(&[3u8; 32], vec![server]);
+    let data = validator.validate(&token, now).unwrap();
+    assert_eq!(data.client_id, 7);
+    assert_eq!(&data.user_data[..9], b"team blue");
+    assert_eq!(data.user_data.len(), USER_DATA_SIZE);
+    // a token can only be used once
+    assert_eq!(validator.validate(&token, now), None);
+
+    assert_eq!(generator.generate(7, &[server; 9], now, &[]).unwrap_err(), ConnectTokenError::TooManyAddresses);
+    assert_eq!(generator.generate(7, &[server], now, &[0u8; 257]).unwrap_err(), ConnectTokenError::UserDataTooLarge(257));
+}
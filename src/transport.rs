@@ -0,0 +1,249 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(all(feature = "mmsg", target_os = "linux"))]
+use mmsg;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+/// Something able to send and receive datagrams, that a Socket can run over.
+///
+/// Datagrams may be lost, duplicated or reordered, but they must never be split or merged.
+/// Once `set_nonblocking` has been called, `recv_from` must return an error of kind
+/// `ErrorKind::WouldBlock` instead of waiting when nothing has been received.
+pub trait DatagramTransport {
+    /// The address of the other end of a datagram
+    type Addr: Clone + Eq + Hash + Debug;
+
+    /// Sends a datagram to `addr`, returns the number of bytes sent
+    fn send_to(&self, buf: &[u8], addr: &Self::Addr) -> Result<usize>;
+
+    /// Receives one datagram into `buf`, returns its size and where it came from.
+    ///
+    /// If the datagram is larger than `buf`, the excess bytes are discarded.
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Self::Addr)>;
+
+    fn local_addr(&self) -> Result<Self::Addr>;
+
+    /// Makes `recv_from` return `ErrorKind::WouldBlock` instead of blocking
+    fn set_nonblocking(&self) -> Result<()>;
+
+    /// The IP address of `addr`, if this kind of address has one, so that a whole IP can be banned
+    fn ip_addr(_addr: &Self::Addr) -> Option<IpAddr> {
+        None
+    }
+
+    /// Receives up to one datagram per buffer, and pushes where each came from to `addrs`.
+    ///
+    /// Every buffer received into is truncated to the size of its datagram, the others are left
+    /// untouched. Returns how many datagrams were received, and fails only if none was.
+    ///
+    /// By default, `recv_from` is called once per datagram.
+    fn recv_batch(&self, buffers: &mut [Vec<u8>], addrs: &mut Vec<Self::Addr>) -> Result<usize> {
+        for (received, buffer) in buffers.iter_mut().enumerate() {
+            match self.recv_from(buffer.as_mut_slice()) {
+                Ok((size, addr)) => {
+                    buffer.truncate(size);
+                    addrs.push(addr);
+                },
+                Err(e) if received == 0 => return Err(e),
+                Err(_) => return Ok(received),
+            }
+        }
+        Ok(buffers.len())
+    }
+
+    /// Sends datagrams to `addr`, in order. Returns how many were sent, and fails only if none was.
+    ///
+    /// By default, `send_to` is called once per datagram.
+    fn send_batch<B: AsRef<[u8]>>(&self, datagrams: &[B], addr: &Self::Addr) -> Result<usize> {
+        for (sent, datagram) in datagrams.iter().enumerate() {
+            match self.send_to(datagram.as_ref(), addr) {
+                Ok(_) => {},
+                Err(e) if sent == 0 => return Err(e),
+                Err(_) => return Ok(sent),
+            }
+        }
+        Ok(datagrams.len())
+    }
+}
+
+/// With the `mmsg` feature on Linux, a batch of datagrams is sent or received with a single
+/// `sendmmsg` or `recvmmsg` syscall.
+impl DatagramTransport for UdpSocket {
+    type Addr = SocketAddr;
+
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        UdpSocket::set_nonblocking(self, true)
+    }
+
+    fn ip_addr(addr: &SocketAddr) -> Option<IpAddr> {
+        Some(addr.ip())
+    }
+
+    #[cfg(all(feature = "mmsg", target_os = "linux"))]
+    fn recv_batch(&self, buffers: &mut [Vec<u8>], addrs: &mut Vec<SocketAddr>) -> Result<usize> {
+        mmsg::recv_batch(self, buffers, addrs)
+    }
+
+    #[cfg(all(feature = "mmsg", target_os = "linux"))]
+    fn send_batch<B: AsRef<[u8]>>(&self, datagrams: &[B], addr: &SocketAddr) -> Result<usize> {
+        mmsg::send_batch(self, datagrams, addr)
+    }
+}
+
+/// Unix datagram sockets are addressed by their path, so both ends must be bound.
+#[cfg(unix)]
+impl DatagramTransport for UnixDatagram {
+    type Addr = PathBuf;
+
+    fn send_to(&self, buf: &[u8], addr: &PathBuf) -> Result<usize> {
+        UnixDatagram::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, PathBuf)> {
+        loop {
+            let (size, addr) = UnixDatagram::recv_from(self, buf)?;
+            // an unbound sender can't be answered, this can't be one of our remotes
+            if let Some(path) = addr.as_pathname() {
+                return Ok((size, path.to_path_buf()));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> Result<PathBuf> {
+        UnixDatagram::local_addr(self)?.as_pathname()
+            .map(|path| path.to_path_buf())
+            .ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "unix datagram socket is not bound to a path"))
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        UnixDatagram::set_nonblocking(self, true)
+    }
+}
+
+/// Address of one end of a `ChannelTransport` pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChannelAddr(u64);
+
+/// One end of an in-memory pair of transports, to run sockets without any real networking.
+///
+/// Datagrams are never lost and always arrive in order. `ChannelTransport` never blocks.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    addr: ChannelAddr,
+    peer_addr: ChannelAddr,
+    sender: Sender<Box<[u8]>>,
+    receiver: Receiver<Box<[u8]>>,
+}
+
+impl ChannelTransport {
+    /// Creates two transports connected to each other
+    pub fn pair() -> (ChannelTransport, ChannelTransport) {
+        static NEXT_ADDR: AtomicU64 = AtomicU64::new(0);
+        let addr1 = ChannelAddr(NEXT_ADDR.fetch_add(2, Ordering::Relaxed));
+        let addr2 = ChannelAddr(addr1.0 + 1);
+        let (sender1, receiver2) = channel();
+        let (sender2, receiver1) = channel();
+        let transport1 = ChannelTransport {
+            addr: addr1,
+            peer_addr: addr2,
+            sender: sender1,
+            receiver: receiver1,
+        };
+        let transport2 = ChannelTransport {
+            addr: addr2,
+            peer_addr: addr1,
+            sender: sender2,
+            receiver: receiver2,
+        };
+        (transport1, transport2)
+    }
+
+    pub fn peer_addr(&self) -> ChannelAddr {
+        self.peer_addr
+    }
+}
+
+impl DatagramTransport for ChannelTransport {
+    type Addr = ChannelAddr;
+
+    /// Fails with `ErrorKind::ConnectionRefused` if `addr` is not the other end of the pair,
+    /// or if the other end was dropped.
+    fn send_to(&self, buf: &[u8], addr: &ChannelAddr) -> Result<usize> {
+        if *addr != self.peer_addr {
+            return Err(Error::new(ErrorKind::ConnectionRefused, "no such channel address"));
+        }
+        self.sender.send(buf.to_vec().into_boxed_slice())
+            .map_err(|_| Error::new(ErrorKind::ConnectionRefused, "the other end of the channel was dropped"))?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, ChannelAddr)> {
+        match self.receiver.try_recv() {
+            Ok(datagram) => {
+                let size = ::std::cmp::min(buf.len(), datagram.len());
+                buf[..size].copy_from_slice(&datagram[..size]);
+                Ok((size, self.peer_addr))
+            },
+            // like a udp socket without anyone sending to it, nothing will ever be received
+            Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn local_addr(&self) -> Result<ChannelAddr> {
+        Ok(self.addr)
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn channel_transport_pair() {
+    let (transport1, transport2) = ChannelTransport::pair();
+    let mut buffer = [0u8; 4];
+    assert_eq!(transport2.recv_from(&mut buffer).unwrap_err().kind(), ErrorKind::WouldBlock);
+    transport1.send_to(&[1, 2, 3, 4, 5], &transport1.peer_addr()).unwrap();
+    assert_eq!(transport2.recv_from(&mut buffer).unwrap(), (4, transport1.local_addr().unwrap()));
+    assert_eq!(buffer, [1, 2, 3, 4]);
+    assert_eq!(transport1.send_to(&[1], &transport1.local_addr().unwrap()).unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    drop(transport2);
+    assert_eq!(transport1.send_to(&[1], &transport1.peer_addr()).unwrap_err().kind(), ErrorKind::ConnectionRefused);
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_datagram_transport() {
+    let dir = ::std::env::temp_dir();
+    let path1 = dir.join(format!("kestrel-test-{}-1.sock", ::std::process::id()));
+    let path2 = dir.join(format!("kestrel-test-{}-2.sock", ::std::process::id()));
+    let transport1 = UnixDatagram::bind(&path1).unwrap();
+    let transport2 = UnixDatagram::bind(&path2).unwrap();
+    DatagramTransport::set_nonblocking(&transport2).unwrap();
+    let mut buffer = [0u8; 4];
+    assert_eq!(DatagramTransport::recv_from(&transport2, &mut buffer).unwrap_err().kind(), ErrorKind::WouldBlock);
+    DatagramTransport::send_to(&transport1, &[1, 2, 3], &path2).unwrap();
+    assert_eq!(DatagramTransport::recv_from(&transport2, &mut buffer).unwrap(), (3, path1.clone()));
+    let _ = ::std::fs::remove_file(&path1);
+    let _ = ::std::fs::remove_file(&path2);
+}
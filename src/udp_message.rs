@@ -1,9 +1,36 @@
 use byteorder::{BigEndian, ByteOrder};
 use consts::*;
 use fragment::*;
-use misc::*;
+use pool::{BufferPool, Payload};
+use cookie::{Cookie, COOKIE_SIZE};
+use token::{ConnectToken, CONNECT_TOKEN_SIZE};
+use crypto::{SessionKeys, Salt, HandshakeMac, SALT_SIZE, HANDSHAKE_MAC_SIZE, AEAD_TAG_SIZE};
+use migration::{ConnectionId, PathToken, CONNECTION_ID_SIZE, PATH_TOKEN_SIZE};
+use resumption::{SessionTicket, SESSION_TICKET_SIZE};
+use checksum::Checksum;
 
-use crc::crc32::checksum_ieee as crc32_check;
+/// Offset of the packet type byte, first so that it can be read before the checksum is found
+const PACKET_TYPE_OFFSET: usize = 0;
+/// Offset of the packet's content, right after the generic header
+const PAYLOAD_OFFSET: usize = PACKET_TYPE_SIZE;
+/// Offset of the fragment's data, right after the fragment header
+const FRAG_DATA_OFFSET: usize = PAYLOAD_OFFSET + FRAG_HEADER_SIZE;
+/// Size of the smallest valid packet, a packet without payload
+const MIN_PACKET_SIZE: usize = PAYLOAD_OFFSET;
+/// Set in the packet type byte of encrypted packets
+const ENCRYPTED_FLAG: u8 = 0x80;
+/// Set in the packet type byte of packets followed by the connection id of the receiver
+const CONNECTION_ID_FLAG: u8 = 0x40;
+/// Size of the sequence number that follows the authentication tag of encrypted packets
+const SEQ_SIZE: usize = 4;
+/// Handshake packets are read before the checksum of the connection is agreed on
+const HANDSHAKE_CHECKSUM: Checksum = Checksum::Crc32;
+
+/// A UdpMessage decrypted by `UdpMessage::open`, and its sequence number
+pub (crate) type OpenedMessage = (u32, UdpMessage<Vec<u8>>);
+
+/// A Packet parsed from a received UdpMessage, a fragment holds its buffer
+pub (crate) type OwnedPacket = Packet<Payload>;
 
 #[derive(Debug)]
 pub (crate) struct UdpMessage<B: AsRef<[u8]>> {
@@ -14,44 +41,431 @@ pub (crate) struct UdpMessage<B: AsRef<[u8]>> {
 pub enum UdpMessageError {
     /// Received data was not big enough to be a fragment
     NotBigEnough, // (That's what she said)
-    /// The Crc inside the message was not valid
+    /// The checksum at the end of the message was not valid
     InvalidCrc,
     /// Invalid Frag Info happens when frag_total + 1 is lower than frag_id
     InvalidFragInfo,
     /// Frag Total is too large (should be <= 63)
     FragTotalTooLarge,
+    /// The packet type is not one we know of
+    UnknownPacketType,
+    /// The packet was not encrypted while it should have been, or it could not be decrypted
+    Unauthenticated,
+    /// The encrypted packet was already received, or is too old to tell
+    Replayed,
+}
+
+/// What a UdpMessage holds, written in its first byte.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub (crate) enum PacketType {
+    /// A fragment of a user message
+    Fragment = 0,
+    /// An acknowledgment of the fragments received for one seq_id
+    Ack = 1,
+    /// Asks the receiver to accept us as a remote.
+    ///
+    /// It is padded to the size of a ConnectChallenge, so that answering it never sends more
+    /// bytes than were received.
+    ConnectRequest = 2,
+    /// Answer to a ConnectResponse with a valid cookie: we are now a remote of the sender.
+    ///
+    /// Holds the salt, the connection id and the session ticket of the sender, and the proof that
+    /// it knows the pre-shared key if there is one.
+    ConnectAccept = 3,
+    /// Sent when nothing else was sent for a while, so that the remote knows we're still here
+    Heartbeat = 4,
+    /// The sender forgets about the receiver
+    Disconnect = 5,
+    /// Answer to a ConnectRequest, holds a cookie that must be sent back in a ConnectResponse
+    ConnectChallenge = 6,
+    /// Sends back the cookie of a ConnectChallenge, with the salt and the connection id of the sender
+    /// and the proof that it knows the pre-shared key if there is one. May be followed by a connect token.
+    ConnectResponse = 7,
+    /// Answer to a ConnectResponse with a valid cookie, when the connection can't be accepted.
+    ///
+    /// Holds the cookie, so that only the client that sent it can believe it, and the reason.
+    ConnectReject = 8,
+    /// Sent to the new address of a remote, holds a token that must come back from that address
+    PathChallenge = 9,
+    /// Sends back the token of a PathChallenge
+    PathResponse = 10,
+    /// Asks to resume a suspended session, holds the session ticket of the ConnectAccept
+    ResumeRequest = 11,
+    /// Answer to a ResumeRequest with a valid ticket: the session goes on
+    ResumeAccept = 12,
+}
+
+impl PacketType {
+    fn from_u8(b: u8) -> Option<PacketType> {
+        match b {
+            0 => Some(PacketType::Fragment),
+            1 => Some(PacketType::Ack),
+            2 => Some(PacketType::ConnectRequest),
+            3 => Some(PacketType::ConnectAccept),
+            4 => Some(PacketType::Heartbeat),
+            5 => Some(PacketType::Disconnect),
+            6 => Some(PacketType::ConnectChallenge),
+            7 => Some(PacketType::ConnectResponse),
+            8 => Some(PacketType::ConnectReject),
+            9 => Some(PacketType::PathChallenge),
+            10 => Some(PacketType::PathResponse),
+            11 => Some(PacketType::ResumeRequest),
+            12 => Some(PacketType::ResumeAccept),
+            _ => None,
+        }
+    }
+
+    /// Size of the smallest valid packet of this type
+    fn min_size(self) -> usize {
+        match self {
+            PacketType::Fragment => FRAG_DATA_OFFSET,
+            PacketType::Ack => PAYLOAD_OFFSET + ACK_SIZE,
+            PacketType::ConnectRequest | PacketType::ConnectChallenge => PAYLOAD_OFFSET + COOKIE_SIZE,
+            PacketType::ConnectResponse => PAYLOAD_OFFSET + COOKIE_SIZE + SALT_SIZE + CONNECTION_ID_SIZE + 1 + HANDSHAKE_MAC_SIZE,
+            PacketType::ConnectAccept => PAYLOAD_OFFSET + SALT_SIZE + CONNECTION_ID_SIZE + SESSION_TICKET_SIZE + HANDSHAKE_MAC_SIZE,
+            PacketType::ConnectReject => PAYLOAD_OFFSET + COOKIE_SIZE + 1,
+            PacketType::Heartbeat | PacketType::Disconnect | PacketType::ResumeAccept => PAYLOAD_OFFSET,
+            PacketType::ResumeRequest => PAYLOAD_OFFSET + SESSION_TICKET_SIZE,
+            PacketType::PathChallenge | PacketType::PathResponse => PAYLOAD_OFFSET + PATH_TOKEN_SIZE,
+        }
+    }
+
+    /// Handshake packets are never encrypted, they are how the keys are agreed on
+    pub fn is_handshake(self) -> bool {
+        matches!(self, PacketType::ConnectRequest | PacketType::ConnectChallenge | PacketType::ConnectResponse | PacketType::ConnectAccept | PacketType::ConnectReject)
+    }
+}
+
+/// Why a server refused a connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// The server already has as many remotes as it accepts
+    ServerFull = 0,
+    /// The server requires a connect token, and ours was missing, expired, or already used
+    InvalidConnectToken = 1,
+    /// The server checks the packets with another `Checksum` than ours
+    ChecksumMismatch = 2,
+}
+
+impl RejectReason {
+    fn from_u8(b: u8) -> Option<RejectReason> {
+        match b {
+            0 => Some(RejectReason::ServerFull),
+            1 => Some(RejectReason::InvalidConnectToken),
+            2 => Some(RejectReason::ChecksumMismatch),
+            _ => None,
+        }
+    }
+}
+
+/// A packet of the connection handshake.
+///
+/// Without pre-shared key, salts and macs are zeroes and are ignored. The connection id is the one
+/// the sender picked for the connection, the session ticket the one the server gives to the client.
+/// The checksum is the one the client uses, the server only accepts it if it uses the same.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub (crate) enum Handshake {
+    Request,
+    Challenge(Cookie),
+    Response { cookie: Cookie, salt: Salt, connection_id: ConnectionId, checksum: Checksum, mac: HandshakeMac, token: Option<ConnectToken> },
+    Accept { salt: Salt, connection_id: ConnectionId, session_ticket: SessionTicket, mac: HandshakeMac },
+    Reject { cookie: Cookie, reason: RejectReason },
+}
+
+impl Handshake {
+    pub fn packet_type(&self) -> PacketType {
+        match *self {
+            Handshake::Request => PacketType::ConnectRequest,
+            Handshake::Challenge(_) => PacketType::ConnectChallenge,
+            Handshake::Response { .. } => PacketType::ConnectResponse,
+            Handshake::Accept { .. } => PacketType::ConnectAccept,
+            Handshake::Reject { .. } => PacketType::ConnectReject,
+        }
+    }
+}
+
+/// Acknowledges the fragments received for a seq_id.
+///
+/// The n-th bit of `received_frags` is set if the fragment with frag_id n was received.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub (crate) struct Ack {
+    pub seq_id: u32,
+    pub received_frags: u64,
+}
+
+/// A parsed UdpMessage
+#[derive(Debug)]
+pub (crate) enum Packet<T: AsRef<[u8]>> {
+    Fragment(Fragment<T>),
+    Ack(Ack),
+    /// A packet without payload
+    Control(PacketType),
+    Handshake(Handshake),
+    PathChallenge(PathToken),
+    PathResponse(PathToken),
+    ResumeRequest(SessionTicket),
+}
+
+impl<T: AsRef<[u8]>> Packet<T> {
+    pub fn packet_type(&self) -> PacketType {
+        match *self {
+            Packet::Fragment(_) => PacketType::Fragment,
+            Packet::Ack(_) => PacketType::Ack,
+            Packet::Control(packet_type) => packet_type,
+            Packet::Handshake(ref handshake) => handshake.packet_type(),
+            Packet::PathChallenge(_) => PacketType::PathChallenge,
+            Packet::PathResponse(_) => PacketType::PathResponse,
+            Packet::ResumeRequest(_) => PacketType::ResumeRequest,
+        }
+    }
+}
+
+/// Copies `bytes` into an array of the same size
+fn read<A: Default + AsMut<[u8]>>(bytes: &[u8]) -> A {
+    let mut array = A::default();
+    array.as_mut().copy_from_slice(bytes);
+    array
+}
+
+/// Empties `buffer` and writes the header of a packet followed by `payload_size` zeroes
+fn start_packet(mut buffer: Vec<u8>, packet_type: PacketType, payload_size: usize) -> Vec<u8> {
+    buffer.clear();
+    buffer.resize(PAYLOAD_OFFSET + payload_size, 0);
+    buffer[PACKET_TYPE_OFFSET] = packet_type as u8;
+    buffer
 }
 
-impl<'a, T: AsRef<[u8]>> From<&'a Fragment<T>> for UdpMessage<Box<[u8]>> {
-    fn from(f: &'a Fragment<T>) -> UdpMessage<Box<[u8]>> {
-        let mut bytes_mut: Vec<u8> = vec!(0u8; CRC32_SIZE + FRAG_HEADER_SIZE + f.data.as_ref().len());
-        BigEndian::write_u32(&mut bytes_mut[4..8], f.seq_id);
+impl<'a, T: AsRef<[u8]>> From<&'a Fragment<T>> for UdpMessage<Vec<u8>> {
+    fn from(f: &'a Fragment<T>) -> UdpMessage<Vec<u8>> {
+        UdpMessage::fragment(Vec::new(), f)
+    }
+}
+
+impl<'a> From<&'a Ack> for UdpMessage<Vec<u8>> {
+    fn from(ack: &'a Ack) -> UdpMessage<Vec<u8>> {
+        UdpMessage::ack(Vec::new(), ack)
+    }
+}
+
+impl<'a> From<&'a Handshake> for UdpMessage<Vec<u8>> {
+    fn from(handshake: &'a Handshake) -> UdpMessage<Vec<u8>> {
+        UdpMessage::handshake(Vec::new(), handshake)
+    }
+}
+
+/// The builders write the packet in the buffer they are given, usually one from a `BufferPool`:
+/// whatever it held is lost.
+impl UdpMessage<Vec<u8>> {
+    pub (crate) fn fragment<T: AsRef<[u8]>>(buffer: Vec<u8>, f: &Fragment<T>) -> UdpMessage<Vec<u8>> {
+        let mut bytes_mut = start_packet(buffer, PacketType::Fragment, FRAG_HEADER_SIZE + f.data.as_ref().len());
+        BigEndian::write_u32(&mut bytes_mut[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4], f.seq_id);
         // write frag_id and frag_total as u8s
-        bytes_mut[8] = f.frag_id;
-        bytes_mut[9] = f.frag_total;
-        (&mut bytes_mut[10..]).copy_from_slice(f.data.as_ref());
-        let generated_crc: u32 = crc32_check(&bytes_mut[4..]);
-        BigEndian::write_u32(&mut bytes_mut[0..4], generated_crc);
-        UdpMessage {buffer: bytes_mut.into_boxed_slice()}
+        bytes_mut[PAYLOAD_OFFSET + 4] = f.frag_id;
+        bytes_mut[PAYLOAD_OFFSET + 5] = f.frag_total;
+        bytes_mut[FRAG_DATA_OFFSET..].copy_from_slice(f.data.as_ref());
+        UdpMessage {buffer: bytes_mut}
+    }
+
+    /// Copies a fragment built by `fragment`, under another seq_id. A message sent to several
+    /// remotes is only fragmented once.
+    pub (crate) fn fragment_with_seq_id(mut buffer: Vec<u8>, fragment: &UdpMessage<Vec<u8>>, seq_id: u32) -> UdpMessage<Vec<u8>> {
+        buffer.clear();
+        buffer.extend_from_slice(&fragment.buffer);
+        BigEndian::write_u32(&mut buffer[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4], seq_id);
+        UdpMessage {buffer}
+    }
+
+    pub (crate) fn ack(buffer: Vec<u8>, ack: &Ack) -> UdpMessage<Vec<u8>> {
+        let mut bytes_mut = start_packet(buffer, PacketType::Ack, ACK_SIZE);
+        BigEndian::write_u32(&mut bytes_mut[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4], ack.seq_id);
+        BigEndian::write_u64(&mut bytes_mut[PAYLOAD_OFFSET + 4..PAYLOAD_OFFSET + 12], ack.received_frags);
+        UdpMessage {buffer: bytes_mut}
+    }
+
+    /// Builds a packet without payload, like a Heartbeat
+    pub (crate) fn control(buffer: Vec<u8>, packet_type: PacketType) -> UdpMessage<Vec<u8>> {
+        Self::with_payload(buffer, packet_type, &[])
+    }
+
+    /// Builds a PathChallenge or a PathResponse
+    pub (crate) fn path(buffer: Vec<u8>, packet_type: PacketType, token: &PathToken) -> UdpMessage<Vec<u8>> {
+        Self::with_payload(buffer, packet_type, token)
+    }
+
+    pub (crate) fn resume_request(buffer: Vec<u8>, ticket: &SessionTicket) -> UdpMessage<Vec<u8>> {
+        Self::with_payload(buffer, PacketType::ResumeRequest, ticket)
+    }
+
+    fn with_payload(buffer: Vec<u8>, packet_type: PacketType, payload: &[u8]) -> UdpMessage<Vec<u8>> {
+        let mut bytes_mut = start_packet(buffer, packet_type, payload.len());
+        bytes_mut[PAYLOAD_OFFSET..].copy_from_slice(payload);
+        UdpMessage {buffer: bytes_mut}
+    }
+
+    pub (crate) fn handshake(buffer: Vec<u8>, handshake: &Handshake) -> UdpMessage<Vec<u8>> {
+        let packet_type = handshake.packet_type();
+        // a ConnectRequest is only padding
+        let mut bytes_mut = start_packet(buffer, packet_type, packet_type.min_size() - PAYLOAD_OFFSET);
+        match *handshake {
+            Handshake::Request => {},
+            Handshake::Challenge(ref cookie) => bytes_mut[PAYLOAD_OFFSET..].copy_from_slice(cookie),
+            Handshake::Response { ref cookie, ref salt, ref connection_id, checksum, ref mac, ref token } => {
+                let (cookie_bytes, rest) = bytes_mut[PAYLOAD_OFFSET..].split_at_mut(COOKIE_SIZE);
+                cookie_bytes.copy_from_slice(cookie);
+                rest[..SALT_SIZE].copy_from_slice(salt);
+                rest[SALT_SIZE..SALT_SIZE + CONNECTION_ID_SIZE].copy_from_slice(connection_id);
+                rest[SALT_SIZE + CONNECTION_ID_SIZE] = checksum as u8;
+                rest[SALT_SIZE + CONNECTION_ID_SIZE + 1..].copy_from_slice(mac);
+                if let Some(ref token) = *token {
+                    bytes_mut.extend_from_slice(token.handshake_bytes());
+                }
+            },
+            Handshake::Accept { ref salt, ref connection_id, ref session_ticket, ref mac } => {
+                let (salt_bytes, rest) = bytes_mut[PAYLOAD_OFFSET..].split_at_mut(SALT_SIZE);
+                let (connection_id_bytes, rest) = rest.split_at_mut(CONNECTION_ID_SIZE);
+                let (session_ticket_bytes, mac_bytes) = rest.split_at_mut(SESSION_TICKET_SIZE);
+                salt_bytes.copy_from_slice(salt);
+                connection_id_bytes.copy_from_slice(connection_id);
+                session_ticket_bytes.copy_from_slice(session_ticket);
+                mac_bytes.copy_from_slice(mac);
+            },
+            Handshake::Reject { ref cookie, reason } => {
+                bytes_mut[PAYLOAD_OFFSET..PAYLOAD_OFFSET + COOKIE_SIZE].copy_from_slice(cookie);
+                bytes_mut[PAYLOAD_OFFSET + COOKIE_SIZE] = reason as u8;
+            },
+        }
+        HANDSHAKE_CHECKSUM.append(&mut bytes_mut);
+        UdpMessage {buffer: bytes_mut}
+    }
+
+    /// Appends the checksum of everything before it, last of all as it covers the connection id.
+    ///
+    /// Handshake packets already have theirs, and encrypted packets don't need one.
+    pub (crate) fn append_checksum(&mut self, checksum: Checksum) {
+        checksum.append(&mut self.buffer);
+    }
+
+    /// Appends the connection id the receiver picked, so that it still recognizes us if our
+    /// address changes.
+    ///
+    /// Sealed packets are followed by the connection id as is: the receiver removes it, and the
+    /// flag, before opening them. The checksum of the other packets covers it.
+    pub (crate) fn append_connection_id(&mut self, connection_id: &ConnectionId) {
+        self.buffer.extend_from_slice(connection_id);
+        self.buffer[PACKET_TYPE_OFFSET] |= CONNECTION_ID_FLAG;
+    }
+
+    /// Returns the connection id appended by `append_connection_id`, without checking anything.
+    ///
+    /// `checksum` is the one the packet ends with, unless it is encrypted.
+    pub (crate) fn connection_id(&self, checksum: Checksum) -> Option<ConnectionId> {
+        let end = self.buffer.len().checked_sub(if self.is_encrypted() { 0 } else { checksum.size() })?;
+        if !self.has_connection_id() || end < MIN_PACKET_SIZE + CONNECTION_ID_SIZE {
+            return None;
+        }
+        Some(read(&self.buffer[end - CONNECTION_ID_SIZE..end]))
+    }
+
+    fn has_connection_id(&self) -> bool {
+        self.buffer.get(PACKET_TYPE_OFFSET).is_some_and(|b| b & CONNECTION_ID_FLAG != 0)
+    }
+
+    /// Checks the checksum of a packet that is not encrypted, and removes it. Encrypted packets
+    /// are returned as is, `open` authenticates them.
+    ///
+    /// Handshake packets are always checked with `HANDSHAKE_CHECKSUM`, the others with `checksum`.
+    pub (crate) fn check(mut self, checksum: Checksum) -> Result<UdpMessage<Vec<u8>>, UdpMessageError> {
+        let size = self.checked_size(checksum)?;
+        self.buffer.truncate(size);
+        Ok(self)
+    }
+
+    /// Reverts `append_connection_id` on a packet whose checksum was removed by `check`, packets
+    /// without connection id are returned as is.
+    pub (crate) fn strip_connection_id(mut self) -> Result<UdpMessage<Vec<u8>>, UdpMessageError> {
+        if !self.has_connection_id() {
+            return Ok(self);
+        }
+        if self.buffer.len() < MIN_PACKET_SIZE + CONNECTION_ID_SIZE {
+            return Err(UdpMessageError::NotBigEnough);
+        }
+        let id_offset = self.buffer.len() - CONNECTION_ID_SIZE;
+        self.buffer.truncate(id_offset);
+        self.buffer[PACKET_TYPE_OFFSET] &= !CONNECTION_ID_FLAG;
+        Ok(self)
+    }
+
+    /// Encrypts this packet for a remote, `seq` must never be used twice with the same keys.
+    ///
+    /// `seq` follows the authentication tag: the tag already detects corruption, so there is no
+    /// checksum.
+    pub (crate) fn seal(&mut self, keys: &SessionKeys, seq: u32) {
+        self.buffer[PACKET_TYPE_OFFSET] |= ENCRYPTED_FLAG;
+        keys.seal(&mut self.buffer, PAYLOAD_OFFSET, seq);
+        let mut seq_bytes = [0u8; SEQ_SIZE];
+        BigEndian::write_u32(&mut seq_bytes, seq);
+        self.buffer.extend_from_slice(&seq_bytes);
+    }
+
+    /// Decrypts a packet sealed by `seal`, and returns its sequence number. The result must be
+    /// parsed with `into_packet`.
+    pub (crate) fn open(mut self, keys: &SessionKeys) -> Result<OpenedMessage, UdpMessageError> {
+        if self.buffer.len() < MIN_PACKET_SIZE + AEAD_TAG_SIZE + SEQ_SIZE {
+            return Err(UdpMessageError::NotBigEnough);
+        }
+        let seq_offset = self.buffer.len() - SEQ_SIZE;
+        let seq = BigEndian::read_u32(&self.buffer[seq_offset..]);
+        self.buffer.truncate(seq_offset);
+        keys.open(&mut self.buffer, PAYLOAD_OFFSET, seq).map_err(|()| UdpMessageError::Unauthenticated)?;
+        self.buffer[PACKET_TYPE_OFFSET] &= !ENCRYPTED_FLAG;
+        Ok((seq, self))
+    }
+
+    /// Returns the buffer of this message, to give it back to a pool
+    pub (crate) fn into_buffer(self) -> Vec<u8> {
+        self.buffer
     }
 }
 
 impl<B: AsRef<[u8]>> UdpMessage<B> {
-    fn check_header(udp_message: &[u8]) -> Result<(u32, u8, u8), UdpMessageError> {
+    /// Checks the size, and returns the type of the packet
+    fn check_header(udp_message: &[u8]) -> Result<PacketType, UdpMessageError> {
         let buffer = udp_message;
-        if buffer.len() < 10 {
+        if buffer.len() < MIN_PACKET_SIZE {
             return Err(UdpMessageError::NotBigEnough);
         }
-        let message_crc32: u32 = BigEndian::read_u32(&buffer[0..4]);
-        let seq_id: u32 = BigEndian::read_u32(&buffer[4..8]);
-        let frag_id: u8 = buffer[8];
-        let frag_total: u8 = buffer[9];
-        if frag_total >= 64 {
-            return Err(UdpMessageError::FragTotalTooLarge)
+        let packet_type = PacketType::from_u8(buffer[PACKET_TYPE_OFFSET]).ok_or(UdpMessageError::UnknownPacketType)?;
+        if buffer.len() < packet_type.min_size() {
+            return Err(UdpMessageError::NotBigEnough);
+        }
+        Ok(packet_type)
+    }
+
+    /// Checks the checksum at the end of the packet, and returns the size of what it covers.
+    ///
+    /// Encrypted packets have no checksum, their whole size is returned.
+    fn checked_size(&self, checksum: Checksum) -> Result<usize, UdpMessageError> {
+        let buffer = self.buffer.as_ref();
+        if self.is_encrypted() {
+            return Ok(buffer.len());
         }
-        let computed_crc32 = crc32_check(&buffer[4..]);
-        if computed_crc32 != message_crc32 {
-            return Err(UdpMessageError::InvalidCrc)
+        let checksum = if self.is_handshake() { HANDSHAKE_CHECKSUM } else { checksum };
+        if buffer.len() < MIN_PACKET_SIZE + checksum.size() {
+            return Err(UdpMessageError::NotBigEnough);
+        }
+        checksum.verify(buffer).map(<[u8]>::len).ok_or(UdpMessageError::InvalidCrc)
+    }
+
+    fn check_frag_header(udp_message: &[u8]) -> Result<(u32, u8, u8), UdpMessageError> {
+        let buffer = udp_message;
+        if buffer.len() < FRAG_DATA_OFFSET {
+            return Err(UdpMessageError::NotBigEnough);
+        }
+        let seq_id: u32 = BigEndian::read_u32(&buffer[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4]);
+        let frag_id: u8 = buffer[PAYLOAD_OFFSET + 4];
+        let frag_total: u8 = buffer[PAYLOAD_OFFSET + 5];
+        if frag_total as usize >= MAX_FRAGMENTS_IN_MESSAGE {
+            return Err(UdpMessageError::FragTotalTooLarge)
         }
         // since frag_total is really +1, if frag_id == frag_total, it's actually the last fragment
         // that we received. if frag_id = frag_total = 0, the first and last fragment of a message was received.
@@ -61,54 +475,304 @@ impl<B: AsRef<[u8]>> UdpMessage<B> {
         Ok((seq_id, frag_id, frag_total))
     }
 
-    pub (crate) fn new(b: B) -> UdpMessage<B>{
-        UdpMessage {buffer: b}
+    fn check_ack(udp_message: &[u8]) -> Result<Ack, UdpMessageError> {
+        let buffer = udp_message;
+        if buffer.len() < PAYLOAD_OFFSET + ACK_SIZE {
+            return Err(UdpMessageError::NotBigEnough);
+        }
+        Ok(Ack {
+            seq_id: BigEndian::read_u32(&buffer[PAYLOAD_OFFSET..PAYLOAD_OFFSET + 4]),
+            received_frags: BigEndian::read_u64(&buffer[PAYLOAD_OFFSET + 4..PAYLOAD_OFFSET + 12]),
+        })
     }
 
-    /// Reads one message from a udp socket and returns its content as a UdpMessage
-    ///
-    /// Proper parameters that you see fit must have been set on UdpSocket. For instance,
-    /// it may be wise to set this udp socket as non-blocking  if you don't want to block
-    /// your thread forever trying to read one message.
-    pub fn from_udp_socket(udp_socket: &::std::net::UdpSocket) -> ::std::io::Result<(UdpMessage<Box<[u8]>>, ::std::net::SocketAddr)> {
-        let mut buffer = vec!(0; MAX_UDP_MESSAGE_SIZE);
-        let (message_size, socket_addr) = udp_socket.recv_from(buffer.as_mut_slice())?;
-        buffer.truncate(message_size);
-        let udp_message = UdpMessage {buffer: buffer.into_boxed_slice()};
-        Ok((udp_message, socket_addr))
+    /// Reads the token of a PathChallenge or a PathResponse, whose size was checked by `check_header`
+    fn read_path_token(udp_message: &[u8]) -> PathToken {
+        read(&udp_message[PAYLOAD_OFFSET..PAYLOAD_OFFSET + PATH_TOKEN_SIZE])
+    }
+
+    /// Reads the ticket of a ResumeRequest, whose size was checked by `check_header`
+    fn read_session_ticket(udp_message: &[u8]) -> SessionTicket {
+        read(&udp_message[PAYLOAD_OFFSET..PAYLOAD_OFFSET + SESSION_TICKET_SIZE])
+    }
+
+    /// Reads the payload of a handshake packet, whose size was checked by `check_header`
+    fn read_handshake(udp_message: &[u8], packet_type: PacketType) -> Result<Handshake, UdpMessageError> {
+        let payload = &udp_message[PAYLOAD_OFFSET..];
+        Ok(match packet_type {
+            PacketType::ConnectChallenge => Handshake::Challenge(read(&payload[..COOKIE_SIZE])),
+            PacketType::ConnectResponse => {
+                let (cookie, rest) = payload.split_at(COOKIE_SIZE);
+                let (salt, rest) = rest.split_at(SALT_SIZE);
+                let (connection_id, rest) = rest.split_at(CONNECTION_ID_SIZE);
+                let (checksum, rest) = rest.split_at(1);
+                let (mac, rest) = rest.split_at(HANDSHAKE_MAC_SIZE);
+                Handshake::Response {
+                    cookie: read(cookie),
+                    salt: read(salt),
+                    connection_id: read(connection_id),
+                    checksum: Checksum::from_u8(checksum[0]).ok_or(UdpMessageError::UnknownPacketType)?,
+                    mac: read(mac),
+                    // anything else than a whole token is ignored, the server will reject it if it requires one
+                    token: ConnectToken::from_handshake_bytes(rest).ok(),
+                }
+            },
+            PacketType::ConnectAccept => {
+                let (salt, rest) = payload.split_at(SALT_SIZE);
+                let (connection_id, rest) = rest.split_at(CONNECTION_ID_SIZE);
+                let (session_ticket, rest) = rest.split_at(SESSION_TICKET_SIZE);
+                Handshake::Accept {
+                    salt: read(salt),
+                    connection_id: read(connection_id),
+                    session_ticket: read(session_ticket),
+                    mac: read(&rest[..HANDSHAKE_MAC_SIZE]),
+                }
+            },
+            PacketType::ConnectReject => Handshake::Reject {
+                cookie: read(&payload[..COOKIE_SIZE]),
+                reason: RejectReason::from_u8(payload[COOKIE_SIZE]).ok_or(UdpMessageError::UnknownPacketType)?,
+            },
+            _ => Handshake::Request,
+        })
+    }
+
+    /// Returns true if this packet was encrypted by `seal`
+    pub (crate) fn is_encrypted(&self) -> bool {
+        self.buffer.as_ref().get(PACKET_TYPE_OFFSET).is_some_and(|b| b & ENCRYPTED_FLAG != 0)
+    }
+
+    /// Returns true if this is a handshake packet, without checking anything else
+    pub (crate) fn is_handshake(&self) -> bool {
+        self.buffer.as_ref().get(PACKET_TYPE_OFFSET).and_then(|b| PacketType::from_u8(*b)).is_some_and(PacketType::is_handshake)
+    }
+
+    pub (crate) fn new(b: B) -> UdpMessage<B>{
+        UdpMessage {buffer: b}
     }
 
     /// useful for debug purposes
     pub (crate) fn as_bytes(&self) -> &[u8] {
         self.buffer.as_ref()
     }
-    
+
 }
-    
+
 impl<'a> UdpMessage<&'a [u8]> {
-    pub (crate) fn into_fragment(self) -> Result<Fragment<&'a [u8]>, UdpMessageError> {
-        let (seq_id, frag_id, frag_total) = Self::check_header(self.buffer.as_ref())?;
-        Ok(Fragment {
-            seq_id,
-            frag_id,
-            frag_total,
-            data: &self.buffer[10..]
-        })
+    /// Checks the checksum, which handshake packets always have, then parses the packet
+    pub (crate) fn into_packet(self, checksum: Checksum) -> Result<Packet<&'a [u8]>, UdpMessageError> {
+        let size = self.checked_size(checksum)?;
+        UdpMessage::new(&self.buffer[..size]).parse()
+    }
+
+    fn parse(self) -> Result<Packet<&'a [u8]>, UdpMessageError> {
+        match Self::check_header(self.buffer)? {
+            PacketType::Fragment => {
+                let (seq_id, frag_id, frag_total) = Self::check_frag_header(self.buffer)?;
+                Ok(Packet::Fragment(Fragment {
+                    seq_id,
+                    frag_id,
+                    frag_total,
+                    data: &self.buffer[FRAG_DATA_OFFSET..]
+                }))
+            },
+            PacketType::Ack => Ok(Packet::Ack(Self::check_ack(self.buffer)?)),
+            PacketType::PathChallenge => Ok(Packet::PathChallenge(Self::read_path_token(self.buffer))),
+            PacketType::PathResponse => Ok(Packet::PathResponse(Self::read_path_token(self.buffer))),
+            PacketType::ResumeRequest => Ok(Packet::ResumeRequest(Self::read_session_ticket(self.buffer))),
+            packet_type if packet_type.is_handshake() => Ok(Packet::Handshake(Self::read_handshake(self.buffer, packet_type)?)),
+            packet_type => Ok(Packet::Control(packet_type)),
+        }
     }
 }
 
-impl UdpMessage<Box<[u8]>> {
+impl UdpMessage<Vec<u8>> {
 
-    /// Tries to build a Fragment from a UdpMessage.
+    /// Tries to build a Packet from a UdpMessage checked by `check`, or decrypted by `open`.
     ///
-    ///  No copies of data are involved
-    pub (crate) fn into_fragment(self) -> Result<Fragment<StrippedBoxedSlice<u8>>, UdpMessageError> {
-        let (seq_id, frag_id, frag_total) = Self::check_header(self.buffer.as_ref())?;
-        Ok(Fragment {
-            seq_id,
-            frag_id,
-            frag_total,
-            data: StrippedBoxedSlice::new(self.buffer, 10usize)
-        })
+    /// No copies of data are involved: a fragment keeps the buffer, which is given back to `pool`
+    /// for any other packet.
+    pub (crate) fn into_packet(self, pool: &mut BufferPool) -> Result<OwnedPacket, UdpMessageError> {
+        let packet = match UdpMessage::new(self.buffer.as_slice()).parse() {
+            Ok(Packet::Fragment(Fragment { seq_id, frag_id, frag_total, .. })) => {
+                return Ok(Packet::Fragment(Fragment {
+                    seq_id,
+                    frag_id,
+                    frag_total,
+                    data: Payload::new(self.buffer, FRAG_DATA_OFFSET),
+                }));
+            },
+            Ok(Packet::Ack(ack)) => Ok(Packet::Ack(ack)),
+            Ok(Packet::Control(packet_type)) => Ok(Packet::Control(packet_type)),
+            Ok(Packet::Handshake(handshake)) => Ok(Packet::Handshake(handshake)),
+            Ok(Packet::PathChallenge(token)) => Ok(Packet::PathChallenge(token)),
+            Ok(Packet::PathResponse(token)) => Ok(Packet::PathResponse(token)),
+            Ok(Packet::ResumeRequest(ticket)) => Ok(Packet::ResumeRequest(ticket)),
+            Err(e) => Err(e),
+        };
+        pool.give_back(self.buffer);
+        packet
+    }
+}
+
+#[test]
+fn frag_udp_with_another_seq_id() {
+    let fragment = Fragment { seq_id: 0, frag_id: 1, frag_total: 2, data: &[1u8, 2, 3][..] };
+    let udp_message = UdpMessage::from(&fragment);
+    match UdpMessage::fragment_with_seq_id(Vec::new(), &udp_message, 7).into_packet(&mut BufferPool::new()).unwrap() {
+        Packet::Fragment(received) => {
+            assert_eq!((received.seq_id, received.frag_id, received.frag_total), (7, 1, 2));
+            assert_eq!(received.data.as_ref(), &[1u8, 2, 3]);
+        },
+        p => panic!("expected a fragment, got {:?}", p),
+    }
+}
+
+#[test]
+fn ack_udp_conversions() {
+    let sent_ack = Ack { seq_id: 42, received_frags: 0b1011 };
+    let udp_message = UdpMessage::from(&sent_ack);
+    match udp_message.into_packet(&mut BufferPool::new()).unwrap() {
+        Packet::Ack(received_ack) => assert_eq!(received_ack, sent_ack),
+        p => panic!("expected an ack, got {:?}", p),
+    }
+}
+
+#[test]
+fn control_udp_conversions() {
+    let mut pool = BufferPool::new();
+    let udp_message = UdpMessage::control(pool.take(), PacketType::Heartbeat);
+    assert_eq!(udp_message.as_bytes().len(), MIN_PACKET_SIZE);
+    match udp_message.into_packet(&mut pool).unwrap() {
+        Packet::Control(PacketType::Heartbeat) => {},
+        p => panic!("expected a heartbeat, got {:?}", p),
+    }
+    // the buffer is reused once parsed
+    assert_eq!(pool.len(), 1);
+}
+
+#[test]
+fn handshake_udp_conversions() {
+    let request = UdpMessage::from(&Handshake::Request);
+    let challenge = UdpMessage::from(&Handshake::Challenge([7u8; COOKIE_SIZE]));
+    let response = UdpMessage::from(&Handshake::Response { cookie: [7u8; COOKIE_SIZE], salt: [1u8; SALT_SIZE], connection_id: [5u8; CONNECTION_ID_SIZE], checksum: Checksum::Hash64, mac: [2u8; HANDSHAKE_MAC_SIZE], token: None });
+    let token = ConnectToken::from_handshake_bytes(&[8u8; CONNECT_TOKEN_SIZE]).unwrap();
+    let response_with_token = UdpMessage::from(&Handshake::Response { cookie: [7u8; COOKIE_SIZE], salt: [1u8; SALT_SIZE], connection_id: [5u8; CONNECTION_ID_SIZE], checksum: Checksum::Crc32c, mac: [2u8; HANDSHAKE_MAC_SIZE], token: Some(token) });
+    let accept = UdpMessage::from(&Handshake::Accept { salt: [3u8; SALT_SIZE], connection_id: [6u8; CONNECTION_ID_SIZE], session_ticket: [8u8; SESSION_TICKET_SIZE], mac: [4u8; HANDSHAKE_MAC_SIZE] });
+    let reject = UdpMessage::from(&Handshake::Reject { cookie: [7u8; COOKIE_SIZE], reason: RejectReason::ServerFull });
+    // answering a handshake packet must not send more bytes than were received
+    assert!(challenge.as_bytes().len() <= request.as_bytes().len());
+    assert!(accept.as_bytes().len() <= response.as_bytes().len());
+    assert!(reject.as_bytes().len() <= response.as_bytes().len());
+    for udp_message in &[request, challenge, response, response_with_token, accept, reject] {
+        // whatever the checksum of the connection, handshake packets are checked with a crc32
+        let handshake = match UdpMessage::new(udp_message.as_bytes()).into_packet(Checksum::None).unwrap() {
+            Packet::Handshake(handshake) => handshake,
+            p => panic!("expected a handshake packet, got {:?}", p),
+        };
+        assert_eq!(udp_message.as_bytes(), UdpMessage::from(&handshake).as_bytes());
+    }
+    // a request without its padding is dropped
+    let short_request = UdpMessage::control(Vec::new(), PacketType::ConnectRequest);
+    assert_eq!(short_request.into_packet(&mut BufferPool::new()).unwrap_err(), UdpMessageError::NotBigEnough);
+}
+
+#[test]
+fn sealed_udp_conversions() {
+    use crypto::random_salt;
+    let (salt1, salt2) = (random_salt(), random_salt());
+    let keys1 = SessionKeys::derive(&[9u8; 32], &salt1, &salt2);
+    let keys2 = SessionKeys::derive(&[9u8; 32], &salt2, &salt1);
+    let sent_ack = Ack { seq_id: 42, received_frags: 0b1011 };
+    let mut sealed = UdpMessage::from(&sent_ack);
+    sealed.seal(&keys1, 5);
+    assert!(sealed.is_encrypted());
+    assert_eq!(sealed.as_bytes().len(), PAYLOAD_OFFSET + ACK_SIZE + AEAD_TAG_SIZE + SEQ_SIZE);
+    // sealed packets have no checksum to check
+    let sealed = sealed.check(Checksum::Hash64).unwrap();
+    let (seq, opened) = sealed.open(&keys2).unwrap();
+    assert_eq!(seq, 5);
+    match opened.into_packet(&mut BufferPool::new()).unwrap() {
+        Packet::Ack(received_ack) => assert_eq!(received_ack, sent_ack),
+        p => panic!("expected an ack, got {:?}", p),
+    }
+    let mut corrupted = UdpMessage::from(&sent_ack);
+    corrupted.seal(&keys1, 6);
+    let mut corrupted = corrupted.into_buffer();
+    corrupted[PAYLOAD_OFFSET] ^= 1;
+    let e = UdpMessage::new(corrupted).open(&keys2).unwrap_err();
+    assert_eq!(e, UdpMessageError::Unauthenticated);
+}
+
+#[test]
+fn connection_id_udp_conversions() {
+    use crypto::random_salt;
+    let mut pool = BufferPool::new();
+    let challenge = UdpMessage::path(Vec::new(), PacketType::PathChallenge, &[3u8; PATH_TOKEN_SIZE]);
+    let mut with_id = UdpMessage::new(challenge.as_bytes().to_vec());
+    with_id.append_connection_id(&[9u8; CONNECTION_ID_SIZE]);
+    with_id.append_checksum(Checksum::Hash64);
+    assert_eq!(with_id.as_bytes().len(), challenge.as_bytes().len() + CONNECTION_ID_SIZE + 8);
+    assert_eq!(with_id.connection_id(Checksum::Hash64), Some([9u8; CONNECTION_ID_SIZE]));
+    match with_id.check(Checksum::Hash64).and_then(UdpMessage::strip_connection_id).unwrap().into_packet(&mut pool).unwrap() {
+        Packet::PathChallenge(token) => assert_eq!(token, [3u8; PATH_TOKEN_SIZE]),
+        p => panic!("expected a path challenge, got {:?}", p),
+    }
+    // the connection id is covered by the checksum
+    let mut corrupted = challenge;
+    corrupted.append_connection_id(&[9u8; CONNECTION_ID_SIZE]);
+    corrupted.append_checksum(Checksum::Crc32);
+    let mut corrupted = corrupted.into_buffer();
+    let id_end = corrupted.len() - Checksum::Crc32.size();
+    corrupted[id_end - 1] ^= 1;
+    let e = UdpMessage::new(corrupted).check(Checksum::Crc32).unwrap_err();
+    assert_eq!(e, UdpMessageError::InvalidCrc);
+
+    // sealed packets can still be opened once the connection id is removed
+    let (salt1, salt2) = (random_salt(), random_salt());
+    let keys1 = SessionKeys::derive(&[9u8; 32], &salt1, &salt2);
+    let keys2 = SessionKeys::derive(&[9u8; 32], &salt2, &salt1);
+    let mut with_id = UdpMessage::path(pool.take(), PacketType::PathResponse, &[4u8; PATH_TOKEN_SIZE]);
+    with_id.seal(&keys1, 0);
+    with_id.append_connection_id(&[8u8; CONNECTION_ID_SIZE]);
+    assert_eq!(with_id.connection_id(Checksum::Hash64), Some([8u8; CONNECTION_ID_SIZE]));
+    let (_, opened) = with_id.check(Checksum::Hash64).and_then(UdpMessage::strip_connection_id).unwrap().open(&keys2).unwrap();
+    match opened.into_packet(&mut pool).unwrap() {
+        Packet::PathResponse(token) => assert_eq!(token, [4u8; PATH_TOKEN_SIZE]),
+        p => panic!("expected a path response, got {:?}", p),
+    }
+    // packets without connection id are left as is
+    let heartbeat = UdpMessage::control(Vec::new(), PacketType::Heartbeat);
+    assert_eq!(heartbeat.connection_id(Checksum::None), None);
+    assert_eq!(UdpMessage::control(Vec::new(), PacketType::Heartbeat).strip_connection_id().unwrap().as_bytes(), heartbeat.as_bytes());
+}
+
+#[test]
+fn udp_fail_unknown_packet_type() {
+    let mut buffer = vec!(0u8; MIN_PACKET_SIZE);
+    buffer[PACKET_TYPE_OFFSET] = 255;
+    Checksum::Crc32.append(&mut buffer);
+    let e = UdpMessage::new(buffer.as_slice()).into_packet(Checksum::Crc32).unwrap_err();
+    assert_eq!(e, UdpMessageError::UnknownPacketType);
+}
+
+#[test]
+fn checksum_udp_conversions() {
+    let mut pool = BufferPool::new();
+    let sent_ack = Ack { seq_id: 42, received_frags: 0b1011 };
+    for &checksum in &[Checksum::Crc32, Checksum::Crc32c, Checksum::Hash64, Checksum::None] {
+        let mut udp_message = UdpMessage::ack(pool.take(), &sent_ack);
+        udp_message.append_checksum(checksum);
+        assert_eq!(udp_message.as_bytes().len(), PAYLOAD_OFFSET + ACK_SIZE + checksum.size());
+        match udp_message.check(checksum).unwrap().into_packet(&mut pool).unwrap() {
+            Packet::Ack(received_ack) => assert_eq!(received_ack, sent_ack),
+            p => panic!("expected an ack, got {:?}", p),
+        }
     }
-}
\ No newline at end of file
+    // both ends must use the same checksum
+    let mut udp_message = UdpMessage::ack(pool.take(), &sent_ack);
+    udp_message.append_checksum(Checksum::Crc32c);
+    assert_eq!(udp_message.check(Checksum::Crc32).unwrap_err(), UdpMessageError::InvalidCrc);
+    let mut udp_message = UdpMessage::control(pool.take(), PacketType::Heartbeat);
+    udp_message.append_checksum(Checksum::Crc32);
+    assert_eq!(udp_message.check(Checksum::Hash64).unwrap_err(), UdpMessageError::NotBigEnough);
+}